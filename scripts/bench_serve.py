@@ -0,0 +1,122 @@
+#!/usr/bin/env python3
+"""Measure how well kimage-serve keeps serving small images during large downloads.
+
+Eight clients download large images while a ninth fetches a small one every 50 ms,
+and the script reports the large-image throughput and the small-image latency. A
+worker thread blocked on file I/O shows up as a high small-image latency.
+
+Run it against a release build (`cargo build --release`) with a single worker, using
+a `bench.toml` of
+
+    server_url = "http://127.0.0.1:8080"
+    port = 8080
+    api_key = "bench"
+    storage_path = "/tmp/kimage-bench"
+    workers = 1
+
+and then
+
+    mkdir -p /tmp/kimage-bench
+    scripts/bench_serve.py setup /tmp/kimage-bench
+    target/release/kimage-serve --config bench.toml &
+    scripts/bench_serve.py warm
+    sync && echo 3 | sudo tee /proc/sys/vm/drop_caches
+    scripts/bench_serve.py cold
+
+`setup` writes `small.png`, `big.png` and `cold0.png` to `cold39.png` into the
+storage directory. `warm` fetches `big.png` over and over, so it is served from the
+page cache; `cold` fetches each `coldN.png` once, so drop the page cache first.
+"""
+import argparse
+import os
+import statistics
+import threading
+import time
+import urllib.request
+from concurrent.futures import ThreadPoolExecutor
+
+PNG_SIGNATURE = b"\x89PNG\r\n\x1a\n"
+BIG_MB = 32
+COLD_FILES = 40
+CLIENTS = 8
+
+
+def setup(storage_path):
+    """Write the images the benchmark fetches; only their leading bytes need to be a PNG"""
+    def write(name, size):
+        with open(os.path.join(storage_path, name), "wb") as f:
+            f.write(PNG_SIGNATURE + os.urandom(size - len(PNG_SIGNATURE)))
+
+    write("small.png", 4096)
+    write("big.png", BIG_MB << 20)
+    for i in range(COLD_FILES):
+        write(f"cold{i}.png", BIG_MB << 20)
+
+
+def fetch(url):
+    with urllib.request.urlopen(url) as response:
+        response.read()
+
+
+def run(base, names_for_client, stop):
+    """Download with `CLIENTS` clients while timing small fetches, until they're done"""
+    downloaded = [0]
+    lock = threading.Lock()
+    latencies = []
+    big_done = threading.Event()
+
+    def big_loop(client):
+        for name in names_for_client(client):
+            if time.time() >= stop:
+                return
+            fetch(base + name)
+            with lock:
+                downloaded[0] += 1
+
+    def small_loop():
+        while not big_done.is_set():
+            start = time.time()
+            fetch(base + "small.png")
+            latencies.append((time.time() - start) * 1000)
+            time.sleep(0.05)
+
+    start = time.time()
+    with ThreadPoolExecutor(CLIENTS + 1) as pool:
+        big = [pool.submit(big_loop, client) for client in range(CLIENTS)]
+        small = pool.submit(small_loop)
+        for future in big:
+            future.result()
+        elapsed = time.time() - start
+        big_done.set()
+        small.result()
+
+    latencies.sort()
+    print(
+        f"{downloaded[0]} x {BIG_MB} MB in {elapsed:.1f} s "
+        f"({downloaded[0] * BIG_MB / elapsed:.0f} MB/s); "
+        f"small p50 {statistics.median(latencies):.1f} ms, "
+        f"p99 {latencies[int(len(latencies) * 0.99)]:.1f} ms, n={len(latencies)}"
+    )
+
+
+def main():
+    parser = argparse.ArgumentParser(description=__doc__.splitlines()[0])
+    parser.add_argument("mode", choices=["setup", "warm", "cold"])
+    parser.add_argument("storage_path", nargs="?", help="storage directory, for setup")
+    parser.add_argument("--url", default="http://127.0.0.1:8080/i/", help="image URL prefix")
+    parser.add_argument("--duration", type=float, default=20, help="seconds to run for")
+    args = parser.parse_args()
+
+    stop = time.time() + args.duration
+    if args.mode == "setup":
+        if not args.storage_path:
+            parser.error("setup needs the storage directory")
+        setup(args.storage_path)
+    elif args.mode == "warm":
+        run(args.url, lambda client: iter(lambda: "big.png", None), stop)
+    else:
+        run(args.url, lambda client: (f"cold{i}.png" for i in range(client, COLD_FILES, CLIENTS)), stop)
+
+
+if __name__ == "__main__":
+    main()
@@ -1,177 +1,5904 @@
 //! An Actix-based server for handling image uploads and serving uploaded images.
 //!
-//! This server provides endpoints for uploading images (converting from base64)
-//! and serving previously uploaded images. It uses `pretty_env_logger` for logging.
+//! This server provides endpoints for uploading images (sent raw or as base64)
+//! and serving previously uploaded images. It logs through `kimage::logging`.
 
+use actix_cors::Cors;
 use actix_multipart::Multipart;
-use actix_web::{web, App, Error, HttpRequest, HttpResponse, HttpServer};
-use anyhow::{Context, Result};
+use actix_web::body::{BodySize, MessageBody, SizedStream};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::header::{
+    self, CacheControl, CacheDirective, ContentRange, ContentRangeSpec, ETag, EntityTag, Expires,
+    HeaderName, IfModifiedSince, IfNoneMatch, IfRange, LastModified, Range, TryIntoHeaderValue,
+};
+use actix_web::http::{Method, StatusCode, Uri};
+use actix_web::middleware::{from_fn, Compress, Condition, Next};
+use actix_web::{
+    web, App, HttpMessage, HttpRequest, HttpResponse, HttpResponseBuilder, HttpServer,
+    ResponseError, Scope,
+};
+use anyhow::{anyhow, bail, Context, Result};
 use base64::{engine::general_purpose, Engine as _};
+use chrono::Utc;
+use clap::{ArgAction, Parser};
 use dirs::home_dir;
 use futures::{StreamExt, TryStreamExt};
-use log::{error, info};
-use rand::Rng;
+use governor::clock::{Clock, DefaultClock};
+use governor::{DefaultDirectRateLimiter, Quota, RateLimiter};
+use hmac::{Hmac, Mac};
+use image::codecs::png::PngDecoder;
+use image::codecs::webp::WebPDecoder;
+use image::imageops::FilterType;
+use image::{DynamicImage, ImageFormat, ImageOutputFormat};
+use image_hasher::HasherConfig;
+use kimage::client::{apply_orientation, exif_orientation};
+use kimage::index::{FormatTotals, Index, IndexEntry};
+use kimage::logging::{self, LogFormat};
+use kimage::metrics::Metrics;
+#[cfg(feature = "s3")]
+use kimage::storage::S3Storage;
+use kimage::storage::{LocalStorage, S3Options, Storage, StoredObject};
+use kimage::ConfigError;
+use log::{error, info, warn};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
 use std::fs;
-use std::path::PathBuf;
+use std::future::Future;
+use std::io::{self, Cursor};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::num::NonZeroU32;
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, Mutex, PoisonError, RwLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use subtle::ConstantTimeEq;
+use uuid::Uuid;
+
+/// Command-line arguments for the image server
+#[derive(Parser, Debug)]
+#[command(author, version, about, long_about = None)]
+struct Args {
+    /// Config file to use instead of `$KIMAGE_CONFIG` or `~/.config/kimage.toml`
+    #[arg(long, value_name = "PATH")]
+    config: Option<PathBuf>,
+    /// Log only warnings; pass twice to log only errors
+    #[arg(short, long, action = ArgAction::Count)]
+    quiet: u8,
+    /// Log more detail; pass twice to log everything
+    #[arg(short, long, action = ArgAction::Count)]
+    verbose: u8,
+    /// Write a commented template config, with a new API key, and exit
+    #[arg(long)]
+    init: bool,
+    /// Overwrite an existing config file with `--init`
+    #[arg(long, requires = "init")]
+    force: bool,
+}
 
 /// Server configuration
 #[derive(Deserialize, Clone)]
 struct Config {
-    /// Port number for the server to listen on
+    /// Port number for the server to listen on, unless `unix_socket` is set
     port: u16,
-    /// API key for authenticating upload requests
-    api_key: String,
-    /// Path to store uploaded images
+    /// IP address of the interface to listen on; localhost only by default
+    #[serde(default = "default_bind_address")]
+    bind_address: IpAddr,
+    /// Unix domain socket to listen on instead of `bind_address` and `port`, for a local proxy
+    #[serde(default)]
+    unix_socket: Option<PathBuf>,
+    /// Permissions given to `unix_socket`, such as `0o660` to let the proxy's group connect
+    #[serde(default = "default_unix_socket_mode")]
+    unix_socket_mode: u32,
+    /// Single API key for authenticating upload requests, kept for older configs
+    #[serde(default)]
+    api_key: Option<String>,
+    /// File to read the single API key from instead, such as a mounted secret
+    #[serde(default)]
+    api_key_file: Option<PathBuf>,
+    /// Environment variable to read the single API key from instead
+    #[serde(default)]
+    api_key_env: Option<String>,
+    /// Labelled API keys for authenticating upload requests
+    #[serde(default)]
+    api_keys: Vec<ApiKey>,
+    /// Where uploaded images are kept
+    #[serde(default)]
+    backend: Backend,
+    /// Path to store uploaded images in when using the local backend
+    #[serde(default)]
     storage_path: PathBuf,
+    /// Bucket to store uploaded images in when using the S3 backend
+    #[serde(default)]
+    s3: Option<S3Options>,
+    /// SQLite database to index uploads in, so listing doesn't scan storage
+    ///
+    /// Only uploads made while the index is enabled are listed.
+    #[serde(default)]
+    index_path: Option<PathBuf>,
     /// URL of server
     server_url: String,
+    /// Path all routes are mounted under, such as `/img` behind a path-based reverse proxy;
+    /// empty to mount them at the root
+    ///
+    /// Routes are registered at startup, so changing this needs a restart.
+    #[serde(default)]
+    url_prefix: String,
+    /// Lifetime applied to uploads that don't request one, in seconds
+    #[serde(default)]
+    default_ttl_seconds: Option<u64>,
+    /// How often the background task sweeps expired images, in seconds
+    #[serde(default = "default_sweep_interval")]
+    sweep_interval_seconds: u64,
+    /// How long to wait for in-flight requests to finish when shutting down, in seconds
+    #[serde(default = "default_shutdown_timeout")]
+    shutdown_timeout_seconds: u64,
+    /// Number of worker threads serving requests, or 0 for one per CPU core
+    ///
+    /// Workers are started at startup, so changing this needs a restart.
+    #[serde(default)]
+    workers: usize,
+    /// Name uploads after a hash of their contents so identical uploads share a file
+    #[serde(default)]
+    dedupe: bool,
+    /// Largest width or height a thumbnail may be requested at
+    #[serde(default = "default_max_thumbnail_dimension")]
+    max_thumbnail_dimension: u32,
+    /// Widest image accepted or decoded, in pixels
+    #[serde(default = "default_max_image_dimension")]
+    max_width: u32,
+    /// Tallest image accepted or decoded, in pixels
+    #[serde(default = "default_max_image_dimension")]
+    max_height: u32,
+    /// Most pixels an image accepted or decoded may have, so a small file can't
+    /// decode into a huge bitmap
+    #[serde(default = "default_max_pixels")]
+    max_pixels: u64,
+    /// Path segment images are served under, empty to serve them from the root
+    ///
+    /// Routes are registered at startup, so changing this needs a restart.
+    #[serde(default = "default_image_prefix")]
+    image_prefix: String,
+    /// Public URL of an image, built from the `{base}`, `{path}` and `{filename}` placeholders
+    ///
+    /// Defaults to `{base}{path}`, where the server itself serves the image.
+    #[serde(default)]
+    url_template: Option<String>,
+    /// Maximum number of uploads each API key may make per minute
+    #[serde(default)]
+    uploads_per_minute: Option<NonZeroU32>,
+    /// Password for the HTML gallery, sent via HTTP Basic auth; the gallery is off when unset
+    #[serde(default)]
+    gallery_key: Option<String>,
+    /// Secret that links from `POST /sign` are signed with; signed links are off when unset
+    #[serde(default)]
+    signing_secret: Option<String>,
+    /// Only serve images through signed links
+    #[serde(default)]
+    require_signed_urls: bool,
+    /// PEM certificate chain to serve HTTPS with, together with `tls_key_path`
+    #[serde(default)]
+    tls_cert_path: Option<PathBuf>,
+    /// PEM private key to serve HTTPS with, together with `tls_cert_path`
+    #[serde(default)]
+    tls_key_path: Option<PathBuf>,
+    /// Origins allowed to fetch images cross-origin, e.g. `https://app.example.com`,
+    /// or `*` for any origin
+    ///
+    /// CORS is set up at startup, so changing this needs a restart.
+    #[serde(default)]
+    allowed_origins: Vec<String>,
+    /// How long clients and proxies may cache served images, in seconds
+    #[serde(default = "default_cache_max_age")]
+    cache_max_age_seconds: u32,
+    /// Image formats uploads may be in, named by extension such as `png` or `jpg`
+    #[serde(default = "default_allowed_formats")]
+    allowed_formats: Vec<String>,
+    /// How filenames are generated for uploads that don't choose one
+    #[serde(default)]
+    filename_strategy: FilenameStrategy,
+    /// How new uploads are spread over subdirectories of storage
+    #[serde(default)]
+    storage_layout: StorageLayout,
+    /// How each request is written to the access log
+    #[serde(default)]
+    access_log: AccessLogFormat,
+    /// What requests for missing images are answered with
+    #[serde(default)]
+    not_found: NotFoundResponse,
+    /// Image served for missing images when `not_found` is `"image"`; relative paths are
+    /// resolved against the home directory
+    #[serde(default)]
+    not_found_image: Option<PathBuf>,
+    /// Take client addresses from `X-Forwarded-For` and `Forwarded`, for servers behind a proxy
+    ///
+    /// Only enable this when a proxy sets those headers, since clients can forge them.
+    #[serde(default)]
+    trust_proxy_headers: bool,
+    /// Most bytes stored images may take up in total; unlimited when unset
+    ///
+    /// Thumbnails and metadata aren't counted.
+    #[serde(default)]
+    max_total_bytes: Option<u64>,
+    /// What to do with uploads that would take storage past `max_total_bytes`
+    #[serde(default)]
+    quota_policy: QuotaPolicy,
+    /// Format of the server's logs, overridden by the `KIMAGE_LOG_FORMAT` environment variable
+    #[serde(default)]
+    log_format: LogFormat,
+    /// Serve Prometheus metrics at `/metrics`
+    ///
+    /// Routes are registered at startup, so changing this needs a restart.
+    #[serde(default)]
+    metrics: bool,
+    /// Port to serve `/metrics` on instead of the main port, so it can be kept private
+    #[serde(default)]
+    metrics_port: Option<u16>,
+    /// Seconds a resumable upload may go without receiving data before it is abandoned
+    #[serde(default = "default_resumable_upload_timeout")]
+    resumable_upload_timeout_seconds: u64,
+    /// URL to POST a JSON notification to after each successful upload
+    #[serde(default)]
+    webhook_url: Option<String>,
+    /// Number of random characters in filenames generated by the `random` strategy
+    #[serde(default = "default_filename_length")]
+    filename_length: usize,
+    /// Compress responses with gzip, deflate, brotli or zstd when the client accepts it
+    ///
+    /// Images other than SVG are sent as they are, since their formats are compressed already.
+    /// The middleware is set up at startup, so changing this needs a restart.
+    #[serde(default = "default_compress")]
+    compress: bool,
+    /// Hosts `POST /upload-url` may fetch images from, such as `example.com` or
+    /// `*.example.com` for its subdomains; empty disables it
+    #[serde(default)]
+    fetch_allowed_hosts: Vec<String>,
+    /// Largest image `POST /upload-url` will fetch, in bytes
+    #[serde(default = "default_fetch_max_bytes")]
+    fetch_max_bytes: u64,
+    /// Record a perceptual hash of each upload in the index, so `/similar/...` can find
+    /// near-duplicates
+    ///
+    /// Needs `index_path`. Every upload is decoded to hash it, which costs CPU.
+    #[serde(default)]
+    perceptual_hash: bool,
+    /// Most bits two perceptual hashes may differ by for `/similar/...` to report the image
+    #[serde(default = "default_similar_max_distance")]
+    similar_max_distance: u32,
 }
 
-/// Response structure for successful uploads
-#[derive(Serialize)]
-struct UploadResponse {
-    /// URL of the uploaded image
-    url: String,
+fn default_sweep_interval() -> u64 {
+    300
 }
 
-/// Handle image upload requests
-async fn upload(req: HttpRequest, mut payload: Multipart) -> Result<HttpResponse, Error> {
-    let config = load_config().map_err(|e| {
-        error!("Failed to load config: {}", e);
-        actix_web::error::ErrorInternalServerError("Failed to load config")
-    })?;
+fn default_shutdown_timeout() -> u64 {
+    30
+}
 
-    // Check authorization
-    let auth_header = req
-        .headers()
-        .get("Authorization")
-        .and_then(|h| h.to_str().ok())
-        .ok_or_else(|| {
-            error!("Missing Authorization header");
-            actix_web::error::ErrorUnauthorized("Missing Authorization header")
-        })?;
+fn default_max_thumbnail_dimension() -> u32 {
+    2048
+}
+
+fn default_max_image_dimension() -> u32 {
+    16384
+}
+
+fn default_max_pixels() -> u64 {
+    100_000_000
+}
+
+fn default_image_prefix() -> String {
+    "i".to_string()
+}
+
+fn default_cache_max_age() -> u32 {
+    86400
+}
+
+fn default_unix_socket_mode() -> u32 {
+    0o660
+}
+
+fn default_allowed_formats() -> Vec<String> {
+    ["png", "jpeg", "gif", "webp"].map(str::to_owned).to_vec()
+}
+
+fn default_resumable_upload_timeout() -> u64 {
+    3600
+}
 
-    if auth_header != config.api_key {
-        info!("Unauthorized access attempt");
-        return Ok(HttpResponse::Unauthorized().finish());
+fn default_similar_max_distance() -> u32 {
+    10
+}
+
+fn default_compress() -> bool {
+    true
+}
+
+fn default_fetch_max_bytes() -> u64 {
+    20 * 1024 * 1024
+}
+
+fn default_filename_length() -> usize {
+    10
+}
+
+/// Fewest random characters a generated filename may have, so names stay hard to guess
+const MIN_FILENAME_LENGTH: usize = 6;
+
+/// Longest generated filename stem, matching the limit on client-chosen names
+const MAX_FILENAME_LENGTH: usize = 64;
+
+/// How many random names to try before giving up on finding a free one
+const FILENAME_ATTEMPTS: usize = 5;
+
+/// Random characters appended to timestamp filenames to tell apart uploads in the same second
+const TIMESTAMP_SUFFIX_LENGTH: usize = 4;
+
+/// How many times a webhook notification is attempted before giving up
+const WEBHOOK_ATTEMPTS: u32 = 3;
+
+/// How long each webhook request may take before it is abandoned
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Wait before retrying a failed webhook notification, doubled for each further retry
+const WEBHOOK_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// Default interface to listen on
+fn default_bind_address() -> IpAddr {
+    IpAddr::V4(Ipv4Addr::LOCALHOST)
+}
+
+/// Storage backends uploaded images can be kept in
+#[derive(Deserialize, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+enum Backend {
+    /// A directory on disk, set by `storage_path`
+    #[default]
+    Local,
+    /// An S3-compatible bucket, set by the `[s3]` section
+    S3,
+}
+
+/// What happens to uploads that don't fit in the storage quota
+#[derive(Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+enum QuotaPolicy {
+    /// Refuse the upload with `507 Insufficient Storage`
+    #[default]
+    Reject,
+    /// Delete the least recently modified images until the upload fits
+    Evict,
+}
+
+/// Ways of naming uploads that don't choose their own name
+#[derive(Deserialize, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+enum FilenameStrategy {
+    /// Random letters and digits, `filename_length` of them
+    #[default]
+    Random,
+    /// The UTC upload time plus a short random suffix, e.g. `20240115-143022-x9Qa`
+    Timestamp,
+    /// A time-ordered version 7 UUID
+    Uuid,
+}
+
+/// Ways of spreading uploads over directories, so none grows too large
+///
+/// The directories become part of the image's name and URL. Changing the
+/// layout only affects new uploads; existing images keep their paths.
+#[derive(Deserialize, Clone, Copy, Default)]
+#[serde(rename_all = "kebab-case")]
+enum StorageLayout {
+    /// Every image directly in the storage root
+    #[default]
+    Flat,
+    /// A directory per UTC upload day, e.g. `2024/01/15/abc.png`
+    Date,
+    /// Two levels of directories taken from a hash of the filename, e.g. `3f/a1/abc.png`
+    HashPrefix,
+}
+
+impl StorageLayout {
+    /// Storage key, and path in the image URL, for a new upload called `filename`
+    fn key(self, filename: &str) -> String {
+        match self {
+            Self::Flat => filename.to_owned(),
+            Self::Date => format!("{}/{}", Utc::now().format("%Y/%m/%d"), filename),
+            Self::HashPrefix => {
+                let hash = hex_string(&Sha256::digest(filename.as_bytes())[..2]);
+                format!("{}/{}/{}", &hash[..2], &hash[2..], filename)
+            }
+        }
     }
 
-    // Process the multipart form data
-    while let Ok(Some(mut field)) = payload.try_next().await {
-        let content_type = field.content_disposition();
-        if let Some(name) = content_type.get_name() {
-            if name == "image" {
-                // Collect all chunks of the file
-                let mut bytes = Vec::new();
-                while let Some(chunk) = field.next().await {
-                    let data = chunk.map_err(|e| {
-                        error!("Failed to read multipart data: {}", e);
-                        actix_web::error::ErrorInternalServerError("Failed to read multipart data")
-                    })?;
-                    bytes.extend_from_slice(&data);
-                }
+    /// Storage key for a content-addressed `filename`, which must not depend on the upload day
+    ///
+    /// The date layout falls back to hash prefixes, so identical images uploaded on
+    /// different days still end up at the same key.
+    fn content_key(self, filename: &str) -> String {
+        match self {
+            Self::Date => Self::HashPrefix.key(filename),
+            layout => layout.key(filename),
+        }
+    }
+}
 
-                // Decode the base64 image data
-                let decoded = general_purpose::STANDARD.decode(&bytes).map_err(|e| {
-                    error!("Invalid base64 data: {}", e);
-                    actix_web::error::ErrorBadRequest("Invalid base64 data")
-                })?;
+/// Formats the access log can be written in
+#[derive(Deserialize, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+enum AccessLogFormat {
+    /// No access log
+    Off,
+    /// One human-readable line per request
+    #[default]
+    Plain,
+    /// One JSON object per request
+    Json,
+}
+
+/// What requests for missing images are answered with, always with a 404 status
+#[derive(Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+enum NotFoundResponse {
+    /// A JSON error, like the rest of the API
+    #[default]
+    Json,
+    /// A small HTML page, friendlier in a browser
+    Html,
+    /// The image at `not_found_image`
+    Image,
+}
+
+/// Label of the API key a request was authorized with, recorded for the access log
+struct KeyLabel(String);
+
+/// State shared between request handlers
+struct AppState {
+    /// Where the config was loaded from, so it can be reloaded
+    config_path: PathBuf,
+    /// The active configuration, replaced wholesale on reload
+    config: RwLock<Arc<Config>>,
+    /// Upload rate limiters, keyed by API key label
+    rate_limiters: Mutex<HashMap<String, DefaultDirectRateLimiter>>,
+    /// Where images are kept, opened once at startup
+    storage: Arc<dyn Storage>,
+    /// Index of uploads, if `index_path` is set; opened once at startup
+    index: Option<Arc<Index>>,
+    /// Running total of bytes taken up by stored images, counted when the quota is first checked
+    stored_bytes: tokio::sync::Mutex<Option<u64>>,
+    /// Client webhook notifications are sent with
+    http_client: reqwest::Client,
+    /// Client images are fetched with for `POST /upload-url`, which doesn't follow redirects
+    /// by itself so each one can be checked against `fetch_allowed_hosts`
+    fetch_client: reqwest::Client,
+    /// Counters and histograms exported at `/metrics`
+    metrics: Metrics,
+    /// Resumable uploads that are still receiving data, keyed by ID
+    resumable_uploads: Mutex<HashMap<String, ResumableUpload>>,
+    /// Directory the data of resumable uploads is collected in until they're complete
+    partial_upload_dir: PathBuf,
+    /// The last answer to `GET /stats` and when it was worked out
+    stats: tokio::sync::Mutex<Option<(Instant, StatsResponse)>>,
+    /// Source of generated filenames, which tests replace with a seeded one
+    filename_rng: Mutex<StdRng>,
+}
+
+impl AppState {
+    fn new(
+        config_path: PathBuf,
+        config: Config,
+        storage: Arc<dyn Storage>,
+        index: Option<Arc<Index>>,
+        metrics: Metrics,
+    ) -> Self {
+        Self {
+            config_path,
+            partial_upload_dir: partial_upload_dir(&config),
+            config: RwLock::new(Arc::new(config)),
+            rate_limiters: Mutex::new(HashMap::new()),
+            storage,
+            index,
+            stored_bytes: tokio::sync::Mutex::new(None),
+            http_client: reqwest::Client::new(),
+            fetch_client: reqwest::Client::builder()
+                .redirect(reqwest::redirect::Policy::none())
+                .timeout(FETCH_TIMEOUT)
+                .build()
+                .expect("Failed to create HTTP client"),
+            metrics,
+            resumable_uploads: Mutex::new(HashMap::new()),
+            stats: tokio::sync::Mutex::new(None),
+            filename_rng: Mutex::new(StdRng::from_entropy()),
+        }
+    }
+
+    /// Snapshot of the current configuration
+    fn config(&self) -> Arc<Config> {
+        self.config
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+
+    /// Re-read the config file, keeping the current config if it fails to load
+    ///
+    /// Settings used to start the server, such as the port or storage backend,
+    /// only take effect on restart.
+    fn reload(&self) -> Result<()> {
+        let config = load_config(&self.config_path)?;
+        *self.config.write().unwrap_or_else(PoisonError::into_inner) = Arc::new(config);
+        // Limiters are rebuilt lazily so a changed quota takes effect
+        self.rate_limiters
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clear();
+        Ok(())
+    }
 
-                // Generate a unique filename and save the image
-                let filename = generate_filename();
-                let file_path = config.storage_path.join(&filename);
-                info!("Saving file to: {:?}", file_path);
-                fs::write(&file_path, &decoded).map_err(|e| {
-                    error!("Failed to write file: {}", e);
-                    actix_web::error::ErrorInternalServerError("Failed to write file")
+    /// Make room for an upload of `size` bytes under `max_total_bytes`, and count it as stored
+    ///
+    /// Under the `evict` policy the least recently modified images are deleted
+    /// until the upload fits. Call [`AppState::release_storage`] if the upload
+    /// isn't stored after all.
+    async fn reserve_storage(&self, config: &Config, size: u64) -> Result<(), ApiError> {
+        let Some(max_total_bytes) = config.max_total_bytes else {
+            return Ok(());
+        };
+        let fits = |total: u64| total.saturating_add(size) <= max_total_bytes;
+        let mut stored_bytes = self.stored_bytes.lock().await;
+        let total = match *stored_bytes {
+            Some(total) if fits(total) => total,
+            // Recount before refusing or evicting, in case the running total has drifted
+            _ => {
+                let mut images = stored_images(self.storage.as_ref()).await.map_err(|e| {
+                    error!("Failed to measure storage: {:#}", e);
+                    ApiError::internal("storage_error", "Failed to measure storage")
                 })?;
+                // Resumable uploads hold their reservation until they complete
+                let mut total = images.iter().map(|image| image.size).sum::<u64>()
+                    + self.resumable_reserved_bytes();
+                if !fits(total)
+                    && config.quota_policy == QuotaPolicy::Evict
+                    && size <= max_total_bytes
+                {
+                    images.sort_by_key(|image| image.modified);
+                    for image in images {
+                        if fits(total) {
+                            break;
+                        }
+                        match self.evict(&image.key).await {
+                            Ok(freed) => total = total.saturating_sub(freed),
+                            Err(e) => {
+                                error!("Failed to evict {}: {:#}", image.key, e);
+                                break;
+                            }
+                        }
+                    }
+                }
+                *stored_bytes = Some(total);
+                if !fits(total) {
+                    info!(
+                        "Rejected upload of {} bytes: {} of {} bytes are stored",
+                        size, total, max_total_bytes
+                    );
+                    return Err(ApiError::new(
+                        StatusCode::INSUFFICIENT_STORAGE,
+                        "quota_exceeded",
+                        "Not enough storage left for this image",
+                    ));
+                }
+                total
+            }
+        };
+        *stored_bytes = Some(total + size);
+        Ok(())
+    }
+
+    /// Stop counting `size` bytes of stored images, after an image is removed or an upload fails
+    async fn release_storage(&self, size: u64) {
+        if let Some(total) = self.stored_bytes.lock().await.as_mut() {
+            *total = total.saturating_sub(size);
+        }
+    }
+
+    /// Bytes reserved by resumable uploads that haven't completed yet
+    fn resumable_reserved_bytes(&self) -> u64 {
+        self.resumable_uploads
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .values()
+            .map(|upload| upload.size)
+            .sum()
+    }
+
+    /// Number of resumable uploads the key labelled `key_label` has open
+    fn open_resumable_uploads(&self, key_label: &str) -> usize {
+        self.resumable_uploads
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .values()
+            .filter(|upload| upload.key_label == key_label)
+            .count()
+    }
+
+    /// File the data of a resumable upload is collected in
+    fn partial_upload_path(&self, id: &str) -> PathBuf {
+        self.partial_upload_dir.join(id)
+    }
+
+    /// Run `f` on a resumable upload, which only the key that started it may touch
+    fn with_resumable_upload<T>(
+        &self,
+        id: &str,
+        key_label: &str,
+        f: impl FnOnce(&mut ResumableUpload) -> T,
+    ) -> Result<T, ApiError> {
+        let mut uploads = self
+            .resumable_uploads
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        match uploads.get_mut(id) {
+            Some(upload) if upload.key_label == key_label => Ok(f(upload)),
+            _ => Err(ApiError::not_found("No such upload")),
+        }
+    }
 
-                // Construct and return the URL of the uploaded image
-                let url = format!("{}/{}", config.server_url, filename);
-                info!("File uploaded successfully: {}", url);
-                return Ok(HttpResponse::Ok().json(UploadResponse { url }));
+    /// Forget resumable uploads that haven't received data for `timeout`, deleting their
+    /// data and releasing their storage, and return how many there were
+    async fn remove_abandoned_uploads(&self, timeout: Duration) -> usize {
+        let (abandoned, reserved) = {
+            let mut uploads = self
+                .resumable_uploads
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner);
+            let abandoned: Vec<String> = uploads
+                .iter()
+                .filter(|(_, upload)| !upload.busy && upload.last_activity.elapsed() >= timeout)
+                .map(|(id, _)| id.clone())
+                .collect();
+            let reserved: u64 = abandoned.iter().map(|id| uploads[id].size).sum();
+            uploads.retain(|id, _| !abandoned.contains(id));
+            (abandoned, reserved)
+        };
+        self.release_storage(reserved).await;
+        for id in &abandoned {
+            let path = self.partial_upload_path(id);
+            if let Err(e) = tokio::fs::remove_file(&path).await {
+                if e.kind() != io::ErrorKind::NotFound {
+                    warn!("Failed to remove abandoned upload {:?}: {}", path, e);
+                }
             }
         }
+        abandoned.len()
+    }
+
+    /// Delete an image to make room for an upload, returning how many bytes were freed
+    async fn evict(&self, filename: &str) -> Result<u64> {
+        let freed = remove_image(self.storage.as_ref(), filename).await?;
+        if let Some(index) = self.index.clone() {
+            let name = filename.to_owned();
+            blocking(move || index.remove(&name)).await?;
+        }
+        warn!(
+            "Evicted {} ({} bytes) to stay within the storage quota",
+            filename, freed
+        );
+        Ok(freed)
     }
 
-    error!("Bad request: No image field found in payload");
-    Ok(HttpResponse::BadRequest().finish())
+    /// Count an upload against a key's quota
+    ///
+    /// Returns how long to wait before retrying if the quota is exhausted.
+    fn check_rate_limit(&self, label: &str, per_minute: NonZeroU32) -> Result<(), Duration> {
+        let mut limiters = self
+            .rate_limiters
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        let limiter = limiters
+            .entry(label.to_owned())
+            .or_insert_with(|| RateLimiter::direct(Quota::per_minute(per_minute)));
+        limiter
+            .check()
+            .map_err(|not_until| not_until.wait_time_from(DefaultClock::default().now()))
+    }
 }
 
-/// Serve previously uploaded images
-async fn serve_image(filename: web::Path<String>) -> Result<HttpResponse, Error> {
-    let config = load_config().map_err(|e| {
-        error!("Failed to load config: {}", e);
-        actix_web::error::ErrorInternalServerError("Failed to load config")
-    })?;
+/// An API key and the label it is logged under
+#[derive(Deserialize, Clone)]
+struct ApiKey {
+    /// Human-readable name for whoever holds the key
+    label: String,
+    /// The key itself
+    key: String,
+}
 
-    let file_path = config.storage_path.join(filename.as_str());
-    if file_path.exists() {
-        let contents = fs::read(&file_path).map_err(|e| {
-            error!("Failed to read file {:?}: {}", file_path, e);
-            actix_web::error::ErrorInternalServerError("Failed to read file")
-        })?;
-        info!("Serving image: {:?}", file_path);
-        Ok(HttpResponse::Ok().content_type("image/png").body(contents))
+impl Config {
+    /// Check every setting, reporting all the problems found rather than just the first
+    fn validate(&self) -> Result<(), ConfigError> {
+        let mut problems = Vec::new();
+
+        if self.port == 0 && self.unix_socket.is_none() {
+            problems.push("port must not be 0".to_string());
+        }
+        if self.metrics_port == Some(0) {
+            problems.push("metrics_port must not be 0".to_string());
+        }
+        if self.unix_socket_mode > 0o777 {
+            problems.push(format!(
+                "unix_socket_mode {:o} is not a permission mode such as 0o660",
+                self.unix_socket_mode
+            ));
+        }
+        if reqwest::Url::parse(&self.server_url).is_err() {
+            problems.push(format!("server_url {:?} is not a URL", self.server_url));
+        }
+        if self.api_key.is_none() && self.api_keys.is_empty() {
+            problems.push(
+                "api_key (or api_key_file or api_key_env) or api_keys must be set".to_string(),
+            );
+        }
+        if self.api_key.as_deref() == Some("") {
+            problems.push("api_key must not be empty".to_string());
+        }
+        for api_key in self
+            .api_keys
+            .iter()
+            .filter(|api_key| api_key.key.is_empty())
+        {
+            problems.push(format!("The key labelled {:?} is empty", api_key.label));
+        }
+
+        match self.backend {
+            Backend::Local if self.storage_path.as_os_str().is_empty() => {
+                problems.push("storage_path must be set when using the local backend".to_string())
+            }
+            Backend::Local => {
+                if let Some(problem) = uncreatable_dir(&self.storage_path) {
+                    problems.push(format!("storage_path is unusable: {}", problem));
+                }
+            }
+            Backend::S3 if self.s3.is_none() => {
+                problems.push("An [s3] section must be set when using the s3 backend".to_string())
+            }
+            Backend::S3 => {}
+        }
+
+        if !(MIN_FILENAME_LENGTH..=MAX_FILENAME_LENGTH).contains(&self.filename_length) {
+            problems.push(format!(
+                "filename_length must be between {} and {}",
+                MIN_FILENAME_LENGTH, MAX_FILENAME_LENGTH
+            ));
+        }
+        if self.max_width == 0 || self.max_height == 0 || self.max_pixels == 0 {
+            problems.push("max_width, max_height and max_pixels must be at least 1".to_string());
+        }
+        for name in &self.allowed_formats {
+            if ImageFormat::from_extension(name).is_none() {
+                problems.push(format!(
+                    "Unknown image format in allowed_formats: {:?}",
+                    name
+                ));
+            }
+        }
+        if let Some(webhook_url) = &self.webhook_url {
+            if let Err(e) = reqwest::Url::parse(webhook_url) {
+                problems.push(format!("Invalid webhook_url {:?}: {}", webhook_url, e));
+            }
+        }
+        if let Some(template) = &self.url_template {
+            let unknown = URL_TEMPLATE_PLACEHOLDERS
+                .iter()
+                .fold(template.clone(), |rest, placeholder| {
+                    rest.replace(placeholder, "")
+                })
+                .contains(['{', '}']);
+            if unknown {
+                problems.push(format!(
+                    "url_template may only use the placeholders {}",
+                    URL_TEMPLATE_PLACEHOLDERS.join(", ")
+                ));
+            }
+            if !template.contains("{path}") && !template.contains("{filename}") {
+                problems.push("url_template must include {path} or {filename}".to_string());
+            }
+        }
+        if self.signing_secret.as_deref() == Some("") {
+            problems.push("signing_secret must not be empty".to_string());
+        }
+        if self.require_signed_urls && self.signing_secret.is_none() {
+            problems.push("require_signed_urls needs signing_secret to be set".to_string());
+        }
+        if self.not_found == NotFoundResponse::Image && self.not_found_image.is_none() {
+            problems.push("not_found = \"image\" needs not_found_image to be set".to_string());
+        }
+        if self.perceptual_hash && self.index_path.is_none() {
+            problems.push("perceptual_hash needs index_path to be set".to_string());
+        }
+        if self.similar_max_distance > PERCEPTUAL_HASH_BITS {
+            problems.push(format!(
+                "similar_max_distance must be at most {}",
+                PERCEPTUAL_HASH_BITS
+            ));
+        }
+        for origin in &self.allowed_origins {
+            if origin != "*" && !is_valid_origin(origin) {
+                problems.push(format!("Invalid entry in allowed_origins: {:?}", origin));
+            }
+        }
+
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(ConfigError::Invalid(problems))
+        }
+    }
+
+    /// Find the label of the configured key matching `provided`, if any
+    ///
+    /// The legacy `api_key` field is reported under the label `default`.
+    /// Every key is compared so the time taken doesn't reveal which one matched.
+    fn authenticate(&self, provided: &str) -> Option<&str> {
+        let legacy = self.api_key.as_deref().map(|key| ("default", key));
+        let labelled = self
+            .api_keys
+            .iter()
+            .map(|api_key| (api_key.label.as_str(), api_key.key.as_str()));
+
+        let mut matched = None;
+        for (label, key) in legacy.into_iter().chain(labelled) {
+            if api_key_matches(provided, key) && matched.is_none() {
+                matched = Some(label);
+            }
+        }
+        matched
+    }
+
+    /// Largest images the server accepts and will decode
+    fn dimension_limits(&self) -> DimensionLimits {
+        DimensionLimits {
+            max_width: self.max_width,
+            max_height: self.max_height,
+            max_pixels: self.max_pixels,
+        }
+    }
+
+    /// Prefix all routes are mounted under, as `/path` or empty for the root
+    fn url_route_prefix(&self) -> String {
+        route_prefix(&self.url_prefix)
+    }
+
+    /// Route prefix images are served under within the mount, as `/segment` or empty for the root
+    fn image_route_prefix(&self) -> String {
+        route_prefix(&self.image_prefix)
+    }
+
+    /// Path a stored image is served at, relative to the server's origin
+    fn image_path(&self, filename: &str) -> String {
+        format!(
+            "{}{}/{}",
+            self.url_route_prefix(),
+            self.image_route_prefix(),
+            filename
+        )
+    }
+
+    /// Public URL of a stored image, as `url_template` says
+    fn image_url(&self, filename: &str) -> String {
+        match &self.url_template {
+            Some(template) => template
+                .replace("{base}", &self.server_url)
+                .replace("{path}", &self.image_path(filename))
+                .replace("{filename}", filename),
+            None => format!("{}{}", self.server_url, self.image_path(filename)),
+        }
+    }
+
+    /// Public URL of an alias
+    fn alias_url(&self, alias: &str) -> String {
+        format!("{}{}/{}", self.server_url, self.url_route_prefix(), alias)
+    }
+}
+
+/// Placeholders `url_template` may use
+const URL_TEMPLATE_PLACEHOLDERS: &[&str] = &["{base}", "{path}", "{filename}"];
+
+/// Normalize a configured path such as `img/` or `/a/b/` to `/img` or `/a/b`, or empty for the root
+fn route_prefix(path: &str) -> String {
+    let path = path.trim_matches('/');
+    if path.is_empty() {
+        String::new()
     } else {
-        info!("Image not found: {:?}", file_path);
-        Ok(HttpResponse::NotFound().finish())
+        format!("/{}", path)
     }
 }
 
-#[actix_web::main]
-async fn main() -> Result<()> {
-    // Initialize the logger
-    std::env::set_var("RUST_LOG", "info");
-    pretty_env_logger::init();
+/// Metadata recorded alongside each upload under `.meta/`
+#[derive(Serialize, Deserialize)]
+struct ImageMetadata {
+    /// Unix timestamp of when the image was uploaded
+    uploaded_at: u64,
+    /// Unix timestamp after which the image is no longer served
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    expires_at: Option<u64>,
+    /// Content type sniffed from the uploaded bytes, sent when serving the image
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    content_type: Option<String>,
+    /// Salted hash of the token needed to view the image, as made by [`hash_token`]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    token_hash: Option<String>,
+    /// Delete the image once it has been viewed
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    burn: bool,
+}
+
+impl ImageMetadata {
+    /// Whether the image has outlived its TTL at time `now`
+    fn is_expired(&self, now: u64) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= now)
+    }
+}
 
-    // Load the server configuration
-    let config = load_config()?;
-    let port = config.port;
+/// Query parameters accepted when serving an image
+#[derive(Deserialize)]
+struct ServeQuery {
+    /// Maximum width of a thumbnail to serve instead of the original
+    w: Option<u32>,
+    /// Maximum height of a thumbnail to serve instead of the original
+    h: Option<u32>,
+    /// Access token of a password-protected image, also accepted as `X-Image-Token`
+    token: Option<String>,
+    /// Format to convert the image to before serving it
+    format: Option<ConvertFormat>,
+    /// Encoding quality (1-100) of a conversion to JPEG
+    quality: Option<u8>,
+    /// When a signed link expires, as a Unix timestamp
+    exp: Option<u64>,
+    /// Signature of a signed link, made by [`url_signature`]
+    sig: Option<String>,
+}
 
-    info!("Server running on http://localhost:{}", port);
+/// Quality of conversions to JPEG that don't ask for one
+const DEFAULT_JPEG_QUALITY: u8 = 90;
 
-    // Start the HTTP server
-    HttpServer::new(move || {
-        App::new()
-            .route("/upload", web::post().to(upload))
-            .route("/{filename}", web::get().to(serve_image))
-    })
-    .bind(("127.0.0.1", port))?
-    .run()
-    .await
-    .context("Error running server")
+/// Formats images can be converted to when served
+#[derive(Deserialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+enum ConvertFormat {
+    Png,
+    #[serde(alias = "jpg")]
+    Jpeg,
+    /// Encoded losslessly, as that's the only WebP encoding available
+    Webp,
 }
 
-/// Load the server configuration from a TOML file
-fn load_config() -> Result<Config> {
-    let config_path = home_dir()
-        .context("Failed to get home directory")?
-        .join(".config")
-        .join("kimage.toml");
+impl ConvertFormat {
+    /// The `image` crate format this corresponds to
+    fn image_format(self) -> ImageFormat {
+        match self {
+            ConvertFormat::Png => ImageFormat::Png,
+            ConvertFormat::Jpeg => ImageFormat::Jpeg,
+            ConvertFormat::Webp => ImageFormat::WebP,
+        }
+    }
+}
 
-    info!("Loading config from: {:?}", config_path);
-    let config_str = fs::read_to_string(&config_path).context("Failed to read config file")?;
+/// An encoding requested with `?format=` and `?quality=`
+#[derive(Clone, Copy)]
+struct Conversion {
+    format: ConvertFormat,
+    /// JPEG quality; `None` for lossless formats
+    quality: Option<u8>,
+}
 
-    let mut config: Config = toml::from_str(&config_str).context("Failed to parse config file")?;
+impl ServeQuery {
+    /// Whether a scaled-down copy was asked for
+    fn wants_thumbnail(&self) -> bool {
+        self.w.is_some() || self.h.is_some()
+    }
 
-    // Convert relative storage path to absolute
-    if config.storage_path.is_relative() {
-        config.storage_path = home_dir()
-            .context("Failed to get home directory")?
-            .join(&config.storage_path);
+    /// The conversion asked for, if any, checking the quality suits the format
+    fn conversion(&self) -> Result<Option<Conversion>, ApiError> {
+        let invalid_quality =
+            |message: &str| Err(ApiError::bad_request("invalid_quality", message.to_owned()));
+        match (self.format, self.quality) {
+            (None, None) => Ok(None),
+            (Some(ConvertFormat::Jpeg), quality) => match quality.unwrap_or(DEFAULT_JPEG_QUALITY) {
+                quality @ 1..=100 => Ok(Some(Conversion {
+                    format: ConvertFormat::Jpeg,
+                    quality: Some(quality),
+                })),
+                _ => invalid_quality("Quality must be between 1 and 100"),
+            },
+            (Some(format), None) => Ok(Some(Conversion {
+                format,
+                quality: None,
+            })),
+            (_, Some(_)) => invalid_quality("Quality only applies to format=jpeg"),
+        }
     }
+}
 
-    info!("Config loaded successfully");
-    Ok(config)
+/// Response structure for successful uploads
+#[derive(Serialize)]
+struct UploadResponse {
+    /// URL of the uploaded image
+    url: String,
+    /// Key the image is stored under, including any layout directories, as `/sign` and
+    /// `/alias` take it
+    filename: String,
+    /// Width of the image in pixels
+    width: u32,
+    /// Height of the image in pixels
+    height: u32,
+    /// Size of the stored file in bytes
+    bytes: u64,
+    /// Content type the image is served with
+    content_type: String,
+    /// When the image expires, as an RFC 3339 timestamp; left out for images that don't
+    #[serde(skip_serializing_if = "Option::is_none")]
+    expires_at: Option<String>,
+    /// Whether an identical image was already stored, so nothing new was written
+    existing: bool,
 }
 
-/// Generate a random filename for uploaded images
-fn generate_filename() -> String {
-    let mut rng = rand::thread_rng();
-    let random_string: String = (0..10)
-        .map(|_| rng.sample(rand::distributions::Alphanumeric) as char)
-        .collect();
-    format!("{}.png", random_string)
+/// JSON body POSTed to `webhook_url` after a successful upload
+#[derive(Serialize)]
+struct WebhookEvent {
+    /// What happened; always `upload` for now
+    event: &'static str,
+    /// Name the image is stored under
+    filename: String,
+    /// URL the image is served at
+    url: String,
+    /// Size of the stored file in bytes
+    size: u64,
+    /// Label of the API key the upload was made with
+    key_label: String,
+    /// When the upload was made, as an RFC 3339 timestamp
+    timestamp: String,
+}
+
+/// JSON body sent with every error response
+#[derive(Serialize)]
+struct ErrorResponse {
+    /// Human-readable description of what went wrong
+    error: String,
+    /// Stable machine-readable error identifier
+    code: String,
+}
+
+/// An error that is reported to the client as an [`ErrorResponse`]
+#[derive(Debug)]
+struct ApiError {
+    status: StatusCode,
+    code: &'static str,
+    message: String,
+    /// Extra headers to send with the response, such as `Retry-After`
+    headers: Vec<(HeaderName, String)>,
+}
+
+impl ApiError {
+    fn new(status: StatusCode, code: &'static str, message: impl Into<String>) -> Self {
+        Self {
+            status,
+            code,
+            message: message.into(),
+            headers: Vec::new(),
+        }
+    }
+
+    fn with_header(mut self, name: HeaderName, value: impl ToString) -> Self {
+        self.headers.push((name, value.to_string()));
+        self
+    }
+
+    fn bad_request(code: &'static str, message: impl Into<String>) -> Self {
+        Self::new(StatusCode::BAD_REQUEST, code, message)
+    }
+
+    fn unauthorized(code: &'static str, message: impl Into<String>) -> Self {
+        Self::new(StatusCode::UNAUTHORIZED, code, message)
+    }
+
+    fn not_found(message: impl Into<String>) -> Self {
+        Self::new(StatusCode::NOT_FOUND, "not_found", message)
+    }
+
+    fn internal(code: &'static str, message: impl Into<String>) -> Self {
+        Self::new(StatusCode::INTERNAL_SERVER_ERROR, code, message)
+    }
+
+    fn too_many_requests(retry_after: Duration) -> Self {
+        // Round up so clients never retry before the quota has refilled
+        let seconds = retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0);
+        Self::new(
+            StatusCode::TOO_MANY_REQUESTS,
+            "rate_limited",
+            "Upload rate limit exceeded",
+        )
+        .with_header(header::RETRY_AFTER, seconds)
+    }
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl ResponseError for ApiError {
+    fn status_code(&self) -> StatusCode {
+        self.status
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        let mut response = HttpResponse::build(self.status);
+        for (name, value) in &self.headers {
+            response.insert_header((name.clone(), value.as_str()));
+        }
+        response.json(ErrorResponse {
+            error: self.message.clone(),
+            code: self.code.to_string(),
+        })
+    }
+}
+
+/// Response structure for the health check
+#[derive(Serialize)]
+struct HealthResponse {
+    /// Always `ok`; failures are reported as an [`ErrorResponse`]
+    status: &'static str,
+}
+
+/// Report whether the server is able to accept uploads
+async fn health(state: web::Data<AppState>) -> Result<HttpResponse, ApiError> {
+    state.storage.check_writable().await.map_err(|e| {
+        error!("Health check failed: {:#}", e);
+        ApiError::new(
+            StatusCode::SERVICE_UNAVAILABLE,
+            "storage_unavailable",
+            format!("{:#}", e),
+        )
+    })?;
+    Ok(HttpResponse::Ok().json(HealthResponse { status: "ok" }))
+}
+
+/// Check the request's `Authorization` header, returning the matching key's label
+///
+/// The header holds either the raw key or Basic credentials with the key as the
+/// password or the username, as tools given a URL like `https://KEY@host/upload` send.
+fn authorize<'a>(req: &HttpRequest, config: &'a Config) -> Result<&'a str, ApiError> {
+    let auth_header = req
+        .headers()
+        .get("Authorization")
+        .and_then(|h| h.to_str().ok())
+        .ok_or_else(|| {
+            error!("Missing Authorization header");
+            ApiError::unauthorized("missing_authorization", "Missing Authorization header")
+        })?;
+
+    let label = config
+        .authenticate(auth_header)
+        .or_else(|| {
+            let (username, password) = basic_auth_credentials(req)?;
+            config
+                .authenticate(&password)
+                .or_else(|| config.authenticate(&username))
+        })
+        .ok_or_else(|| {
+            info!("Unauthorized access attempt");
+            ApiError::unauthorized("invalid_api_key", "Invalid API key")
+        })?;
+    req.extensions_mut().insert(KeyLabel(label.to_owned()));
+    Ok(label)
+}
+
+/// Query parameters for listing stored images
+#[derive(Deserialize)]
+struct ListQuery {
+    /// Number of images to skip
+    #[serde(default)]
+    offset: usize,
+    /// Maximum number of images to return
+    #[serde(default = "default_list_limit")]
+    limit: usize,
+    /// Order by modification time
+    #[serde(default)]
+    sort: SortOrder,
+}
+
+fn default_list_limit() -> usize {
+    50
+}
+
+/// Most images a single listing page may return
+const MAX_LIST_LIMIT: usize = 1000;
+
+/// Ordering of listed images by modification time
+#[derive(Deserialize, Default, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+enum SortOrder {
+    #[default]
+    Newest,
+    Oldest,
+}
+
+/// A stored image as reported by the listing endpoint
+#[derive(Serialize)]
+struct ImageEntry {
+    /// Name the image is stored and served under
+    filename: String,
+    /// Size in bytes
+    size: u64,
+    /// Last modification time as seconds since the Unix epoch
+    modified: u64,
+    /// Public URL of the image
+    url: String,
+}
+
+/// Response structure for the listing endpoint
+#[derive(Serialize)]
+struct ListResponse {
+    /// Number of images stored in total
+    total: usize,
+    /// Number of images skipped before this page
+    offset: usize,
+    /// Page size that was applied
+    limit: usize,
+    /// The requested page of images
+    images: Vec<ImageEntry>,
+}
+
+/// List stored images with their size and modification time
+async fn list(
+    req: HttpRequest,
+    query: web::Query<ListQuery>,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    let config = state.config();
+    let key_label = authorize(&req, &config)?;
+    info!("Listing images for key: {}", key_label);
+
+    let limit = query.limit.min(MAX_LIST_LIMIT);
+    let (total, images) = image_page(
+        &state,
+        &config,
+        Listing::Listed,
+        query.sort,
+        query.offset,
+        limit,
+    )
+    .await
+    .map_err(|e| {
+        error!("Failed to list images: {:#}", e);
+        ApiError::internal("storage_error", "Failed to list images")
+    })?;
+    Ok(HttpResponse::Ok().json(ListResponse {
+        total,
+        offset: query.offset,
+        limit,
+        images,
+    }))
+}
+
+/// Overview of what is stored, returned by `GET /stats`
+#[derive(Serialize, Clone)]
+struct StatsResponse {
+    /// Number of stored images
+    files: u64,
+    /// Combined size of stored images in bytes
+    bytes: u64,
+    /// Images and bytes by content type
+    formats: BTreeMap<String, FormatStats>,
+    /// When the earliest stored image was uploaded
+    oldest_upload: Option<String>,
+    /// When the latest stored image was uploaded
+    newest_upload: Option<String>,
+    /// Space left on the volume holding `storage_path`; `None` for other backends
+    free_bytes: Option<u64>,
+}
+
+/// How many images of one format are stored
+#[derive(Serialize, Clone)]
+struct FormatStats {
+    files: u64,
+    bytes: u64,
+}
+
+/// How long an answer to `GET /stats` is reused before storage is looked at again
+const STATS_CACHE_DURATION: Duration = Duration::from_secs(10);
+
+/// Summarize stored images, reusing a recent answer so frequent polling stays cheap
+async fn stats(req: HttpRequest, state: web::Data<AppState>) -> Result<HttpResponse, ApiError> {
+    let config = state.config();
+    let key_label = authorize(&req, &config)?;
+    info!("Reporting stats for key: {}", key_label);
+
+    // The lock is held while working the answer out, so concurrent requests share one scan
+    let mut cached = state.stats.lock().await;
+    if let Some((computed_at, response)) = cached.as_ref() {
+        if computed_at.elapsed() < STATS_CACHE_DURATION {
+            return Ok(HttpResponse::Ok().json(response));
+        }
+    }
+    let response = storage_stats(&state, &config).await.map_err(|e| {
+        error!("Failed to gather stats: {:#}", e);
+        ApiError::internal("storage_error", "Failed to gather stats")
+    })?;
+    let body = HttpResponse::Ok().json(&response);
+    *cached = Some((Instant::now(), response));
+    Ok(body)
+}
+
+/// Work out the stats of servable images
+///
+/// The index is queried when one is configured; otherwise storage is scanned.
+async fn storage_stats(state: &AppState, config: &Config) -> Result<StatsResponse> {
+    let totals = match state.index.clone() {
+        Some(index) => blocking(move || index.totals_by_format(unix_now())).await?,
+        None => scanned_totals(config, state.storage.as_ref()).await?,
+    };
+
+    let mut response = StatsResponse {
+        files: 0,
+        bytes: 0,
+        formats: BTreeMap::new(),
+        oldest_upload: None,
+        newest_upload: None,
+        free_bytes: None,
+    };
+    response.oldest_upload = totals.iter().map(|totals| totals.oldest).min().map(rfc3339);
+    response.newest_upload = totals.iter().map(|totals| totals.newest).max().map(rfc3339);
+    for totals in totals {
+        response.files += totals.files;
+        response.bytes += totals.bytes;
+        response.formats.insert(
+            totals.content_type,
+            FormatStats {
+                files: totals.files,
+                bytes: totals.bytes,
+            },
+        );
+    }
+
+    if matches!(config.backend, Backend::Local) {
+        response.free_bytes = fs4::available_space(&config.storage_path)
+            .map_err(|e| {
+                warn!(
+                    "Failed to read free space of {:?}: {}",
+                    config.storage_path, e
+                )
+            })
+            .ok();
+    }
+    Ok(response)
+}
+
+/// Total up servable images by content type by scanning storage
+async fn scanned_totals(config: &Config, storage: &dyn Storage) -> Result<Vec<FormatTotals>> {
+    let mut totals: BTreeMap<&str, FormatTotals> = BTreeMap::new();
+    for image in list_images(config, storage, Listing::All).await? {
+        let content_type = Path::new(&image.filename)
+            .extension()
+            .and_then(|extension| content_type_for(&extension.to_string_lossy()))
+            .unwrap_or("application/octet-stream");
+        let entry = totals.entry(content_type).or_insert_with(|| FormatTotals {
+            content_type: content_type.to_owned(),
+            files: 0,
+            bytes: 0,
+            oldest: image.modified,
+            newest: image.modified,
+        });
+        entry.files += 1;
+        entry.bytes += image.size;
+        entry.oldest = entry.oldest.min(image.modified);
+        entry.newest = entry.newest.max(image.modified);
+    }
+    Ok(totals.into_values().collect())
+}
+
+/// Query parameters for finding similar images
+#[derive(Deserialize)]
+struct SimilarQuery {
+    /// Most bits the perceptual hashes may differ by, instead of `similar_max_distance`
+    max_distance: Option<u32>,
+}
+
+/// An image that looks like the one searched for
+#[derive(Serialize)]
+struct SimilarImage {
+    /// Name the image is stored and served under
+    filename: String,
+    /// Size in bytes
+    size: u64,
+    /// Public URL of the image
+    url: String,
+    /// Number of bits its perceptual hash differs by; 0 for visually identical images
+    distance: u32,
+}
+
+/// Response structure for the similarity endpoint
+#[derive(Serialize)]
+struct SimilarResponse {
+    /// Image that was searched for
+    filename: String,
+    /// Images that look like it, closest first
+    images: Vec<SimilarImage>,
+}
+
+/// List images that look like `filename`, such as re-encoded or cropped copies of a screenshot
+async fn similar(
+    req: HttpRequest,
+    path: web::Path<String>,
+    query: web::Query<SimilarQuery>,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    let config = state.config();
+    let key_label = authorize(&req, &config)?;
+    let (true, Some(index)) = (config.perceptual_hash, state.index.clone()) else {
+        return Err(ApiError::not_found("Similarity search is disabled"));
+    };
+    let filename = path.into_inner();
+    info!(
+        "Finding images similar to {} for key: {}",
+        filename, key_label
+    );
+
+    let max_distance = query.max_distance.unwrap_or(config.similar_max_distance);
+    let now = unix_now();
+    let searched = filename.clone();
+    let matches = blocking(move || {
+        let phash = index
+            .get(&searched)?
+            .filter(|entry| entry.expires_at.is_none_or(|expires_at| expires_at > now))
+            .and_then(|entry| entry.phash);
+        match phash {
+            Some(phash) => index.similar(phash, max_distance, now).map(Some),
+            None => Ok(None),
+        }
+    })
+    .await
+    .map_err(|e| {
+        error!("Failed to find images similar to {}: {:#}", filename, e);
+        ApiError::internal("storage_error", "Failed to query index")
+    })?;
+    let Some(matches) = matches else {
+        return Err(ApiError::not_found(format!(
+            "No perceptual hash is recorded for {}",
+            filename
+        )));
+    };
+
+    let images = matches
+        .into_iter()
+        .filter(|(_, entry)| entry.filename != filename)
+        .map(|(distance, entry)| SimilarImage {
+            url: config.image_url(&entry.filename),
+            filename: entry.filename,
+            size: entry.size,
+            distance,
+        })
+        .collect();
+    Ok(HttpResponse::Ok().json(SimilarResponse { filename, images }))
+}
+
+/// Query parameters for the HTML gallery
+#[derive(Deserialize)]
+struct GalleryQuery {
+    /// 1-based page number
+    #[serde(default = "default_gallery_page")]
+    page: usize,
+}
+
+fn default_gallery_page() -> usize {
+    1
+}
+
+/// Number of images shown on each gallery page
+const GALLERY_PAGE_SIZE: usize = 48;
+
+/// Edge length of the thumbnails shown in the gallery
+const GALLERY_THUMBNAIL_SIZE: u32 = 200;
+
+/// Render a paginated HTML page of thumbnails linking to the full images
+async fn gallery(
+    req: HttpRequest,
+    query: web::Query<GalleryQuery>,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    let config = state.config();
+    let Some(gallery_key) = config.gallery_key.as_deref() else {
+        return Err(ApiError::not_found("The gallery is disabled"));
+    };
+
+    // Browsers prompt for Basic auth credentials; only the password is checked
+    let authorized =
+        basic_auth_password(&req).is_some_and(|password| api_key_matches(&password, gallery_key));
+    if !authorized {
+        info!("Unauthorized gallery access attempt");
+        return Err(
+            ApiError::unauthorized("invalid_gallery_key", "Gallery login required")
+                .with_header(header::WWW_AUTHENTICATE, "Basic realm=\"kimage gallery\""),
+        );
+    }
+
+    let page = query.page.max(1);
+    let offset = (page - 1).saturating_mul(GALLERY_PAGE_SIZE);
+    let (count, images) = image_page(
+        &state,
+        &config,
+        Listing::Gallery,
+        SortOrder::Newest,
+        offset,
+        GALLERY_PAGE_SIZE,
+    )
+    .await
+    .map_err(|e| {
+        error!("Failed to list images for gallery: {:#}", e);
+        ApiError::internal("storage_error", "Failed to list images")
+    })?;
+
+    let pages = count.div_ceil(GALLERY_PAGE_SIZE).max(1);
+    let tiles: String = images
+        .iter()
+        .map(|image| {
+            // A templated URL may already carry a query
+            let separator = if image.url.contains('?') { "&amp;" } else { "?" };
+            let url = escape_html(&image.url);
+            format!(
+                r#"<a href="{url}"><img src="{url}{separator}w={size}&amp;h={size}" alt="{name}" title="{name}" loading="lazy"></a>"#,
+                url = url,
+                separator = separator,
+                size = GALLERY_THUMBNAIL_SIZE,
+                name = escape_html(&image.filename),
+            )
+        })
+        .collect();
+
+    let mut nav = String::new();
+    if page > 1 {
+        nav.push_str(&format!(
+            r#"<a href="?page={}">&larr; Newer</a> "#,
+            page - 1
+        ));
+    }
+    nav.push_str(&format!("Page {} of {}", page, pages));
+    if page < pages {
+        nav.push_str(&format!(
+            r#" <a href="?page={}">Older &rarr;</a>"#,
+            page + 1
+        ));
+    }
+
+    let html = format!(
+        r#"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8">
+<title>kimage gallery</title>
+<style>
+body {{ font-family: sans-serif; margin: 2em; }}
+.grid {{ display: flex; flex-wrap: wrap; gap: 8px; }}
+.grid img {{ width: {size}px; height: {size}px; object-fit: cover; background: #eee; }}
+nav {{ margin: 1em 0; }}
+</style>
+</head>
+<body>
+<h1>kimage gallery</h1>
+<p>{count} images</p>
+<nav>{nav}</nav>
+<div class="grid">{tiles}</div>
+<nav>{nav}</nav>
+</body>
+</html>
+"#,
+        size = GALLERY_THUMBNAIL_SIZE,
+        count = count,
+        nav = nav,
+        tiles = tiles,
+    );
+    Ok(HttpResponse::Ok()
+        .content_type("text/html; charset=utf-8")
+        .body(html))
+}
+
+/// Extract the password from an `Authorization: Basic` header
+fn basic_auth_password(req: &HttpRequest) -> Option<String> {
+    basic_auth_credentials(req).map(|(_username, password)| password)
+}
+
+/// Extract the username and password from an `Authorization: Basic` header
+fn basic_auth_credentials(req: &HttpRequest) -> Option<(String, String)> {
+    let value = req.headers().get(header::AUTHORIZATION)?.to_str().ok()?;
+    let (scheme, credentials) = value.split_once(' ')?;
+    if !scheme.eq_ignore_ascii_case("basic") {
+        return None;
+    }
+    let decoded = general_purpose::STANDARD.decode(credentials.trim()).ok()?;
+    let decoded = String::from_utf8(decoded).ok()?;
+    let (username, password) = decoded.split_once(':')?;
+    Some((username.to_owned(), password.to_owned()))
+}
+
+/// Escape text for inclusion in HTML content or attribute values
+fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// An upload received in full, along with how the client asked for it to be stored
+struct UploadRequest {
+    /// The image itself
+    contents: Vec<u8>,
+    /// Content type the client declared for the image
+    mime: Option<String>,
+    /// Name of the file the client uploaded, if it sent one
+    original_name: Option<String>,
+    /// Extension the client asked for the image to be stored under
+    extension: Option<String>,
+    /// Name the client asked for the image to be stored under, without the extension
+    slug: Option<String>,
+    /// Password needed to view the image
+    password: Option<String>,
+    /// Seconds until the image expires, if it does
+    ttl: Option<u64>,
+    /// Whether the image is deleted once it has been viewed
+    burn: bool,
+}
+
+/// Authorize an upload and count it against its key's rate limit, returning the key's label
+fn authorize_upload<'a>(
+    req: &HttpRequest,
+    state: &AppState,
+    config: &'a Config,
+) -> Result<&'a str, ApiError> {
+    let key_label = authorize(req, config)?;
+    info!("Upload authorized with key: {}", key_label);
+
+    if let Some(per_minute) = config.uploads_per_minute {
+        state
+            .check_rate_limit(key_label, per_minute)
+            .map_err(|retry_after| {
+                info!("Rate limit exceeded for key: {}", key_label);
+                ApiError::too_many_requests(retry_after)
+            })?;
+    }
+    Ok(key_label)
+}
+
+/// Read the expiry (`X-Expire-After`) and one-time (`X-Burn`) settings of an upload
+fn upload_headers(req: &HttpRequest, config: &Config) -> Result<(Option<u64>, bool), ApiError> {
+    // Work out when the upload should expire, preferring the client's request
+    let ttl = match req.headers().get("X-Expire-After") {
+        Some(value) => Some(
+            value
+                .to_str()
+                .ok()
+                .and_then(|v| v.trim().parse::<u64>().ok())
+                .ok_or_else(|| {
+                    error!("Invalid X-Expire-After header: {:?}", value);
+                    ApiError::bad_request("invalid_expiry", "Invalid X-Expire-After header")
+                })?,
+        ),
+        None => config.default_ttl_seconds,
+    };
+
+    // One-time images are deleted the first time they're viewed
+    let burn = match req.headers().get("X-Burn") {
+        Some(value) => value
+            .to_str()
+            .ok()
+            .and_then(|v| v.trim().to_ascii_lowercase().parse::<bool>().ok())
+            .ok_or_else(|| {
+                error!("Invalid X-Burn header: {:?}", value);
+                ApiError::bad_request("invalid_burn", "X-Burn must be true or false")
+            })?,
+        None => false,
+    };
+    Ok((ttl, burn))
+}
+
+/// Handle image upload requests
+async fn upload(
+    req: HttpRequest,
+    state: web::Data<AppState>,
+    mut payload: Multipart,
+) -> Result<HttpResponse, ApiError> {
+    let config = state.config();
+    let key_label = authorize_upload(&req, &state, &config)?;
+    let (ttl, burn) = upload_headers(&req, &config)?;
+
+    // Process the multipart form data
+    let mut image = None;
+    let mut image_mime = None;
+    let mut original_name = None;
+    let mut extension = None;
+    let mut slug = None;
+    let mut password = None;
+    while let Ok(Some(mut field)) = payload.try_next().await {
+        let content_type = field.content_disposition();
+        let Some(name) = content_type.get_name().map(str::to_owned) else {
+            continue;
+        };
+        let file_name = content_type.get_filename().map(str::to_owned);
+        let mime = field
+            .content_type()
+            .map(|mime| mime.essence_str().to_owned());
+
+        // Collect all chunks of the field
+        let mut bytes = Vec::new();
+        while let Some(chunk) = field.next().await {
+            let data = chunk.map_err(|e| {
+                error!("Failed to read multipart data: {}", e);
+                ApiError::bad_request("invalid_multipart", "Failed to read multipart data")
+            })?;
+            bytes.extend_from_slice(&data);
+        }
+
+        match name.as_str() {
+            "image" => {
+                image = Some(bytes);
+                image_mime = mime;
+                original_name = file_name;
+            }
+            "extension" => extension = Some(String::from_utf8_lossy(&bytes).to_lowercase()),
+            "name" => slug = Some(String::from_utf8_lossy(&bytes).trim().to_owned()),
+            "password" => password = Some(String::from_utf8_lossy(&bytes).into_owned()),
+            _ => {}
+        }
+    }
+
+    let Some(bytes) = image else {
+        error!("Bad request: No image field found in payload");
+        return Err(ApiError::bad_request(
+            "missing_image",
+            "No image field found in payload",
+        ));
+    };
+
+    // A part may leave its type out or call itself a generic blob, but one declared as
+    // something other than an image is refused before its contents are looked at
+    if let Some(mime) = image_mime
+        .as_deref()
+        .filter(|mime| !mime.starts_with("image/") && *mime != "application/octet-stream")
+    {
+        info!("Rejected upload declared as {}", mime);
+        return Err(ApiError::new(
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            "unsupported_media_type",
+            format!("Uploads must be images, not {}", mime),
+        ));
+    }
+
+    // Take the image as sent, or decode it if an older client base64-encoded it
+    let decoded = decode_image_field(bytes).map_err(|e| {
+        error!("Invalid base64 data: {}", e);
+        ApiError::bad_request("invalid_base64", "Invalid base64 data")
+    })?;
+
+    let request = UploadRequest {
+        contents: decoded,
+        mime: image_mime,
+        original_name,
+        extension,
+        slug,
+        password,
+        ttl,
+        burn,
+    };
+    store_upload(&state, &config, key_label, request).await
+}
+
+/// Validate a received upload, store it and answer with its URL
+async fn store_upload(
+    state: &AppState,
+    config: &Config,
+    key_label: &str,
+    request: UploadRequest,
+) -> Result<HttpResponse, ApiError> {
+    let UploadRequest {
+        contents: decoded,
+        mime: image_mime,
+        original_name,
+        extension,
+        slug,
+        password,
+        ttl,
+        burn,
+    } = request;
+
+    // Refuse anything that isn't an image in an allowed format, so the server
+    // can't be used to host arbitrary files
+    let (format, (width, height)) = validate_image(&decoded, config).map_err(|reason| {
+        info!("Rejected upload: {}", reason);
+        ApiError::new(
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            "unsupported_image",
+            reason,
+        )
+    })?;
+    config
+        .dimension_limits()
+        .check(width, height)
+        .map_err(|reason| {
+            info!("Rejected upload: {}", reason);
+            ApiError::new(StatusCode::PAYLOAD_TOO_LARGE, "image_too_large", reason)
+        })?;
+
+    // Store the image under an extension for what it really is: the extension the client
+    // sent is only kept if it names the sniffed format, and a declared type that disagrees
+    // with the contents is overruled
+    let sniffed = format.extensions_str()[0];
+    if let Some(mime) = image_mime
+        .as_deref()
+        .filter(|mime| mime.starts_with("image/"))
+    {
+        if ImageFormat::from_mime_type(mime) != Some(format) {
+            info!(
+                "Upload declared as {} is a {:?} image, storing it as .{}",
+                mime, format, sniffed
+            );
+        }
+    }
+    let extension = match extension {
+        Some(extension) if ImageFormat::from_extension(&extension) == Some(format) => extension,
+        Some(extension) => {
+            info!(
+                "Upload sent as .{} is a {:?} image, storing it as .{}",
+                extension, format, sniffed
+            );
+            sniffed.to_owned()
+        }
+        None => sniffed.to_owned(),
+    };
+    if content_type_for(&extension).is_none() {
+        error!("Bad request: Unsupported extension {:?}", extension);
+        return Err(ApiError::bad_request(
+            "unsupported_extension",
+            format!("Unsupported extension {:?}", extension),
+        ));
+    }
+
+    if password.as_deref() == Some("") {
+        return Err(ApiError::bad_request(
+            "invalid_password",
+            "Passwords must not be empty",
+        ));
+    }
+
+    // Pick a filename: the requested slug, content-addressed when deduplicating, or random.
+    // Protected and one-time images are never deduplicated, so they can't be
+    // reached through an unprotected copy or take other uploaders' copies with them
+    let dedupe = config.dedupe && slug.is_none() && password.is_none() && !burn;
+    if let Some(slug) = &slug {
+        validate_slug(slug, config).map_err(|reason| {
+            info!("Rejected slug {:?}: {}", slug, reason);
+            ApiError::bad_request("invalid_name", reason)
+        })?;
+    }
+    let filename = match &slug {
+        Some(slug) => config
+            .storage_layout
+            .key(&format!("{}.{}", slug, extension)),
+        None if dedupe => config
+            .storage_layout
+            .content_key(&content_filename(&decoded, &extension)),
+        None => free_filename(state, &extension, config)
+            .await
+            .map_err(|e| {
+                error!("Failed to pick a filename: {:#}", e);
+                ApiError::internal("storage_error", "Failed to pick a filename")
+            })?,
+    };
+    let content_type = detect_content_type(Path::new(&filename), &decoded).to_owned();
+    let size = decoded.len() as u64;
+    let response = |url: String, expires_at: Option<u64>, existing: bool| UploadResponse {
+        url,
+        filename: filename.clone(),
+        width,
+        height,
+        bytes: size,
+        content_type: content_type.clone(),
+        expires_at: expires_at.map(rfc3339),
+        existing,
+    };
+    let storage = state.storage.as_ref();
+    // Whether this upload replaces an identical copy that expired before being swept
+    let mut replaces_expired = false;
+    if dedupe {
+        let stored = storage.exists(&filename).await.map_err(|e| {
+            error!("Failed to look up {}: {:#}", filename, e);
+            ApiError::internal("storage_error", "Failed to look up file")
+        })?;
+        if stored {
+            let metadata = read_metadata(storage, &filename).await.map_err(|e| {
+                error!("Failed to read metadata for {}: {:#}", filename, e);
+                ApiError::internal("storage_error", "Failed to read metadata")
+            })?;
+            if metadata
+                .as_ref()
+                .is_some_and(|metadata| metadata.is_expired(unix_now()))
+            {
+                info!(
+                    "Identical image {} has expired, storing it afresh",
+                    filename
+                );
+                replaces_expired = true;
+            } else {
+                // The stored copy keeps its own expiry
+                let expires_at = metadata.and_then(|metadata| metadata.expires_at);
+                let url = config.image_url(&filename);
+                info!("Identical image already stored: {}", url);
+                return Ok(HttpResponse::Ok().json(response(url, expires_at, true)));
+            }
+        }
+    }
+
+    // Hash the pixels for similarity search while the upload is still in memory;
+    // an image that can't be decoded is stored without a hash
+    let phash = if config.perceptual_hash && state.index.is_some() {
+        let contents = decoded.clone();
+        let limits = config.dimension_limits();
+        match blocking(move || perceptual_hash(&contents, limits)).await {
+            Ok(phash) => Some(phash),
+            Err(e) => {
+                warn!("Failed to compute perceptual hash of {}: {:#}", filename, e);
+                None
+            }
+        }
+    } else {
+        None
+    };
+
+    state.reserve_storage(config, size).await?;
+    info!("Saving file as: {}", filename);
+    // Content-addressed names may be overwritten with identical data; any other
+    // name is claimed atomically so two uploads can't both take it
+    let stored = if dedupe {
+        storage.put(&filename, decoded).await.map(|()| true)
+    } else {
+        storage.put_new(&filename, decoded).await
+    };
+    let stored = match stored {
+        Ok(stored) => stored,
+        Err(e) => {
+            state.release_storage(size).await;
+            error!("Failed to write file: {:#}", e);
+            return Err(ApiError::internal("storage_error", "Failed to write file"));
+        }
+    };
+    if replaces_expired {
+        // The expired copy was the same size and has just been overwritten
+        state.release_storage(size).await;
+    }
+    if !stored {
+        state.release_storage(size).await;
+        info!("Name already taken: {}", filename);
+        if slug.is_none() {
+            return Err(ApiError::internal(
+                "storage_error",
+                "Generated filename was taken concurrently",
+            ));
+        }
+        return Err(ApiError::new(
+            StatusCode::CONFLICT,
+            "name_taken",
+            format!("An image named {} already exists", filename),
+        ));
+    }
+
+    // Record the upload time and expiry next to the image
+    let uploaded_at = unix_now();
+    let metadata = ImageMetadata {
+        uploaded_at,
+        expires_at: ttl.map(|ttl| uploaded_at.saturating_add(ttl)),
+        content_type: Some(content_type.clone()),
+        token_hash: password.as_deref().map(hash_token),
+        burn,
+    };
+    write_metadata(storage, &filename, &metadata)
+        .await
+        .map_err(|e| {
+            error!("Failed to write metadata for {}: {:#}", filename, e);
+            ApiError::internal("storage_error", "Failed to write metadata")
+        })?;
+    if let Some(index) = state.index.clone() {
+        let entry = IndexEntry {
+            filename: filename.clone(),
+            original_name,
+            key_label: key_label.to_owned(),
+            size,
+            content_type: content_type.clone(),
+            created_at: uploaded_at,
+            expires_at: metadata.expires_at,
+            token_hash: metadata.token_hash.clone(),
+            burn,
+            phash,
+        };
+        blocking(move || index.insert(&entry)).await.map_err(|e| {
+            error!("Failed to index {}: {:#}", filename, e);
+            ApiError::internal("storage_error", "Failed to index upload")
+        })?;
+    }
+
+    // Construct and return the URL of the uploaded image
+    let url = config.image_url(&filename);
+    info!("File uploaded successfully: {}", url);
+    state.metrics.record_upload(size);
+    if let Some(webhook_url) = config.webhook_url.clone() {
+        let event = WebhookEvent {
+            event: "upload",
+            filename: filename.clone(),
+            url: url.clone(),
+            size,
+            key_label: key_label.to_owned(),
+            timestamp: rfc3339(uploaded_at),
+        };
+        let client = state.http_client.clone();
+        actix_web::rt::spawn(async move { notify_webhook(&client, &webhook_url, &event).await });
+    }
+    Ok(HttpResponse::Ok().json(response(url, metadata.expires_at, false)))
+}
+
+/// Largest image a resumable upload may send, in bytes
+const MAX_RESUMABLE_UPLOAD_SIZE: u64 = 1 << 30;
+
+/// Most resumable uploads one API key may have open at once, each holding its full size
+/// of the storage quota
+const MAX_RESUMABLE_UPLOADS_PER_KEY: usize = 4;
+
+/// Length of the IDs resumable uploads are given
+const RESUMABLE_UPLOAD_ID_LENGTH: usize = 24;
+
+/// Header a chunk of a resumable upload gives its position in the image with
+const UPLOAD_OFFSET: &str = "Upload-Offset";
+
+/// Name of the hidden directory in local storage that resumable uploads are collected in
+const PARTIAL_UPLOAD_DIR: &str = ".uploads";
+
+/// Directory resumable uploads are collected in
+///
+/// With local storage this is a hidden directory in it, so the data is already on
+/// the right disk; other backends use the system's temporary directory.
+fn partial_upload_dir(config: &Config) -> PathBuf {
+    match config.backend {
+        Backend::Local => config.storage_path.join(PARTIAL_UPLOAD_DIR),
+        Backend::S3 => std::env::temp_dir().join("kimage-uploads"),
+    }
+}
+
+/// A resumable upload that is still receiving data
+struct ResumableUpload {
+    /// Label of the API key that started the upload
+    key_label: String,
+    /// Size of the complete image in bytes
+    size: u64,
+    /// Bytes received so far, which the next chunk must start at
+    offset: u64,
+    /// When data was last received, to tell when the upload has been abandoned
+    last_activity: Instant,
+    /// Whether a chunk is being received right now
+    busy: bool,
+    /// How to store the image once it is complete, with its contents left empty
+    request: UploadRequest,
+}
+
+/// Body of a request to start a resumable upload
+#[derive(Deserialize)]
+struct ResumableUploadInit {
+    /// Size of the complete image in bytes
+    size: u64,
+    /// Extension to store the image under
+    extension: Option<String>,
+    /// Name to store the image under, without the extension
+    name: Option<String>,
+    /// Password needed to view the image
+    password: Option<String>,
+    /// Name of the file being uploaded
+    file_name: Option<String>,
+}
+
+/// Progress of a resumable upload
+#[derive(Serialize)]
+struct ResumableUploadStatus {
+    /// ID to send chunks to, as `PATCH /upload/{id}`
+    id: String,
+    /// Bytes received so far, which the next chunk must start at
+    offset: u64,
+    /// Size of the complete image in bytes
+    size: u64,
+}
+
+/// Start a resumable upload, whose data is then sent in chunks to `PATCH /upload/{id}`
+///
+/// Takes the same `X-Expire-After` and `X-Burn` headers as a plain upload. The full
+/// size is reserved against `max_total_bytes` up front, and released once the upload
+/// completes or is abandoned.
+async fn start_resumable_upload(
+    req: HttpRequest,
+    body: web::Json<ResumableUploadInit>,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    let config = state.config();
+    let key_label = authorize_upload(&req, &state, &config)?;
+    let (ttl, burn) = upload_headers(&req, &config)?;
+    let ResumableUploadInit {
+        size,
+        extension,
+        name,
+        password,
+        file_name,
+    } = body.into_inner();
+    if size == 0 {
+        return Err(ApiError::bad_request(
+            "invalid_size",
+            "Uploads must be at least 1 byte",
+        ));
+    }
+    if size > MAX_RESUMABLE_UPLOAD_SIZE {
+        return Err(ApiError::new(
+            StatusCode::PAYLOAD_TOO_LARGE,
+            "too_large",
+            format!(
+                "Resumable uploads may be at most {} bytes",
+                MAX_RESUMABLE_UPLOAD_SIZE
+            ),
+        ));
+    }
+
+    let too_many = || {
+        info!(
+            "Rejected resumable upload: {} already has {} open",
+            key_label, MAX_RESUMABLE_UPLOADS_PER_KEY
+        );
+        ApiError::new(
+            StatusCode::TOO_MANY_REQUESTS,
+            "too_many_uploads",
+            format!(
+                "At most {} resumable uploads may be open at once",
+                MAX_RESUMABLE_UPLOADS_PER_KEY
+            ),
+        )
+    };
+    if state.open_resumable_uploads(key_label) >= MAX_RESUMABLE_UPLOADS_PER_KEY {
+        return Err(too_many());
+    }
+
+    // Collect the data in a file of its own until the last chunk arrives
+    let id = random_string(RESUMABLE_UPLOAD_ID_LENGTH, &mut rand::thread_rng());
+    let path = state.partial_upload_path(&id);
+    let created = async {
+        tokio::fs::create_dir_all(&state.partial_upload_dir).await?;
+        tokio::fs::File::create(&path).await
+    };
+    created.await.map_err(|e| {
+        error!("Failed to create {:?}: {}", path, e);
+        ApiError::internal("storage_error", "Failed to start upload")
+    })?;
+    if let Err(e) = state.reserve_storage(&config, size).await {
+        remove_partial_upload(&path).await;
+        return Err(e);
+    }
+    let upload = ResumableUpload {
+        key_label: key_label.to_owned(),
+        size,
+        offset: 0,
+        last_activity: Instant::now(),
+        busy: false,
+        request: UploadRequest {
+            contents: Vec::new(),
+            mime: None,
+            original_name: file_name,
+            extension: extension.map(|extension| extension.to_lowercase()),
+            slug: name.map(|name| name.trim().to_owned()),
+            password,
+            ttl,
+            burn,
+        },
+    };
+    // Check the limit again now the upload is added, in case others started meanwhile
+    let added = {
+        let mut uploads = state
+            .resumable_uploads
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        let open = uploads
+            .values()
+            .filter(|upload| upload.key_label == key_label)
+            .count();
+        open < MAX_RESUMABLE_UPLOADS_PER_KEY && uploads.insert(id.clone(), upload).is_none()
+    };
+    if !added {
+        state.release_storage(size).await;
+        remove_partial_upload(&path).await;
+        return Err(too_many());
+    }
+    info!("Started resumable upload {} of {} bytes", id, size);
+    Ok(HttpResponse::Created().json(ResumableUploadStatus {
+        id,
+        offset: 0,
+        size,
+    }))
+}
+
+/// Report how much of a resumable upload has been received, so the client can resume it
+async fn resumable_upload_status(
+    req: HttpRequest,
+    id: web::Path<String>,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    let config = state.config();
+    let key_label = authorize(&req, &config)?;
+    let (offset, size) =
+        state.with_resumable_upload(&id, key_label, |upload| (upload.offset, upload.size))?;
+    Ok(HttpResponse::Ok().json(ResumableUploadStatus {
+        id: id.into_inner(),
+        offset,
+        size,
+    }))
+}
+
+/// Append a chunk, which must start at the `Upload-Offset` header, to a resumable upload
+///
+/// Answers `202 Accepted` with the new offset while more data is expected, and stores
+/// the image like a plain upload once the last byte arrives.
+async fn append_resumable_upload(
+    req: HttpRequest,
+    id: web::Path<String>,
+    mut payload: web::Payload,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    let config = state.config();
+    let key_label = authorize(&req, &config)?;
+    let id = id.into_inner();
+    let offset = req
+        .headers()
+        .get(UPLOAD_OFFSET)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.trim().parse::<u64>().ok())
+        .ok_or_else(|| {
+            ApiError::bad_request(
+                "invalid_offset",
+                "Upload-Offset must give the number of bytes already sent",
+            )
+        })?;
+
+    // Claim the upload, so two chunks can't be written at once
+    let size = state.with_resumable_upload(&id, key_label, |upload| {
+        if upload.busy {
+            return Err(ApiError::new(
+                StatusCode::CONFLICT,
+                "upload_busy",
+                "Another chunk of this upload is being received",
+            ));
+        }
+        if upload.offset != offset {
+            return Err(ApiError::new(
+                StatusCode::CONFLICT,
+                "offset_mismatch",
+                format!("The upload has {} bytes, not {}", upload.offset, offset),
+            ));
+        }
+        upload.busy = true;
+        Ok(upload.size)
+    })??;
+    let claim = ChunkClaim {
+        state: &state,
+        id: &id,
+    };
+
+    // Whatever part of the chunk arrives is kept, so a dropped connection loses nothing
+    let path = state.partial_upload_path(&id);
+    let (received, result) = append_chunk(&path, offset, size - offset, &mut payload).await;
+    let offset = offset + received;
+    state.with_resumable_upload(&id, key_label, |upload| {
+        upload.offset = offset;
+        upload.last_activity = Instant::now();
+    })?;
+    drop(claim);
+    result?;
+    if offset < size {
+        return Ok(HttpResponse::Accepted().json(ResumableUploadStatus { id, offset, size }));
+    }
+
+    // Store the assembled image like any other upload
+    let upload = state
+        .resumable_uploads
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .remove(&id)
+        .ok_or_else(|| ApiError::not_found("No such upload"))?;
+    // Storing the image reserves its own room, so the upload's reservation is done with
+    state.release_storage(size).await;
+    let contents = tokio::fs::read(&path).await;
+    if let Err(e) = tokio::fs::remove_file(&path).await {
+        warn!("Failed to remove {:?}: {}", path, e);
+    }
+    let contents = contents.map_err(|e| {
+        error!("Failed to read {:?}: {}", path, e);
+        ApiError::internal("storage_error", "Failed to read upload")
+    })?;
+    info!("Received all {} bytes of resumable upload {}", size, id);
+    let request = UploadRequest {
+        contents,
+        ..upload.request
+    };
+    store_upload(&state, &config, key_label, request).await
+}
+
+/// Delete the data of a resumable upload that couldn't be started
+async fn remove_partial_upload(path: &Path) {
+    if let Err(e) = tokio::fs::remove_file(path).await {
+        warn!("Failed to remove {:?}: {}", path, e);
+    }
+}
+
+/// Marks a resumable upload as receiving a chunk until dropped, even if the request is
+/// abandoned midway
+struct ChunkClaim<'a> {
+    state: &'a AppState,
+    id: &'a str,
+}
+
+impl Drop for ChunkClaim<'_> {
+    fn drop(&mut self) {
+        let mut uploads = self
+            .state
+            .resumable_uploads
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        if let Some(upload) = uploads.get_mut(self.id) {
+            upload.busy = false;
+        }
+    }
+}
+
+/// Append a request body to the file at `path` after its first `start` bytes, refusing
+/// to let it grow by more than `limit` bytes
+///
+/// Returns how many bytes were appended, along with any error that cut the body short.
+/// The file is left holding exactly the bytes counted; anything past `start` left by an
+/// earlier chunk that was never acknowledged is dropped first.
+async fn append_chunk(
+    path: &Path,
+    start: u64,
+    limit: u64,
+    payload: &mut web::Payload,
+) -> (u64, Result<(), ApiError>) {
+    use tokio::io::AsyncWriteExt;
+
+    let opened = async {
+        let file = tokio::fs::OpenOptions::new()
+            .append(true)
+            .open(path)
+            .await?;
+        file.set_len(start).await?;
+        Ok::<_, io::Error>(file)
+    };
+    let mut file = match opened.await {
+        Ok(file) => file,
+        Err(e) => {
+            error!("Failed to open {:?}: {}", path, e);
+            return (
+                0,
+                Err(ApiError::internal("storage_error", "Failed to open upload")),
+            );
+        }
+    };
+    let mut appended = 0;
+    let mut result = Ok(());
+    while let Some(chunk) = payload.next().await {
+        let chunk = match chunk {
+            Ok(chunk) => chunk,
+            Err(e) => {
+                info!("Chunk was cut short: {}", e);
+                result = Err(ApiError::bad_request(
+                    "invalid_chunk",
+                    "Failed to read chunk",
+                ));
+                break;
+            }
+        };
+        if appended + chunk.len() as u64 > limit {
+            result = Err(ApiError::new(
+                StatusCode::PAYLOAD_TOO_LARGE,
+                "chunk_too_large",
+                "The chunk runs past the end of the upload",
+            ));
+            break;
+        }
+        if let Err(e) = file.write_all(&chunk).await {
+            error!("Failed to write {:?}: {}", path, e);
+            result = Err(ApiError::internal("storage_error", "Failed to write chunk"));
+            break;
+        }
+        appended += chunk.len() as u64;
+    }
+
+    // Drop anything past what was counted, such as part of a chunk that failed to write
+    let settled = match file.flush().await {
+        Ok(()) => file.set_len(start + appended).await,
+        Err(e) => Err(e),
+    };
+    if let Err(e) = settled {
+        error!("Failed to write {:?}: {}", path, e);
+        return (
+            0,
+            Err(ApiError::internal("storage_error", "Failed to write chunk")),
+        );
+    }
+    (appended, result)
+}
+
+/// Most redirects followed when fetching an image for `POST /upload-url`
+const FETCH_MAX_REDIRECTS: usize = 5;
+
+/// How long fetching an image for `POST /upload-url` may take, body included
+const FETCH_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// JSON body of `POST /upload-url`
+#[derive(Deserialize)]
+struct UrlUploadRequest {
+    /// Where to fetch the image from
+    url: String,
+    /// Name to store the image under, without the extension
+    name: Option<String>,
+    /// Password needed to view the image
+    password: Option<String>,
+}
+
+/// Fetch an image from one of the `fetch_allowed_hosts` and store it like an upload
+///
+/// Takes the same `X-Expire-After` and `X-Burn` headers as a plain upload.
+async fn upload_from_url(
+    req: HttpRequest,
+    body: web::Json<UrlUploadRequest>,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    let config = state.config();
+    let key_label = authorize_upload(&req, &state, &config)?;
+    if config.fetch_allowed_hosts.is_empty() {
+        return Err(ApiError::not_found("Uploading from URLs is disabled"));
+    }
+    let (ttl, burn) = upload_headers(&req, &config)?;
+    let body = body.into_inner();
+
+    let (url, contents, mime) = fetch_image(&state.fetch_client, &config, &body.url).await?;
+    let original_name = url
+        .path_segments()
+        .and_then(|mut segments| segments.next_back())
+        .filter(|name| !name.is_empty())
+        .map(str::to_owned);
+    let request = UploadRequest {
+        contents,
+        mime,
+        original_name,
+        extension: None,
+        slug: body.name,
+        password: body.password,
+        ttl,
+        burn,
+    };
+    store_upload(&state, &config, key_label, request).await
+}
+
+/// Fetch the image at `url`, returning where it was finally found, its contents and
+/// its declared content type
+///
+/// Only `http` and `https` URLs on allowed hosts are fetched, redirects included, and
+/// the download is abandoned once it passes `fetch_max_bytes`.
+async fn fetch_image(
+    client: &reqwest::Client,
+    config: &Config,
+    url: &str,
+) -> Result<(reqwest::Url, Vec<u8>, Option<String>), ApiError> {
+    let mut url = reqwest::Url::parse(url).map_err(|e| {
+        info!("Rejected URL {:?}: {}", url, e);
+        ApiError::bad_request("invalid_url", format!("Invalid URL: {}", e))
+    })?;
+    let fetch_failed = |url: &reqwest::Url, reason: String| {
+        info!("Failed to fetch {}: {}", url, reason);
+        ApiError::new(
+            StatusCode::BAD_GATEWAY,
+            "fetch_failed",
+            format!("Failed to fetch the image: {}", reason),
+        )
+    };
+
+    let mut redirects = 0;
+    let mut response = loop {
+        check_fetch_url(&url, config)?;
+        info!("Fetching {}", url);
+        let response = client
+            .get(url.clone())
+            .send()
+            .await
+            .map_err(|e| fetch_failed(&url, e.to_string()))?;
+        if !response.status().is_redirection() {
+            break response;
+        }
+        redirects += 1;
+        if redirects > FETCH_MAX_REDIRECTS {
+            return Err(fetch_failed(&url, "too many redirects".to_string()));
+        }
+        let location = response
+            .headers()
+            .get(reqwest::header::LOCATION)
+            .and_then(|location| location.to_str().ok())
+            .ok_or_else(|| fetch_failed(&url, "redirect without a location".to_string()))?;
+        url = url
+            .join(location)
+            .map_err(|e| fetch_failed(&url, format!("invalid redirect: {}", e)))?;
+    };
+    if !response.status().is_success() {
+        let reason = format!("the server answered {}", response.status());
+        return Err(fetch_failed(&url, reason));
+    }
+
+    let max = config.fetch_max_bytes;
+    let too_large = || {
+        info!("Image at {} is larger than {} bytes", url, max);
+        ApiError::new(
+            StatusCode::PAYLOAD_TOO_LARGE,
+            "too_large",
+            format!("Images fetched from URLs may be at most {} bytes", max),
+        )
+    };
+    if response.content_length().is_some_and(|length| length > max) {
+        return Err(too_large());
+    }
+    let mime = response
+        .headers()
+        .get(reqwest::header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_owned);
+    let mut contents = Vec::new();
+    while let Some(chunk) = response
+        .chunk()
+        .await
+        .map_err(|e| fetch_failed(&url, e.to_string()))?
+    {
+        if (contents.len() + chunk.len()) as u64 > max {
+            return Err(too_large());
+        }
+        contents.extend_from_slice(&chunk);
+    }
+    Ok((url, contents, mime))
+}
+
+/// Refuse to fetch anything but `http` and `https` URLs on the `fetch_allowed_hosts`
+fn check_fetch_url(url: &reqwest::Url, config: &Config) -> Result<(), ApiError> {
+    if !matches!(url.scheme(), "http" | "https") {
+        info!("Rejected URL with scheme {}: {}", url.scheme(), url);
+        return Err(ApiError::bad_request(
+            "invalid_url",
+            "Only http and https URLs can be fetched",
+        ));
+    }
+    let host = url.host_str().unwrap_or_default();
+    if !is_allowed_host(host, &config.fetch_allowed_hosts) {
+        info!("Rejected URL on a host that isn't allowed: {}", url);
+        return Err(ApiError::new(
+            StatusCode::FORBIDDEN,
+            "host_not_allowed",
+            format!("Fetching from {} is not allowed", host),
+        ));
+    }
+    Ok(())
+}
+
+/// Whether `host` is one of `allowed`, where `*.example.com` stands for any subdomain
+/// of `example.com`
+fn is_allowed_host(host: &str, allowed: &[String]) -> bool {
+    let host = host.to_ascii_lowercase();
+    allowed.iter().any(|pattern| {
+        let pattern = pattern.to_ascii_lowercase();
+        match pattern.strip_prefix("*.") {
+            Some(domain) => host
+                .strip_suffix(domain)
+                .is_some_and(|subdomain| subdomain.len() > 1 && subdomain.ends_with('.')),
+            None => host == pattern,
+        }
+    })
+}
+
+/// Serve previously uploaded images, answering for missing ones as `not_found` says
+///
+/// Also answers `HEAD`, with the same headers but no body, so clients can check
+/// an image exists without downloading it.
+async fn serve_image(
+    req: HttpRequest,
+    filename: web::Path<String>,
+    query: web::Query<ServeQuery>,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    let config = state.config();
+    match serve_stored_image(req, filename, query, state).await {
+        Err(e) if e.status == StatusCode::NOT_FOUND => missing_image(&config, e).await,
+        response => response,
+    }
+}
+
+/// Answer a request for a missing image as `not_found` says, keeping the 404 status
+///
+/// Falls back to the JSON error if `not_found_image` can't be read.
+async fn missing_image(config: &Config, error: ApiError) -> Result<HttpResponse, ApiError> {
+    match config.not_found {
+        NotFoundResponse::Json => Err(error),
+        NotFoundResponse::Html => Ok(HttpResponse::NotFound()
+            .content_type("text/html; charset=utf-8")
+            .body(NOT_FOUND_PAGE)),
+        NotFoundResponse::Image => {
+            let Some(path) = &config.not_found_image else {
+                return Err(error);
+            };
+            match tokio::fs::read(path).await {
+                Ok(contents) => Ok(HttpResponse::NotFound()
+                    .content_type(detect_content_type(path, &contents))
+                    .body(contents)),
+                Err(e) => {
+                    error!("Failed to read not_found_image {:?}: {}", path, e);
+                    Err(error)
+                }
+            }
+        }
+    }
+}
+
+/// Page shown for missing images when `not_found` is `"html"`
+const NOT_FOUND_PAGE: &str = r#"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8">
+<title>Image not found</title>
+<style>
+body { font-family: sans-serif; margin: 2em; text-align: center; color: #444; }
+</style>
+</head>
+<body>
+<h1>Image not found</h1>
+<p>This image doesn't exist. It may have expired or been deleted.</p>
+</body>
+</html>
+"#;
+
+/// Serve a stored image, failing with a 404 if there is none to serve
+async fn serve_stored_image(
+    req: HttpRequest,
+    filename: web::Path<String>,
+    query: web::Query<ServeQuery>,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    let config = state.config();
+    // Without an image prefix, images and aliases share the root
+    if config.image_route_prefix().is_empty() {
+        if let Some(response) = alias_redirect(&req, &state, &config, &filename).await? {
+            return Ok(response);
+        }
+    }
+    state.metrics.image_requests.inc();
+
+    let filename = sanitize_filename(&filename)
+        .ok_or_else(|| {
+            info!("Rejected invalid filename: {:?}", filename.as_str());
+            ApiError::bad_request("invalid_filename", "Invalid filename")
+        })?
+        .to_owned();
+
+    let storage = state.storage.as_ref();
+
+    // Expired images may linger until the next sweep, but are never served
+    let now = unix_now();
+    let metadata = image_metadata(&state, &filename).await.map_err(|e| {
+        error!("Failed to read metadata for {}: {:#}", filename, e);
+        ApiError::internal("storage_error", "Failed to read metadata")
+    })?;
+    if metadata
+        .as_ref()
+        .is_some_and(|metadata| metadata.is_expired(now))
+    {
+        info!("Image expired: {}", filename);
+        return Err(ApiError::not_found("Image not found"));
+    }
+
+    // A valid signed link stands in for an image's token, and is the only way in
+    // when `require_signed_urls` is set
+    let signed_until = match (query.exp, query.sig.as_deref()) {
+        (Some(exp), Some(sig)) => {
+            check_signed_url(&config, &filename, exp, sig, now)?;
+            Some(exp)
+        }
+        (None, None) if config.require_signed_urls => {
+            info!("Unsigned request for {}", filename);
+            return Err(ApiError::new(
+                StatusCode::FORBIDDEN,
+                "signature_required",
+                "This image can only be viewed through a signed link",
+            ));
+        }
+        (None, None) => None,
+        _ => {
+            info!("Incomplete signed link for {}", filename);
+            return Err(ApiError::new(
+                StatusCode::FORBIDDEN,
+                "invalid_signature",
+                "Signed links need both exp and sig",
+            ));
+        }
+    };
+
+    // Protected images need their token, from the query string or a header
+    let token_hash = metadata
+        .as_ref()
+        .and_then(|metadata| metadata.token_hash.as_deref());
+    if let Some(token_hash) = token_hash.filter(|_| signed_until.is_none()) {
+        let token = query.token.as_deref().or_else(|| {
+            req.headers()
+                .get("X-Image-Token")
+                .and_then(|value| value.to_str().ok())
+        });
+        match token {
+            None => {
+                info!("Missing token for protected image: {}", filename);
+                return Err(ApiError::unauthorized(
+                    "token_required",
+                    "This image requires an access token",
+                ));
+            }
+            Some(token) if !verify_token(token_hash, token) => {
+                info!("Wrong token for protected image: {}", filename);
+                return Err(ApiError::new(
+                    StatusCode::FORBIDDEN,
+                    "invalid_token",
+                    "Invalid access token",
+                ));
+            }
+            Some(_) => {}
+        }
+    }
+    let public = token_hash.is_none() && signed_until.is_none();
+
+    if let Some(metadata) = metadata.as_ref().filter(|metadata| metadata.burn) {
+        // Thumbnails would use up the only view, as link previews tend to fetch them
+        if query.wants_thumbnail() || query.format.is_some() {
+            return Err(ApiError::bad_request(
+                "burn_thumbnail",
+                "Thumbnails and conversions aren't available for one-time images",
+            ));
+        }
+        let content_type = metadata
+            .content_type
+            .as_deref()
+            .unwrap_or("application/octet-stream");
+        // Checking a one-time image exists mustn't use up its view
+        if req.method() == Method::HEAD {
+            return burn_image_head(storage, &filename, content_type).await;
+        }
+        return burn_image(&state, &filename, content_type).await;
+    }
+
+    // Don't let caches hold on to an image past its expiry
+    let expires_at = metadata
+        .as_ref()
+        .and_then(|metadata| metadata.expires_at)
+        .into_iter()
+        .chain(signed_until)
+        .min();
+    let max_age = expires_at.map_or(config.cache_max_age_seconds, |expires_at| {
+        let remaining = u32::try_from(expires_at - now).unwrap_or(u32::MAX);
+        config.cache_max_age_seconds.min(remaining)
+    });
+
+    // Serve a scaled-down or converted copy instead when one was requested
+    let conversion = query.conversion()?;
+    let key = if query.wants_thumbnail() || conversion.is_some() {
+        let (width, height) = (query.w.unwrap_or(0), query.h.unwrap_or(0));
+        let max = config.max_thumbnail_dimension;
+        if query.wants_thumbnail() && ((width == 0 && height == 0) || width > max || height > max) {
+            info!(
+                "Rejected thumbnail size {}x{} for {}",
+                width, height, filename
+            );
+            return Err(ApiError::bad_request(
+                "invalid_thumbnail_size",
+                format!("Thumbnail dimensions must be between 1 and {}", max),
+            ));
+        }
+
+        let limits = config.dimension_limits();
+        let key = derived_image(storage, &filename, width, height, conversion, limits)
+            .await
+            .map_err(|e| {
+                error!("Failed to generate a copy of {}: {:#}", filename, e);
+                ApiError::internal("thumbnail_error", "Failed to generate thumbnail")
+            })?
+            .ok_or_else(|| {
+                info!("Image not found: {}", filename);
+                ApiError::not_found("Image not found")
+            })?;
+        info!("Serving {} for {}", key, filename);
+        key
+    } else {
+        info!("Serving image: {}", filename);
+        filename.clone()
+    };
+
+    // Only originals have a recorded type; thumbnails are encoded to match their extension
+    let content_type = metadata
+        .and_then(|metadata| metadata.content_type)
+        .filter(|_| key == filename)
+        .or_else(|| {
+            Path::new(&key)
+                .extension()
+                .and_then(|ext| ext.to_str())
+                .and_then(content_type_for)
+                .map(str::to_owned)
+        })
+        .unwrap_or_else(|| "application/octet-stream".to_string());
+    let mut response = stream_image(&req, storage, &key, &content_type, max_age, public).await?;
+    // Older caches that ignore max-age still learn when the image goes away
+    let expires =
+        expires_at.map(|expires_at| Expires((UNIX_EPOCH + Duration::from_secs(expires_at)).into()));
+    if let Some(Ok(value)) = expires.map(TryIntoHeaderValue::try_into_value) {
+        response.headers_mut().insert(header::EXPIRES, value);
+    }
+    Ok(response)
+}
+
+/// Serve a one-time image and delete it, so that it can only be viewed once
+///
+/// A claim object is created atomically first, so of several concurrent
+/// requests only the one that creates it gets the image.
+async fn burn_image(
+    state: &AppState,
+    filename: &str,
+    content_type: &str,
+) -> Result<HttpResponse, ApiError> {
+    let storage = state.storage.as_ref();
+    let claim = burn_claim_key(filename);
+    let storage_error = |e: anyhow::Error| {
+        error!("Failed to serve one-time image {}: {:#}", filename, e);
+        ApiError::internal("storage_error", "Failed to read file")
+    };
+    if !storage
+        .put_new(&claim, Vec::new())
+        .await
+        .map_err(storage_error)?
+    {
+        info!("One-time image already viewed: {}", filename);
+        return Err(ApiError::not_found("Image not found"));
+    }
+
+    // Give the claim back if the image can't be read, so it can still be viewed later
+    let contents = match storage.get(filename).await {
+        Ok(Some(contents)) => contents,
+        result => {
+            if let Err(e) = storage.delete(&claim).await {
+                error!("Failed to release claim on {}: {:#}", filename, e);
+            }
+            return match result {
+                Err(e) => Err(storage_error(e)),
+                _ => Err(ApiError::not_found("Image not found")),
+            };
+        }
+    };
+
+    // Once read, the image is never served again; on failure the claim is kept
+    // so a partly deleted image stays unavailable
+    let removed = match remove_image(storage, filename).await {
+        Ok(freed) => {
+            state.release_storage(freed).await;
+            true
+        }
+        Err(e) => {
+            error!("Failed to remove one-time image {}: {:#}", filename, e);
+            false
+        }
+    };
+    if let Some(index) = state.index.clone() {
+        let name = filename.to_owned();
+        if let Err(e) = blocking(move || index.remove(&name)).await {
+            error!("Failed to remove {} from the index: {:#}", filename, e);
+        }
+    }
+    if removed {
+        if let Err(e) = storage.delete(&claim).await {
+            error!("Failed to remove claim on {}: {:#}", filename, e);
+        }
+    }
+    info!("Served and removed one-time image: {}", filename);
+
+    Ok(HttpResponse::Ok()
+        .content_type(content_type)
+        .insert_header(CacheControl(vec![CacheDirective::NoStore]))
+        .body(contents))
+}
+
+/// Answer `HEAD` for a one-time image with the headers it would be served with
+async fn burn_image_head(
+    storage: &dyn Storage,
+    filename: &str,
+    content_type: &str,
+) -> Result<HttpResponse, ApiError> {
+    let object = storage
+        .stat(filename)
+        .await
+        .map_err(|e| {
+            error!("Failed to read file {}: {:#}", filename, e);
+            ApiError::internal("storage_error", "Failed to read file")
+        })?
+        .ok_or_else(|| {
+            info!("Image not found: {}", filename);
+            ApiError::not_found("Image not found")
+        })?;
+    Ok(head_response(
+        HttpResponse::Ok()
+            .content_type(content_type)
+            .insert_header(CacheControl(vec![CacheDirective::NoStore])),
+        object.size,
+    ))
+}
+
+/// Finish a response to `HEAD` with the `Content-Length` the body would have had
+///
+/// An empty body would be sent as `Content-Length: 0`, so the length is set
+/// by hand on a bodiless stream instead.
+fn head_response(response: &mut HttpResponseBuilder, length: u64) -> HttpResponse {
+    response
+        .no_chunking(length)
+        .streaming(futures::stream::empty::<Result<bytes::Bytes, io::Error>>())
+}
+
+/// Stream a stored object to the client, or answer 304 Not Modified if its copy is current
+///
+/// The ETag is derived from the object's size and modification time, so it stays
+/// the same across restarts. A single byte range may be requested with `Range`,
+/// which is answered with 206 Partial Content. Objects that aren't `public` may
+/// only be cached by the client, not by shared caches.
+async fn stream_image(
+    req: &HttpRequest,
+    storage: &dyn Storage,
+    key: &str,
+    content_type: &str,
+    max_age: u32,
+    public: bool,
+) -> Result<HttpResponse, ApiError> {
+    let read_error = |e: anyhow::Error| {
+        error!("Failed to read file {}: {:#}", key, e);
+        ApiError::internal("storage_error", "Failed to read file")
+    };
+    let not_found = || {
+        info!("Image not found: {}", key);
+        ApiError::not_found("Image not found")
+    };
+    let object = storage
+        .stat(key)
+        .await
+        .map_err(read_error)?
+        .ok_or_else(not_found)?;
+
+    let etag = EntityTag::new_strong(format!("{:x}-{:x}", object.size, object.modified));
+    let last_modified = UNIX_EPOCH + Duration::from_secs(object.modified);
+    // If-Modified-Since only counts when the client has no ETag to compare
+    let not_modified = match req.get_header::<IfNoneMatch>() {
+        Some(IfNoneMatch::Any) => true,
+        Some(IfNoneMatch::Items(tags)) => tags.iter().any(|tag| tag.weak_eq(&etag)),
+        None => req
+            .get_header::<IfModifiedSince>()
+            .is_some_and(|IfModifiedSince(since)| last_modified <= SystemTime::from(since)),
+    };
+    let range = if not_modified {
+        None
+    } else {
+        requested_range(req, &etag, last_modified, object.size)
+    };
+
+    let status = match range {
+        _ if not_modified => StatusCode::NOT_MODIFIED,
+        None => StatusCode::OK,
+        Some(Some(_)) => StatusCode::PARTIAL_CONTENT,
+        Some(None) => StatusCode::RANGE_NOT_SATISFIABLE,
+    };
+    let mut response = HttpResponse::build(status);
+    response
+        .insert_header(ETag(etag))
+        .insert_header(LastModified(last_modified.into()))
+        .insert_header(CacheControl(vec![
+            if public {
+                CacheDirective::Public
+            } else {
+                CacheDirective::Private
+            },
+            CacheDirective::MaxAge(max_age),
+        ]))
+        .insert_header((header::ACCEPT_RANGES, "bytes"));
+
+    let (range, length) = match range {
+        _ if not_modified => return Ok(response.finish()),
+        None => (None, object.size),
+        Some(Some((start, end))) => {
+            response.insert_header(ContentRange(ContentRangeSpec::Bytes {
+                range: Some((start, end)),
+                instance_length: Some(object.size),
+            }));
+            (Some((start, end)), end - start + 1)
+        }
+        Some(None) => {
+            info!("Unsatisfiable range requested of {}", key);
+            return Ok(response
+                .insert_header(ContentRange(ContentRangeSpec::Bytes {
+                    range: None,
+                    instance_length: Some(object.size),
+                }))
+                .finish());
+        }
+    };
+
+    if req.method() == Method::HEAD {
+        return Ok(head_response(response.content_type(content_type), length));
+    }
+    let body = storage
+        .read(key, range)
+        .await
+        .map_err(read_error)?
+        .ok_or_else(not_found)?;
+    Ok(response
+        .content_type(content_type)
+        .body(SizedStream::new(length, body)))
+}
+
+/// Work out which bytes of a `length` byte image the client asked for
+///
+/// Returns `None` to send the whole image: when no `Range` header was sent, when
+/// several ranges were requested, or when an `If-Range` precondition doesn't hold.
+/// Otherwise returns the inclusive range, or `Some(None)` if it can't be satisfied.
+fn requested_range(
+    req: &HttpRequest,
+    etag: &EntityTag,
+    last_modified: SystemTime,
+    length: u64,
+) -> Option<Option<(u64, u64)>> {
+    let Some(Range::Bytes(ranges)) = req.get_header::<Range>() else {
+        return None;
+    };
+    let [range] = ranges.as_slice() else {
+        return None;
+    };
+    // A date only matches if it is exactly the modification time the client was sent
+    match req.get_header::<IfRange>() {
+        Some(IfRange::EntityTag(tag)) if tag.strong_eq(etag) => {}
+        Some(IfRange::Date(date)) if SystemTime::from(date) == last_modified => {}
+        Some(_) => return None,
+        None => {}
+    }
+    Some(range.to_satisfiable_range(length))
+}
+
+/// Find or produce a thumbnail of an image fitting within `width`x`height`
+///
+/// Returns the key to serve, or `None` if the image doesn't exist. A bound of zero
+/// leaves that dimension unconstrained. Copies are cached under `.cache/<filename>/`
+/// and encoded as `conversion` asks, else in the format the image's extension names
+/// where it can be encoded, otherwise PNG. Images already within the bounds are
+/// served as is unless they are to be converted. Images beyond `limits` aren't decoded.
+async fn derived_image(
+    storage: &dyn Storage,
+    filename: &str,
+    width: u32,
+    height: u32,
+    conversion: Option<Conversion>,
+    limits: DimensionLimits,
+) -> Result<Option<String>> {
+    let (format, quality) = match conversion {
+        Some(conversion) => (conversion.format.image_format(), conversion.quality),
+        None => (
+            Path::new(filename)
+                .extension()
+                .and_then(ImageFormat::from_extension)
+                .filter(|format| format.can_write())
+                .unwrap_or(ImageFormat::Png),
+            None,
+        ),
+    };
+
+    let variant = match quality {
+        Some(quality) => format!("{}x{}-q{}", width, height, quality),
+        None => format!("{}x{}", width, height),
+    };
+    let cache_key = format!(
+        "{}{}.{}",
+        cache_prefix(filename),
+        variant,
+        format.extensions_str()[0]
+    );
+    if storage
+        .exists(&cache_key)
+        .await
+        .context("Failed to look up cached thumbnail")?
+    {
+        return Ok(Some(cache_key));
+    }
+
+    let Some(contents) = storage
+        .get(filename)
+        .await
+        .context("Failed to read image")?
+    else {
+        return Ok(None);
+    };
+    let convert = conversion.is_some();
+    let derived =
+        blocking(move || render_image(&contents, limits, format, quality, width, height, convert))
+            .await?;
+    let Some(derived) = derived else {
+        return Ok(Some(filename.to_owned()));
+    };
+
+    storage
+        .put(&cache_key, derived)
+        .await
+        .context("Failed to cache derived image")?;
+    Ok(Some(cache_key))
+}
+
+/// Scale an image down to fit within `width`x`height`, encoding it as `format`
+///
+/// `quality` applies to JPEG. Returns `None` when the image already fits and
+/// needn't be converted, since it is never scaled up.
+fn render_image(
+    contents: &[u8],
+    limits: DimensionLimits,
+    format: ImageFormat,
+    quality: Option<u8>,
+    width: u32,
+    height: u32,
+    convert: bool,
+) -> Result<Option<Vec<u8>>> {
+    let img = decode_image(contents, limits)?;
+    let bound = |dimension: u32| if dimension == 0 { u32::MAX } else { dimension };
+    let fits = img.width() <= bound(width) && img.height() <= bound(height);
+    if fits && !convert {
+        return Ok(None);
+    }
+
+    let img = if fits {
+        img
+    } else {
+        img.resize(bound(width), bound(height), FilterType::Lanczos3)
+    };
+    // JPEG has no alpha channel, so flatten to RGB before encoding
+    let (img, output) = match (format, quality) {
+        (ImageFormat::Jpeg, quality) => (
+            DynamicImage::ImageRgb8(img.to_rgb8()),
+            quality.map_or(format.into(), ImageOutputFormat::Jpeg),
+        ),
+        (format, _) => (img, format.into()),
+    };
+    let mut buffer = Cursor::new(Vec::new());
+    img.write_to(&mut buffer, output)
+        .with_context(|| format!("Failed to encode image as {:?}", format))?;
+    Ok(Some(buffer.into_inner()))
+}
+
+/// A request as written to the access log
+#[derive(Serialize)]
+struct AccessLogEntry<'a> {
+    /// Address of the client, or of the proxy in front of it unless proxy headers are trusted
+    client_ip: &'a str,
+    method: &'a str,
+    /// Path without the query string, which may hold access tokens
+    path: &'a str,
+    status: u16,
+    /// Size of the response body in bytes, if it was known up front
+    bytes: Option<u64>,
+    /// Time taken to produce the response, in milliseconds
+    latency_ms: f64,
+    /// Label of the API key the request was authorized with
+    key: Option<&'a str>,
+}
+
+/// Middleware writing a line to the access log for every request
+async fn access_log(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    let started = Instant::now();
+    let state = req.app_data::<web::Data<AppState>>().cloned();
+    let config = state.as_ref().map(|state| state.config());
+    let format = config
+        .as_ref()
+        .map_or(AccessLogFormat::default(), |config| config.access_log);
+    let trust_proxy_headers = config
+        .as_ref()
+        .is_some_and(|config| config.trust_proxy_headers);
+    let client_ip = if trust_proxy_headers {
+        req.connection_info()
+            .realip_remote_addr()
+            .unwrap_or("-")
+            .to_owned()
+    } else {
+        req.peer_addr()
+            .map_or_else(|| "-".to_string(), |addr| addr.ip().to_string())
+    };
+    let method = req.method().to_string();
+    let path = req.path().to_owned();
+
+    let res = next.call(req).await?;
+    let elapsed = started.elapsed();
+    if let Some(state) = &state {
+        // Label by route pattern rather than path, so every image shares one series
+        let route = res.request().match_pattern();
+        state
+            .metrics
+            .request_duration
+            .with_label_values(&[route.as_deref().unwrap_or("unmatched")])
+            .observe(elapsed.as_secs_f64());
+        let status = res.status();
+        if status.is_client_error() || status.is_server_error() {
+            state
+                .metrics
+                .errors
+                .with_label_values(&[status.as_str()])
+                .inc();
+        }
+    }
+
+    // Microsecond precision is plenty, and keeps JSON lines short
+    let latency_ms = (elapsed.as_secs_f64() * 1_000_000.0).round() / 1000.0;
+    let bytes = match res.response().body().size() {
+        BodySize::Sized(size) => Some(size),
+        _ => None,
+    };
+    let key = res
+        .request()
+        .extensions()
+        .get::<KeyLabel>()
+        .map(|label| label.0.clone());
+    let entry = AccessLogEntry {
+        client_ip: &client_ip,
+        method: &method,
+        path: &path,
+        status: res.status().as_u16(),
+        bytes,
+        latency_ms,
+        key: key.as_deref(),
+    };
+    match format {
+        AccessLogFormat::Off => {}
+        AccessLogFormat::Plain => info!(
+            target: "kimage_serve::access",
+            "{} \"{} {}\" {} {} {:.1}ms key={}",
+            entry.client_ip,
+            entry.method,
+            entry.path,
+            entry.status,
+            entry
+                .bytes
+                .map_or_else(|| "-".to_string(), |bytes| bytes.to_string()),
+            entry.latency_ms,
+            entry.key.unwrap_or("-")
+        ),
+        AccessLogFormat::Json => match serde_json::to_string(&entry) {
+            Ok(line) => info!(target: "kimage_serve::access", "{}", line),
+            Err(e) => error!("Failed to serialize access log entry: {}", e),
+        },
+    }
+    Ok(res)
+}
+
+/// Query parameters for collecting garbage
+#[derive(Deserialize)]
+struct GcQuery {
+    /// Only report what would be removed
+    #[serde(default)]
+    dry_run: bool,
+}
+
+/// What a garbage collection removed, or would remove on a dry run
+#[derive(Serialize)]
+struct GcResponse {
+    dry_run: bool,
+    /// Images whose TTL has passed
+    expired: Vec<String>,
+    /// Images with no metadata recorded for them
+    orphaned: Vec<String>,
+    /// Total size of the removed images
+    bytes: u64,
+}
+
+/// How long an image may go without metadata before it counts as orphaned, so
+/// that uploads still being written aren't mistaken for orphans
+const ORPHAN_GRACE_SECONDS: u64 = 60;
+
+/// Remove expired images and images with no metadata, answering with what was removed
+async fn collect_garbage(
+    req: HttpRequest,
+    query: web::Query<GcQuery>,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    let config = state.config();
+    let key_label = authorize(&req, &config)?;
+    info!(
+        "Collecting garbage for key: {}{}",
+        key_label,
+        if query.dry_run { " (dry run)" } else { "" }
+    );
+
+    let images = stored_images(state.storage.as_ref()).await.map_err(|e| {
+        error!("Failed to list images: {:#}", e);
+        ApiError::internal("storage_error", "Failed to list images")
+    })?;
+    let now = unix_now();
+    let mut response = GcResponse {
+        dry_run: query.dry_run,
+        expired: Vec::new(),
+        orphaned: Vec::new(),
+        bytes: 0,
+    };
+    for image in images {
+        let removed = match image_metadata(&state, &image.key).await {
+            Ok(Some(metadata)) if metadata.is_expired(now) => &mut response.expired,
+            Ok(Some(_)) => continue,
+            Ok(None) if now.saturating_sub(image.modified) >= ORPHAN_GRACE_SECONDS => {
+                &mut response.orphaned
+            }
+            Ok(None) => continue,
+            Err(e) => {
+                error!("Skipping metadata for {}: {:#}", image.key, e);
+                continue;
+            }
+        };
+
+        if !query.dry_run {
+            let freed = remove_image(state.storage.as_ref(), &image.key)
+                .await
+                .map_err(|e| {
+                    error!("Failed to remove {}: {:#}", image.key, e);
+                    ApiError::internal("storage_error", "Failed to remove image")
+                })?;
+            state.release_storage(freed).await;
+            if let Some(index) = state.index.clone() {
+                let name = image.key.clone();
+                blocking(move || index.remove(&name)).await.map_err(|e| {
+                    error!("Failed to remove index entry for {}: {:#}", image.key, e);
+                    ApiError::internal("storage_error", "Failed to update index")
+                })?;
+            }
+            info!("Removed {}", image.key);
+        }
+        response.bytes += image.size;
+        removed.push(image.key);
+    }
+    Ok(HttpResponse::Ok().json(response))
+}
+
+/// Query parameters for recompressing stored images
+#[derive(Deserialize)]
+struct RecompressQuery {
+    /// Only report what would be saved
+    #[serde(default)]
+    dry_run: bool,
+    /// Only recompress images in this format
+    format: Option<ConvertFormat>,
+    /// Encoding quality (1-100) JPEGs are re-encoded at
+    quality: Option<u8>,
+}
+
+/// An image that was recompressed, or would be on a dry run
+#[derive(Serialize)]
+struct RecompressedImage {
+    filename: String,
+    /// Size before recompressing
+    before: u64,
+    /// Size after recompressing
+    after: u64,
+}
+
+/// What a recompression of stored images saved, or would save on a dry run
+#[derive(Serialize)]
+struct RecompressResponse {
+    dry_run: bool,
+    recompressed: Vec<RecompressedImage>,
+    /// Bytes saved across all recompressed images
+    saved: u64,
+}
+
+/// Re-encode stored images in place, keeping each new encoding only if it is smaller
+///
+/// PNGs are losslessly optimized, JPEGs re-encoded at the requested quality and
+/// WebPs re-encoded losslessly. Re-encoding JPEGs and WebPs drops their metadata, and
+/// a JPEG loses quality every time, so those are only touched when `format` asks for
+/// them. Filenames and formats are unchanged, so links keep working.
+async fn recompress_images(
+    req: HttpRequest,
+    query: web::Query<RecompressQuery>,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    let config = state.config();
+    let key_label = authorize(&req, &config)?;
+    let quality = query.quality.unwrap_or(DEFAULT_JPEG_QUALITY);
+    if !(1..=100).contains(&quality) {
+        return Err(ApiError::bad_request(
+            "invalid_quality",
+            "Quality must be between 1 and 100",
+        ));
+    }
+    info!(
+        "Recompressing images for key: {}{}",
+        key_label,
+        if query.dry_run { " (dry run)" } else { "" }
+    );
+
+    let images = stored_images(state.storage.as_ref()).await.map_err(|e| {
+        error!("Failed to list images: {:#}", e);
+        ApiError::internal("storage_error", "Failed to list images")
+    })?;
+    let wanted = query.format.map(ConvertFormat::image_format);
+    let limits = config.dimension_limits();
+    let mut response = RecompressResponse {
+        dry_run: query.dry_run,
+        recompressed: Vec::new(),
+        saved: 0,
+    };
+    for image in images {
+        // Go by the stored extension first, so images in other formats are skipped unread
+        let Ok(format) = ImageFormat::from_path(&image.key) else {
+            continue;
+        };
+        let requested = match wanted {
+            Some(wanted) => wanted == format,
+            None => format == ImageFormat::Png,
+        };
+        if !requested {
+            continue;
+        }
+        let contents = match state.storage.get(&image.key).await {
+            Ok(Some(contents)) => contents,
+            Ok(None) => continue,
+            Err(e) => {
+                error!("Skipping {}: {:#}", image.key, e);
+                continue;
+            }
+        };
+        let before = contents.len() as u64;
+        let recompressed =
+            match blocking(move || recompress_image(&contents, format, quality, limits)).await {
+                Ok(Some(recompressed)) => recompressed,
+                Ok(None) => continue,
+                Err(e) => {
+                    warn!("Skipping {}: {:#}", image.key, e);
+                    continue;
+                }
+            };
+        let after = recompressed.len() as u64;
+
+        if !query.dry_run {
+            // Stores replace objects atomically, so the image is never seen half written
+            state
+                .storage
+                .put(&image.key, recompressed)
+                .await
+                .map_err(|e| {
+                    error!("Failed to replace {}: {:#}", image.key, e);
+                    ApiError::internal("storage_error", "Failed to replace image")
+                })?;
+            remove_cached_copies(state.storage.as_ref(), &image.key)
+                .await
+                .map_err(|e| {
+                    error!("{:#}", e);
+                    ApiError::internal("storage_error", "Failed to remove cached copies")
+                })?;
+            state.release_storage(before - after).await;
+            if let Some(index) = state.index.clone() {
+                let name = image.key.clone();
+                blocking(move || index.update_size(&name, after))
+                    .await
+                    .map_err(|e| {
+                        error!("Failed to update index entry for {}: {:#}", image.key, e);
+                        ApiError::internal("storage_error", "Failed to update index")
+                    })?;
+            }
+            info!(
+                "Recompressed {} from {} to {} bytes",
+                image.key, before, after
+            );
+        }
+        response.saved += before - after;
+        response.recompressed.push(RecompressedImage {
+            filename: image.key,
+            before,
+            after,
+        });
+    }
+    Ok(HttpResponse::Ok().json(response))
+}
+
+/// Re-encode an image in its own format, returning the new encoding if it is smaller
+///
+/// Animated images are left alone, as re-encoding would keep only their first frame.
+fn recompress_image(
+    contents: &[u8],
+    format: ImageFormat,
+    quality: u8,
+    limits: DimensionLimits,
+) -> Result<Option<Vec<u8>>> {
+    let animated = match format {
+        ImageFormat::Png => {
+            PngDecoder::new(Cursor::new(contents)).is_ok_and(|decoder| decoder.is_apng())
+        }
+        ImageFormat::WebP => {
+            WebPDecoder::new(Cursor::new(contents)).is_ok_and(|decoder| decoder.has_animation())
+        }
+        _ => false,
+    };
+    let output = match format {
+        _ if animated => return Ok(None),
+        ImageFormat::Png => {
+            let optimized =
+                oxipng::optimize_from_memory(contents, &oxipng::Options::from_preset(2))
+                    .context("Failed to optimize PNG")?;
+            return Ok((optimized.len() < contents.len()).then_some(optimized));
+        }
+        ImageFormat::Jpeg => ImageOutputFormat::Jpeg(quality),
+        ImageFormat::WebP => ImageOutputFormat::WebP,
+        _ => return Ok(None),
+    };
+
+    // Re-encoding drops EXIF, so turn the image upright while the orientation is known
+    let img = decode_image(contents, limits)?;
+    let img = apply_orientation(img, exif_orientation(contents));
+    let mut buffer = Cursor::new(Vec::new());
+    img.write_to(&mut buffer, output)
+        .context("Failed to encode image")?;
+    let recompressed = buffer.into_inner();
+    Ok((recompressed.len() < contents.len()).then_some(recompressed))
+}
+
+/// Expose the server's metrics for Prometheus to scrape
+async fn metrics(state: web::Data<AppState>) -> Result<HttpResponse, ApiError> {
+    let body = state.metrics.render().map_err(|e| {
+        error!("Failed to render metrics: {:#}", e);
+        ApiError::internal("metrics_error", "Failed to render metrics")
+    })?;
+    Ok(HttpResponse::Ok()
+        .content_type(kimage::metrics::CONTENT_TYPE)
+        .body(body))
+}
+
+/// Fallback for requests that match no route
+async fn unknown_route() -> Result<HttpResponse, ApiError> {
+    Err(ApiError::not_found("No such route"))
+}
+
+/// Redirect requests for images at the root to their prefixed location
+///
+/// Images used to be served from `/{filename}`; this keeps old links working.
+///
+/// Aliases are served from the same paths, and take precedence.
+async fn redirect_legacy_image(
+    req: HttpRequest,
+    path: web::Path<String>,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    let config = state.config();
+    if let Some(response) = alias_redirect(&req, &state, &config, &path).await? {
+        return Ok(response);
+    }
+    let mut location = config.image_path(&path.into_inner());
+    if !req.query_string().is_empty() {
+        location = format!("{}?{}", location, req.query_string());
+    }
+    info!(
+        "Redirecting legacy image path {} to {}",
+        req.path(),
+        location
+    );
+    Ok(HttpResponse::MovedPermanently()
+        .insert_header((header::LOCATION, location))
+        .finish())
+}
+
+/// Body of a request to give an image an alias
+#[derive(Deserialize)]
+struct AliasRequest {
+    /// Image the alias leads to
+    filename: String,
+    /// Short name to reach the image under, made of letters, digits, `-` and `_`
+    alias: String,
+}
+
+/// Response structure for a created alias
+#[derive(Serialize)]
+struct AliasResponse {
+    /// The new alias
+    alias: String,
+    /// Image the alias leads to
+    filename: String,
+    /// Public URL of the alias
+    url: String,
+}
+
+/// Give an image a short alias, so `/{alias}` redirects to it
+async fn create_alias(
+    req: HttpRequest,
+    body: web::Json<AliasRequest>,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    let config = state.config();
+    let key_label = authorize(&req, &config)?;
+    let Some(index) = state.index.clone() else {
+        return Err(ApiError::not_found("Aliases need index_path to be set"));
+    };
+    let AliasRequest { filename, alias } = body.into_inner();
+    validate_slug(&alias, &config).map_err(|reason| {
+        info!("Rejected alias {:?}: {}", alias, reason);
+        ApiError::bad_request("invalid_alias", reason)
+    })?;
+    let filename = sanitize_filename(&filename)
+        .ok_or_else(|| {
+            info!("Rejected invalid filename: {:?}", filename);
+            ApiError::bad_request("invalid_filename", "Invalid filename")
+        })?
+        .to_owned();
+
+    // The alias must lead to an image being served, and mustn't shadow a stored file
+    let storage = state.storage.as_ref();
+    let lookup = |name: &str| {
+        let name = name.to_owned();
+        async move {
+            storage.exists(&name).await.map_err(|e| {
+                error!("Failed to look up {}: {:#}", name, e);
+                ApiError::internal("storage_error", "Failed to look up file")
+            })
+        }
+    };
+    let expired = image_metadata(&state, &filename)
+        .await
+        .map_err(|e| {
+            error!("Failed to read metadata for {}: {:#}", filename, e);
+            ApiError::internal("storage_error", "Failed to read metadata")
+        })?
+        .is_some_and(|metadata| metadata.is_expired(unix_now()));
+    if expired || !lookup(&filename).await? {
+        return Err(ApiError::not_found("Image not found"));
+    }
+    if lookup(&alias).await? {
+        return Err(ApiError::new(
+            StatusCode::CONFLICT,
+            "alias_taken",
+            format!("A file named {} already exists", alias),
+        ));
+    }
+
+    let (new_alias, target) = (alias.clone(), filename.clone());
+    let created = blocking(move || index.insert_alias(&new_alias, &target, unix_now()))
+        .await
+        .map_err(|e| {
+            error!("Failed to create alias {}: {:#}", alias, e);
+            ApiError::internal("storage_error", "Failed to create alias")
+        })?;
+    if !created {
+        return Err(ApiError::new(
+            StatusCode::CONFLICT,
+            "alias_taken",
+            format!("The alias {} is already taken", alias),
+        ));
+    }
+    info!("Aliased {} to {} for key: {}", alias, filename, key_label);
+    Ok(HttpResponse::Created().json(AliasResponse {
+        url: config.alias_url(&alias),
+        alias,
+        filename,
+    }))
+}
+
+/// Redirect to the image `name` is an alias of, if it is one
+///
+/// Aliases never contain `.` or `/`, so requests for stored filenames skip the lookup.
+async fn alias_redirect(
+    req: &HttpRequest,
+    state: &AppState,
+    config: &Config,
+    name: &str,
+) -> Result<Option<HttpResponse>, ApiError> {
+    let Some(index) = state.index.clone() else {
+        return Ok(None);
+    };
+    if name.contains(['.', '/']) {
+        return Ok(None);
+    }
+    let alias = name.to_owned();
+    let filename = blocking(move || index.resolve_alias(&alias))
+        .await
+        .map_err(|e| {
+            error!("Failed to look up alias {}: {:#}", name, e);
+            ApiError::internal("storage_error", "Failed to look up alias")
+        })?;
+    Ok(filename.map(|filename| {
+        let mut location = config.image_path(&filename);
+        if !req.query_string().is_empty() {
+            location = format!("{}?{}", location, req.query_string());
+        }
+        info!("Redirecting alias {} to {}", name, location);
+        HttpResponse::MovedPermanently()
+            .insert_header((header::LOCATION, location))
+            .finish()
+    }))
+}
+
+/// How long links from `POST /sign` last when the request doesn't say
+const DEFAULT_SIGNED_URL_SECONDS: u64 = 3600;
+
+/// JSON body of `POST /sign`
+#[derive(Deserialize)]
+struct SignRequest {
+    /// Stored name of the image to link to
+    filename: String,
+    /// Seconds until the link stops working
+    expires_in: Option<u64>,
+}
+
+/// Response to `POST /sign`
+#[derive(Serialize)]
+struct SignResponse {
+    /// Link that serves the image until it expires, without an API key
+    url: String,
+    /// When the link stops working, as an RFC 3339 timestamp
+    expires_at: String,
+}
+
+/// Make a link that serves an image to anyone until it expires
+async fn sign(
+    req: HttpRequest,
+    body: web::Json<SignRequest>,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    let config = state.config();
+    let key_label = authorize(&req, &config)?;
+    let Some(secret) = config.signing_secret.as_deref() else {
+        return Err(ApiError::not_found(
+            "Signed links need signing_secret to be set",
+        ));
+    };
+    let body = body.into_inner();
+    let expires_in = body.expires_in.unwrap_or(DEFAULT_SIGNED_URL_SECONDS);
+    if expires_in == 0 {
+        return Err(ApiError::bad_request(
+            "invalid_expiry",
+            "expires_in must be at least 1 second",
+        ));
+    }
+    let filename = sanitize_filename(&body.filename).ok_or_else(|| {
+        info!("Rejected invalid filename: {:?}", body.filename);
+        ApiError::bad_request("invalid_filename", "Invalid filename")
+    })?;
+    let stored = state.storage.exists(filename).await.map_err(|e| {
+        error!("Failed to look up {}: {:#}", filename, e);
+        ApiError::internal("storage_error", "Failed to look up file")
+    })?;
+    if !stored {
+        return Err(ApiError::not_found("Image not found"));
+    }
+    // Expired images may linger until the next sweep, but are never served
+    let now = unix_now();
+    let expired = image_metadata(&state, filename)
+        .await
+        .map_err(|e| {
+            error!("Failed to read metadata for {}: {:#}", filename, e);
+            ApiError::internal("storage_error", "Failed to read metadata")
+        })?
+        .is_some_and(|metadata| metadata.is_expired(now));
+    if expired {
+        return Err(ApiError::not_found("Image not found"));
+    }
+
+    let expires_at = now.saturating_add(expires_in);
+    let url = config.image_url(filename);
+    let separator = if url.contains('?') { '&' } else { '?' };
+    let url = format!(
+        "{}{}exp={}&sig={}",
+        url,
+        separator,
+        expires_at,
+        url_signature(secret, filename, expires_at)
+    );
+    info!(
+        "Signed a link to {} for key {}, valid until {}",
+        filename,
+        key_label,
+        rfc3339(expires_at)
+    );
+    Ok(HttpResponse::Ok().json(SignResponse {
+        url,
+        expires_at: rfc3339(expires_at),
+    }))
+}
+
+/// Refuse a signed link that has expired or whose signature doesn't match
+fn check_signed_url(
+    config: &Config,
+    filename: &str,
+    expires_at: u64,
+    signature: &str,
+    now: u64,
+) -> Result<(), ApiError> {
+    let valid = config
+        .signing_secret
+        .as_deref()
+        .is_some_and(|secret| signature_matches(secret, filename, expires_at, signature));
+    if !valid {
+        info!("Invalid signature for {}", filename);
+        return Err(ApiError::new(
+            StatusCode::FORBIDDEN,
+            "invalid_signature",
+            "Invalid signature",
+        ));
+    }
+    if expires_at <= now {
+        info!("Expired signed link for {}", filename);
+        return Err(ApiError::new(
+            StatusCode::FORBIDDEN,
+            "link_expired",
+            "This link has expired",
+        ));
+    }
+    Ok(())
+}
+
+/// HMAC of a link to `filename` valid until `expires_at`, keyed with `secret`
+fn url_mac(secret: &str, filename: &str, expires_at: u64) -> Hmac<Sha256> {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC takes keys of any size");
+    mac.update(format!("{}\n{}", filename, expires_at).as_bytes());
+    mac
+}
+
+/// Signature of a link to `filename` valid until `expires_at`, as URL-safe base64
+fn url_signature(secret: &str, filename: &str, expires_at: u64) -> String {
+    let mac = url_mac(secret, filename, expires_at);
+    general_purpose::URL_SAFE_NO_PAD.encode(mac.finalize().into_bytes())
+}
+
+/// Check a signature made by [`url_signature`], in constant time
+fn signature_matches(secret: &str, filename: &str, expires_at: u64, signature: &str) -> bool {
+    let Ok(signature) = general_purpose::URL_SAFE_NO_PAD.decode(signature) else {
+        return false;
+    };
+    url_mac(secret, filename, expires_at)
+        .verify_slice(&signature)
+        .is_ok()
+}
+
+/// JSON body parsing that answers malformed bodies in the API's error format
+fn json_config() -> web::JsonConfig {
+    web::JsonConfig::default()
+        .error_handler(|e, _| ApiError::bad_request("invalid_json", e.to_string()).into())
+}
+
+/// POST an upload notification to the webhook, retrying failures with a growing delay
+///
+/// Connection errors and server errors are retried; a rejection by the
+/// receiver, such as `404`, is not. Failures are only logged, as the upload
+/// itself has already succeeded.
+async fn notify_webhook(client: &reqwest::Client, webhook_url: &str, event: &WebhookEvent) {
+    let mut delay = WEBHOOK_RETRY_DELAY;
+    for attempt in 1..=WEBHOOK_ATTEMPTS {
+        let sent = client
+            .post(webhook_url)
+            .timeout(WEBHOOK_TIMEOUT)
+            .json(event)
+            .send()
+            .await
+            .and_then(reqwest::Response::error_for_status);
+        let retryable = match sent {
+            Ok(_) => {
+                info!("Notified webhook of upload {}", event.filename);
+                return;
+            }
+            Err(e) => {
+                warn!(
+                    "Webhook notification for {} failed (attempt {}/{}): {}",
+                    event.filename, attempt, WEBHOOK_ATTEMPTS, e
+                );
+                e.status().is_none_or(|status| status.is_server_error())
+            }
+        };
+        if !retryable {
+            break;
+        }
+        if attempt < WEBHOOK_ATTEMPTS {
+            tokio::time::sleep(delay).await;
+            delay *= 2;
+        }
+    }
+    error!("Gave up notifying webhook of upload {}", event.filename);
+}
+
+/// Run blocking image work on Actix's blocking thread pool
+async fn blocking<T, F>(f: F) -> Result<T>
+where
+    F: FnOnce() -> Result<T> + Send + 'static,
+    T: Send + 'static,
+{
+    web::block(f).await.context("Blocking task failed")?
+}
+
+/// Collect every servable image in storage
+///
+/// Hidden entries (metadata, caches) and images past their expiry are skipped.
+async fn list_images(
+    config: &Config,
+    storage: &dyn Storage,
+    listing: Listing,
+) -> Result<Vec<ImageEntry>> {
+    let objects = stored_images(storage).await?;
+
+    let now = unix_now();
+    let mut images = Vec::new();
+    for object in objects {
+        let hidden = read_metadata(storage, &object.key)
+            .await?
+            .is_some_and(|image| image.is_expired(now) || !listing.includes(&image));
+        if hidden {
+            continue;
+        }
+
+        images.push(ImageEntry {
+            url: config.image_url(&object.key),
+            filename: object.key,
+            size: object.size,
+            modified: object.modified,
+        });
+    }
+    Ok(images)
+}
+
+/// Which unexpired images a listing shows
+#[derive(Clone, Copy)]
+enum Listing {
+    /// Every image, for totalling up storage
+    All,
+    /// What `/list` shows: one-time images are left out, so listing them doesn't
+    /// invite using up their only view
+    Listed,
+    /// What the gallery shows: also leaves out password-protected images, whose
+    /// thumbnails couldn't be loaded
+    Gallery,
+}
+
+impl Listing {
+    /// Whether an image with this metadata is shown
+    fn includes(self, metadata: &ImageMetadata) -> bool {
+        match self {
+            Listing::All => true,
+            Listing::Listed => !metadata.burn,
+            Listing::Gallery => !metadata.burn && metadata.token_hash.is_none(),
+        }
+    }
+}
+
+/// Fetch a page of servable images along with how many there are in total
+///
+/// The index is queried when one is configured; otherwise storage is scanned
+/// and sorted on every call.
+async fn image_page(
+    state: &AppState,
+    config: &Config,
+    listing: Listing,
+    order: SortOrder,
+    offset: usize,
+    limit: usize,
+) -> Result<(usize, Vec<ImageEntry>)> {
+    let Some(index) = state.index.clone() else {
+        let mut images = list_images(config, state.storage.as_ref(), listing).await?;
+        sort_images(&mut images, order);
+        let total = images.len();
+        let images = images.into_iter().skip(offset).take(limit).collect();
+        return Ok((total, images));
+    };
+
+    let newest_first = matches!(order, SortOrder::Newest);
+    let include_protected = !matches!(listing, Listing::Gallery);
+    let (total, entries) =
+        blocking(move || index.list(unix_now(), newest_first, include_protected, offset, limit))
+            .await?;
+    let images = entries
+        .into_iter()
+        .map(|entry| ImageEntry {
+            url: config.image_url(&entry.filename),
+            filename: entry.filename,
+            size: entry.size,
+            modified: entry.created_at,
+        })
+        .collect();
+    Ok((total, images))
+}
+
+/// Sort listed images by modification time, breaking ties by name
+fn sort_images(images: &mut [ImageEntry], order: SortOrder) {
+    images.sort_by(|a, b| {
+        let ordering = a
+            .modified
+            .cmp(&b.modified)
+            .then_with(|| a.filename.cmp(&b.filename));
+        match order {
+            SortOrder::Oldest => ordering,
+            SortOrder::Newest => ordering.reverse(),
+        }
+    });
+}
+
+/// Compare a provided API key against the configured one in constant time
+///
+/// Keys of differing length never match; only the length itself is observable.
+fn api_key_matches(provided: &str, expected: &str) -> bool {
+    provided.as_bytes().ct_eq(expected.as_bytes()).into()
+}
+
+/// Validate a user-supplied filename before it is joined onto the storage path
+///
+/// Nested names such as `2024/01/15/abc.png` are split on `/`, and each part
+/// must be a single plain path component: anything containing `..`, a
+/// backslash, an absolute prefix or a leading dot is rejected.
+fn sanitize_filename(filename: &str) -> Option<&str> {
+    filename
+        .split('/')
+        .all(|segment| {
+            let invalid = segment.is_empty()
+                || segment.starts_with('.')
+                || segment.contains("..")
+                || segment.contains(['\\', '\0']);
+            let mut components = Path::new(segment).components();
+            !invalid
+                && matches!(
+                    (components.next(), components.next()),
+                    (Some(Component::Normal(_)), None)
+                )
+        })
+        .then_some(filename)
+}
+
+#[actix_web::main]
+async fn main() -> Result<()> {
+    // Parse command-line arguments and load the server configuration, which picks the log format
+    let args = Args::parse();
+    let config_path = kimage::config_path(args.config.as_deref())?;
+    if args.init {
+        kimage::init_config(&config_path, args.force)?;
+        println!("Wrote a template config to {}", config_path.display());
+        return Ok(());
+    }
+    let config = match load_config(&config_path) {
+        Ok(config) => config,
+        Err(e) => {
+            // Report it as returning the error would, followed by advice on fixing it
+            let hint = e.hint();
+            eprintln!("Error: {:?}", anyhow::Error::from(e));
+            if let Some(hint) = hint {
+                eprintln!("Hint: {}", hint);
+            }
+            std::process::exit(1);
+        }
+    };
+
+    // Initialize the logger
+    logging::init(
+        LogFormat::from_env()?.unwrap_or(config.log_format),
+        logging::verbosity_level(args.verbose, args.quiet),
+        "info",
+    );
+    info!("Loaded config from: {:?}", config_path);
+    let bind_address = SocketAddr::new(config.bind_address, config.port);
+    let url_prefix = config.url_route_prefix();
+    let image_prefix = config.image_route_prefix();
+    if !url_prefix.is_empty()
+        && config
+            .server_url
+            .trim_end_matches('/')
+            .ends_with(&url_prefix)
+    {
+        warn!(
+            "server_url {:?} already ends with url_prefix {:?}; image URLs will repeat it",
+            config.server_url, url_prefix
+        );
+    }
+    let allowed_origins = config.allowed_origins.clone();
+    let tls_config = match (&config.tls_cert_path, &config.tls_key_path) {
+        (Some(cert_path), Some(key_path)) => Some(load_tls_config(cert_path, key_path)?),
+        (None, None) => None,
+        _ => bail!("tls_cert_path and tls_key_path must be set together to enable TLS"),
+    };
+    if tls_config.is_some() && config.unix_socket.is_some() {
+        bail!("TLS can't be served on a unix_socket; terminate it in the proxy instead");
+    }
+    let unix_socket = config.unix_socket.clone();
+    let unix_socket_mode = config.unix_socket_mode;
+    if tls_config.is_some() && !config.server_url.starts_with("https://") {
+        warn!(
+            "TLS is enabled but server_url {:?} is not an https:// URL",
+            config.server_url
+        );
+    }
+    let sweep_interval = Duration::from_secs(config.sweep_interval_seconds.max(1));
+    let shutdown_timeout = config.shutdown_timeout_seconds;
+    let workers = config.workers;
+    let storage = open_storage(&config).await?;
+    let index = match &config.index_path {
+        Some(index_path) => {
+            info!("Indexing uploads in {:?}", index_path);
+            Some(Arc::new(Index::open(index_path)?))
+        }
+        None => None,
+    };
+    // Serve metrics on the main port, or on their own when a port is set for them
+    let metrics_address = config
+        .metrics_port
+        .filter(|_| config.metrics)
+        .map(|port| SocketAddr::new(config.bind_address, port));
+    let inline_metrics = config.metrics && metrics_address.is_none();
+    let compress = config.compress;
+    let state = web::Data::new(AppState::new(
+        config_path,
+        config,
+        storage,
+        index,
+        Metrics::new()?,
+    ));
+
+    // Resumable uploads don't survive a restart, so the data of unfinished ones is of no use
+    if let Err(e) = fs::remove_dir_all(&state.partial_upload_dir) {
+        if e.kind() != io::ErrorKind::NotFound {
+            warn!(
+                "Failed to remove unfinished uploads in {:?}: {}",
+                state.partial_upload_dir, e
+            );
+        }
+    }
+
+    // Periodically delete images that have outlived their TTL
+    let sweep_state = state.clone();
+    let mut interval = actix_web::rt::time::interval(sweep_interval);
+    actix_web::rt::spawn(async move {
+        loop {
+            interval.tick().await;
+            match sweep_expired(sweep_state.storage.as_ref()).await {
+                Ok((0, _)) => {}
+                Ok((removed, freed)) => {
+                    sweep_state.release_storage(freed).await;
+                    info!("Removed {} expired image(s)", removed);
+                }
+                Err(e) => error!("Failed to sweep expired images: {:#}", e),
+            }
+            if let Some(index) = sweep_state.index.clone() {
+                if let Err(e) = blocking(move || index.remove_expired(unix_now())).await {
+                    error!("Failed to remove expired index entries: {:#}", e);
+                }
+            }
+            let timeout =
+                Duration::from_secs(sweep_state.config().resumable_upload_timeout_seconds);
+            let abandoned = sweep_state.remove_abandoned_uploads(timeout).await;
+            if abandoned > 0 {
+                info!("Removed {} abandoned resumable upload(s)", abandoned);
+            }
+        }
+    });
+
+    // Reload the config file whenever SIGHUP is received
+    #[cfg(unix)]
+    {
+        use actix_web::rt::signal::unix::{signal, SignalKind};
+
+        let mut hangup =
+            signal(SignalKind::hangup()).context("Failed to install SIGHUP handler")?;
+        let reload_state = state.clone();
+        actix_web::rt::spawn(async move {
+            while hangup.recv().await.is_some() {
+                info!("SIGHUP received, reloading config");
+                if let Err(e) = reload_state.reload() {
+                    error!("Failed to reload config, keeping previous config: {:#}", e);
+                }
+            }
+        });
+    }
+
+    let scheme = if tls_config.is_some() {
+        "https"
+    } else {
+        "http"
+    };
+    match &unix_socket {
+        Some(path) => info!("Server running on unix:{}", path.display()),
+        None => info!("Server running on {}://{}", scheme, bind_address),
+    }
+
+    // Start the HTTP server
+    let metrics_state = state.clone();
+    let server = HttpServer::new(move || {
+        App::new()
+            .wrap(Condition::new(compress, Compress::default()))
+            .wrap(from_fn(access_log))
+            .app_data(state.clone())
+            .app_data(query_config())
+            .service(routes(
+                &url_prefix,
+                &image_prefix,
+                &allowed_origins,
+                inline_metrics,
+            ))
+            .default_service(web::to(unknown_route))
+    });
+    let server = match workers {
+        0 => server,
+        workers => {
+            info!("Using {} worker(s)", workers);
+            server.workers(workers)
+        }
+    };
+    let server = match (tls_config, &unix_socket) {
+        (Some(tls_config), _) => server.bind_rustls_0_23(bind_address, tls_config)?,
+        #[cfg(unix)]
+        (None, Some(path)) => {
+            use std::os::unix::fs::PermissionsExt;
+
+            remove_stale_socket(path)?;
+            let server = server
+                .bind_uds(path)
+                .with_context(|| format!("Failed to bind {:?}", path))?;
+            fs::set_permissions(path, fs::Permissions::from_mode(unix_socket_mode))
+                .with_context(|| format!("Failed to set permissions of {:?}", path))?;
+            server
+        }
+        #[cfg(not(unix))]
+        (None, Some(_)) => bail!("unix_socket is only supported on Unix"),
+        (None, None) => server.bind(bind_address)?,
+    };
+    let server = server
+        .disable_signals()
+        .shutdown_timeout(shutdown_timeout)
+        .run();
+
+    // The metrics port only ever serves metrics, without the main server's middleware
+    let metrics_handle = match metrics_address {
+        Some(metrics_address) => {
+            let metrics_server = HttpServer::new(move || {
+                App::new()
+                    .app_data(metrics_state.clone())
+                    .route("/metrics", web::get().to(metrics))
+                    .default_service(web::to(unknown_route))
+            })
+            .workers(1)
+            .disable_signals()
+            .bind(metrics_address)?
+            .run();
+            info!("Metrics served on http://{}/metrics", metrics_address);
+            let handle = metrics_server.handle();
+            actix_web::rt::spawn(async move {
+                if let Err(e) = metrics_server.await {
+                    error!("Error running metrics server: {}", e);
+                }
+            });
+            Some(handle)
+        }
+        None => None,
+    };
+
+    // Stop accepting connections on SIGTERM or Ctrl-C, and let in-flight requests finish
+    let handle = server.handle();
+    let shutdown = shutdown_signal()?;
+    actix_web::rt::spawn(async move {
+        let signal = shutdown.await;
+        info!(
+            "{} received, finishing in-flight requests (up to {}s)",
+            signal, shutdown_timeout
+        );
+        if let Some(metrics_handle) = metrics_handle {
+            metrics_handle.stop(true).await;
+        }
+        handle.stop(true).await;
+    });
+
+    server.await.context("Error running server")?;
+    if let Some(path) = &unix_socket {
+        if let Err(e) = fs::remove_file(path) {
+            warn!("Failed to remove socket {:?}: {}", path, e);
+        }
+    }
+    info!("Server stopped");
+    Ok(())
+}
+
+/// Every route the main server answers, mounted under `url_prefix`
+fn routes(
+    url_prefix: &str,
+    image_prefix: &str,
+    allowed_origins: &[String],
+    inline_metrics: bool,
+) -> Scope {
+    let routes = web::scope(url_prefix)
+        .route("/health", web::get().to(health))
+        .route("/upload", web::post().to(upload))
+        .service(
+            web::resource("/upload-url")
+                .app_data(json_config())
+                .route(web::post().to(upload_from_url)),
+        )
+        .service(
+            web::resource("/upload/init")
+                .app_data(json_config())
+                .route(web::post().to(start_resumable_upload)),
+        )
+        .service(
+            web::resource("/upload/{id}")
+                .route(web::get().to(resumable_upload_status))
+                .route(web::patch().to(append_resumable_upload)),
+        )
+        .route("/list", web::get().to(list))
+        .route("/stats", web::get().to(stats))
+        .route("/gallery", web::get().to(gallery))
+        .route("/similar/{filename:.+}", web::get().to(similar))
+        .service(
+            web::resource("/alias")
+                .app_data(json_config())
+                .route(web::post().to(create_alias)),
+        )
+        .route("/admin/gc", web::post().to(collect_garbage))
+        .route("/admin/recompress", web::post().to(recompress_images))
+        .service(
+            web::resource("/sign")
+                .app_data(json_config())
+                .route(web::post().to(sign)),
+        );
+    // Fixed routes come before images, which take every path when `image_prefix` is empty
+    let routes = if inline_metrics {
+        routes.route("/metrics", web::get().to(metrics))
+    } else {
+        routes
+    };
+    let routes = routes.service(
+        web::resource(format!("{}/{{filename:.+}}", image_prefix))
+            .wrap(Condition::new(
+                !allowed_origins.is_empty(),
+                image_cors(allowed_origins),
+            ))
+            .route(web::get().to(serve_image))
+            .route(web::head().to(serve_image)),
+    );
+    if image_prefix.is_empty() {
+        routes
+    } else {
+        routes.service(
+            web::resource("/{filename}")
+                .route(web::get().to(redirect_legacy_image))
+                .route(web::head().to(redirect_legacy_image)),
+        )
+    }
+}
+
+/// Query string parsing that answers malformed queries in the API's error format
+fn query_config() -> web::QueryConfig {
+    web::QueryConfig::default()
+        .error_handler(|e, _| ApiError::bad_request("invalid_query", e.to_string()).into())
+}
+
+/// Remove a socket file left behind by a server that didn't shut down cleanly
+///
+/// Refuses to touch anything that isn't a socket, or a socket another server
+/// is still listening on.
+#[cfg(unix)]
+fn remove_stale_socket(path: &Path) -> Result<()> {
+    use std::os::unix::fs::FileTypeExt;
+    use std::os::unix::net::UnixStream;
+
+    let metadata = match fs::symlink_metadata(path) {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e).with_context(|| format!("Failed to inspect {:?}", path)),
+    };
+    if !metadata.file_type().is_socket() {
+        bail!("{:?} already exists and is not a socket", path);
+    }
+    if UnixStream::connect(path).is_ok() {
+        bail!("Another server is already listening on {:?}", path);
+    }
+    info!("Removing stale socket {:?}", path);
+    fs::remove_file(path).with_context(|| format!("Failed to remove stale socket {:?}", path))
+}
+
+/// Install handlers for the signals that ask the server to shut down
+///
+/// The returned future completes with the name of the first one received.
+fn shutdown_signal() -> Result<impl Future<Output = &'static str>> {
+    #[cfg(unix)]
+    {
+        use actix_web::rt::signal::unix::{signal, SignalKind};
+
+        let mut terminate =
+            signal(SignalKind::terminate()).context("Failed to install SIGTERM handler")?;
+        let mut interrupt =
+            signal(SignalKind::interrupt()).context("Failed to install SIGINT handler")?;
+        Ok(async move {
+            tokio::select! {
+                _ = terminate.recv() => "SIGTERM",
+                _ = interrupt.recv() => "SIGINT",
+            }
+        })
+    }
+    #[cfg(not(unix))]
+    {
+        Ok(async {
+            let _ = actix_web::rt::signal::ctrl_c().await;
+            "Ctrl-C"
+        })
+    }
+}
+
+/// Build the rustls server configuration from PEM certificate and key files
+fn load_tls_config(cert_path: &Path, key_path: &Path) -> Result<rustls::ServerConfig> {
+    info!("Loading TLS certificate from: {:?}", cert_path);
+    let cert_file = fs::File::open(cert_path).context("Failed to open TLS certificate")?;
+    let certs = rustls_pemfile::certs(&mut io::BufReader::new(cert_file))
+        .collect::<Result<Vec<_>, _>>()
+        .context("Failed to parse TLS certificate")?;
+    if certs.is_empty() {
+        bail!("No certificates found in {:?}", cert_path);
+    }
+
+    let key_file = fs::File::open(key_path).context("Failed to open TLS private key")?;
+    let key = rustls_pemfile::private_key(&mut io::BufReader::new(key_file))
+        .context("Failed to parse TLS private key")?
+        .ok_or_else(|| anyhow!("No private key found in {:?}", key_path))?;
+
+    rustls::ServerConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+        .with_safe_default_protocol_versions()
+        .context("Failed to set up TLS protocol versions")?
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .context("Invalid TLS certificate or key")
+}
+
+/// Whether a string is a bare origin such as `https://app.example.com:8080`
+fn is_valid_origin(origin: &str) -> bool {
+    let Ok(uri) = origin.parse::<Uri>() else {
+        return false;
+    };
+    match (uri.scheme_str(), uri.authority()) {
+        (Some(scheme @ ("http" | "https")), Some(authority)) => {
+            origin == format!("{}://{}", scheme, authority)
+        }
+        _ => false,
+    }
+}
+
+/// CORS policy for image routes, allowing the configured origins to read images
+fn image_cors(allowed_origins: &[String]) -> Cors {
+    let cors = Cors::default()
+        .allowed_methods(["GET", "HEAD"])
+        .block_on_origin_mismatch(false)
+        .max_age(3600);
+    if allowed_origins.iter().any(|origin| origin == "*") {
+        return cors.allow_any_origin().send_wildcard();
+    }
+    allowed_origins
+        .iter()
+        .fold(cors, |cors, origin| cors.allowed_origin(origin))
+}
+
+/// Load the server configuration from a TOML file
+fn load_config(config_path: &Path) -> Result<Config, ConfigError> {
+    let mut config: Config = kimage::read_config(config_path)?;
+    config.api_key = kimage::resolve_api_key(
+        config.api_key.take(),
+        config.api_key_file.as_deref(),
+        config.api_key_env.as_deref(),
+    )?;
+
+    // Convert relative storage and index paths to absolute
+    if !config.storage_path.as_os_str().is_empty() && config.storage_path.is_relative() {
+        config.storage_path = home_dir()
+            .ok_or_else(no_home_dir)?
+            .join(&config.storage_path);
+    }
+    if let Some(index_path) = config.index_path.as_mut().filter(|path| path.is_relative()) {
+        *index_path = home_dir().ok_or_else(no_home_dir)?.join(&*index_path);
+    }
+    if let Some(image) = config
+        .not_found_image
+        .as_mut()
+        .filter(|path| path.is_relative())
+    {
+        *image = home_dir().ok_or_else(no_home_dir)?.join(&*image);
+    }
+
+    config.validate()?;
+    info!("Config loaded successfully");
+    Ok(config)
+}
+
+/// Report that relative paths in the config have nothing to be resolved against
+fn no_home_dir() -> ConfigError {
+    ConfigError::Invalid(vec![
+        "Failed to get home directory to resolve relative paths".to_string(),
+    ])
+}
+
+/// Explain why a directory can't be created at `path`, if it can't
+///
+/// Nothing is created; the nearest existing ancestor is checked instead.
+fn uncreatable_dir(path: &Path) -> Option<String> {
+    if path.exists() {
+        return (!path.is_dir()).then(|| format!("{:?} is not a directory", path));
+    }
+    let ancestor = path
+        .ancestors()
+        .skip(1)
+        .find(|ancestor| ancestor.exists())?;
+    if !ancestor.is_dir() {
+        return Some(format!("{:?} can't be created inside a file", path));
+    }
+    let read_only = fs::metadata(ancestor).is_ok_and(|metadata| metadata.permissions().readonly());
+    read_only.then(|| {
+        format!(
+            "{:?} can't be created, as {:?} is read-only",
+            path, ancestor
+        )
+    })
+}
+
+/// Open the storage backend selected by the config
+async fn open_storage(config: &Config) -> Result<Arc<dyn Storage>> {
+    match config.backend {
+        Backend::Local => {
+            info!("Storing images in {:?}", config.storage_path);
+            // Only created at startup; one that vanishes later is reported rather than recreated
+            if !config.storage_path.exists() {
+                fs::create_dir_all(&config.storage_path).with_context(|| {
+                    format!(
+                        "Failed to create storage directory {:?}; check its permissions",
+                        config.storage_path
+                    )
+                })?;
+                info!("Created storage directory {:?}", config.storage_path);
+            }
+            let storage = LocalStorage::new(&config.storage_path);
+            // Nothing is being written yet, so any temporary files were abandoned by a crash
+            match storage.remove_temp_files().await {
+                Ok(0) => {}
+                Ok(removed) => info!("Removed {} leftover temporary file(s)", removed),
+                Err(e) => warn!("Failed to remove leftover temporary files: {:#}", e),
+            }
+            Ok(Arc::new(storage))
+        }
+        #[cfg(feature = "s3")]
+        Backend::S3 => {
+            let options = config.s3.as_ref().context("Missing [s3] section")?;
+            info!("Storing images in S3 bucket {:?}", options.bucket);
+            Ok(Arc::new(S3Storage::new(options)))
+        }
+        #[cfg(not(feature = "s3"))]
+        Backend::S3 => {
+            bail!("kimage-serve was built without S3 support; rebuild it with `--features s3`")
+        }
+    }
+}
+
+/// Current time as seconds since the Unix epoch
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+/// Format a Unix timestamp as an RFC 3339 date, such as `2024-01-15T14:30:22Z`
+fn rfc3339(timestamp: u64) -> String {
+    i64::try_from(timestamp)
+        .ok()
+        .and_then(|secs| chrono::DateTime::from_timestamp(secs, 0))
+        .unwrap_or(chrono::DateTime::<Utc>::MAX_UTC)
+        .to_rfc3339_opts(chrono::SecondsFormat::Secs, true)
+}
+
+/// Storage key of the metadata sidecar for an uploaded image
+fn metadata_key(filename: &str) -> String {
+    format!(".meta/{}.json", filename)
+}
+
+/// Storage prefix holding cached derivatives (such as thumbnails) of an uploaded image
+fn cache_prefix(filename: &str) -> String {
+    format!(".cache/{}/", filename)
+}
+
+/// Storage key claimed by the request that gets to view a one-time image
+fn burn_claim_key(filename: &str) -> String {
+    format!(".burned/{}", filename)
+}
+
+/// Look up what was recorded about an image when it was uploaded
+///
+/// The index is consulted first when one is configured, falling back to the
+/// sidecar for images uploaded before it was enabled.
+async fn image_metadata(state: &AppState, filename: &str) -> Result<Option<ImageMetadata>> {
+    if let Some(index) = state.index.clone() {
+        let name = filename.to_owned();
+        if let Some(entry) = blocking(move || index.get(&name)).await? {
+            return Ok(Some(ImageMetadata {
+                uploaded_at: entry.created_at,
+                expires_at: entry.expires_at,
+                content_type: Some(entry.content_type),
+                token_hash: entry.token_hash,
+                burn: entry.burn,
+            }));
+        }
+    }
+    read_metadata(state.storage.as_ref(), filename).await
+}
+
+/// Read the metadata sidecar for an image, if one was recorded
+async fn read_metadata(storage: &dyn Storage, filename: &str) -> Result<Option<ImageMetadata>> {
+    let Some(contents) = storage
+        .get(&metadata_key(filename))
+        .await
+        .context("Failed to read metadata file")?
+    else {
+        return Ok(None);
+    };
+    let metadata = serde_json::from_slice(&contents).context("Failed to parse metadata file")?;
+    Ok(Some(metadata))
+}
+
+/// Write the metadata sidecar for an image
+async fn write_metadata(
+    storage: &dyn Storage,
+    filename: &str,
+    metadata: &ImageMetadata,
+) -> Result<()> {
+    let contents = serde_json::to_vec(metadata).context("Failed to serialize metadata")?;
+    storage
+        .put(&metadata_key(filename), contents)
+        .await
+        .context("Failed to write metadata file")
+}
+
+/// Delete every image whose TTL has passed, returning how many were removed and the bytes freed
+async fn sweep_expired(storage: &dyn Storage) -> Result<(usize, u64)> {
+    let sidecars = list_nested(storage, ".meta/")
+        .await
+        .context("Failed to list metadata")?;
+
+    let now = unix_now();
+    let mut removed = 0;
+    let mut freed = 0;
+    for sidecar in sidecars {
+        let Some(filename) = sidecar
+            .key
+            .strip_prefix(".meta/")
+            .and_then(|name| name.strip_suffix(".json"))
+        else {
+            continue;
+        };
+
+        match read_metadata(storage, filename).await {
+            Ok(Some(metadata)) if metadata.is_expired(now) => {}
+            Ok(_) => continue,
+            Err(e) => {
+                error!("Skipping metadata for {}: {:#}", filename, e);
+                continue;
+            }
+        }
+
+        freed += remove_image(storage, filename).await?;
+        info!("Removed expired image: {}", filename);
+        removed += 1;
+    }
+    Ok((removed, freed))
+}
+
+/// Delete an image along with its cached derivatives and metadata sidecar
+///
+/// Returns the size of the image, or 0 if it was already gone.
+async fn remove_image(storage: &dyn Storage, filename: &str) -> Result<u64> {
+    let size = storage
+        .stat(filename)
+        .await
+        .with_context(|| format!("Failed to look up {}", filename))?
+        .map_or(0, |object| object.size);
+    storage
+        .delete(filename)
+        .await
+        .with_context(|| format!("Failed to remove {}", filename))?;
+    remove_cached_copies(storage, filename).await?;
+    storage
+        .delete(&metadata_key(filename))
+        .await
+        .with_context(|| format!("Failed to remove metadata for {}", filename))?;
+    Ok(size)
+}
+
+/// Delete the thumbnails and conversions cached for an image
+async fn remove_cached_copies(storage: &dyn Storage, filename: &str) -> Result<()> {
+    let cached = storage
+        .list(&cache_prefix(filename))
+        .await
+        .with_context(|| format!("Failed to list cached copies of {}", filename))?;
+    for object in cached {
+        storage
+            .delete(&object.key)
+            .await
+            .with_context(|| format!("Failed to remove cached copies of {}", filename))?;
+    }
+    Ok(())
+}
+
+/// Every stored image, leaving out metadata, cached thumbnails and other bookkeeping
+async fn stored_images(storage: &dyn Storage) -> Result<Vec<StoredObject>> {
+    let objects = list_nested(storage, "")
+        .await
+        .context("Failed to list stored images")?;
+    Ok(objects
+        .into_iter()
+        .filter(|object| {
+            !object
+                .key
+                .rsplit('/')
+                .next()
+                .unwrap_or_default()
+                .starts_with('.')
+        })
+        .collect())
+}
+
+/// List every object below `prefix`, however deeply nested
+///
+/// Directories whose names start with a dot, such as `.meta/` inside the
+/// root, hold bookkeeping and are skipped.
+async fn list_nested(storage: &dyn Storage, prefix: &str) -> Result<Vec<StoredObject>> {
+    let mut objects = Vec::new();
+    let mut prefixes = vec![prefix.to_owned()];
+    while let Some(prefix) = prefixes.pop() {
+        objects.extend(storage.list(&prefix).await?);
+        let dirs = storage.list_dirs(&prefix).await?;
+        prefixes.extend(
+            dirs.into_iter()
+                .filter(|dir| !dir[prefix.len()..].starts_with('.')),
+        );
+    }
+    Ok(objects)
+}
+
+/// Names that can't be used as slugs because they clash with server routes
+const RESERVED_NAMES: &[&str] = &[
+    "health",
+    "upload",
+    "list",
+    "gallery",
+    "similar",
+    "alias",
+    "admin",
+    "metrics",
+    "upload-url",
+    "sign",
+    "stats",
+];
+
+/// Check that a client-chosen slug is safe and free to use as a filename stem
+fn validate_slug(slug: &str, config: &Config) -> Result<(), String> {
+    if slug.is_empty() || slug.len() > 64 {
+        return Err("Names must be between 1 and 64 characters".to_string());
+    }
+    if !slug
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    {
+        return Err("Names may only contain letters, digits, '-' and '_'".to_string());
+    }
+    let reserved = RESERVED_NAMES
+        .iter()
+        .copied()
+        .chain(Some(config.image_prefix.trim_matches('/')));
+    if reserved
+        .into_iter()
+        .any(|name| name.eq_ignore_ascii_case(slug))
+    {
+        return Err(format!("{:?} is reserved", slug));
+    }
+    Ok(())
+}
+
+/// Generate a filename for uploaded images with the given extension
+fn generate_filename(extension: &str, config: &Config, rng: &mut impl Rng) -> String {
+    let stem = match config.filename_strategy {
+        FilenameStrategy::Random => random_string(config.filename_length, rng),
+        FilenameStrategy::Timestamp => format!(
+            "{}-{}",
+            Utc::now().format("%Y%m%d-%H%M%S"),
+            random_string(TIMESTAMP_SUFFIX_LENGTH, rng)
+        ),
+        FilenameStrategy::Uuid => Uuid::now_v7().to_string(),
+    };
+    format!("{}.{}", stem, extension)
+}
+
+/// Generate `length` random letters and digits
+fn random_string(length: usize, rng: &mut impl Rng) -> String {
+    (0..length)
+        .map(|_| rng.sample(rand::distributions::Alphanumeric) as char)
+        .collect()
+}
+
+/// Generate a filename that isn't already taken in storage, placed as `storage_layout` says
+async fn free_filename(state: &AppState, extension: &str, config: &Config) -> Result<String> {
+    for _ in 0..FILENAME_ATTEMPTS {
+        let generated = generate_filename(
+            extension,
+            config,
+            &mut *state
+                .filename_rng
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner),
+        );
+        let filename = config.storage_layout.key(&generated);
+        if !state.storage.exists(&filename).await? {
+            return Ok(filename);
+        }
+        warn!("Generated filename {} is taken, picking another", filename);
+    }
+    bail!(
+        "No free filename found in {} attempts; consider raising filename_length",
+        FILENAME_ATTEMPTS
+    )
+}
+
+/// Hash an image's access token for storage, as a random salt and the salted
+/// SHA-256 digest, both in hex and separated by `$`
+fn hash_token(token: &str) -> String {
+    let salt: [u8; 16] = rand::thread_rng().gen();
+    let salt = hex_string(&salt);
+    let digest = Sha256::digest(format!("{}{}", salt, token));
+    format!("{}${}", salt, hex_string(&digest))
+}
+
+/// Check a token against a hash made by [`hash_token`], in constant time
+fn verify_token(hash: &str, token: &str) -> bool {
+    let Some((salt, expected)) = hash.split_once('$') else {
+        return false;
+    };
+    let digest = hex_string(&Sha256::digest(format!("{}{}", salt, token)));
+    digest.as_bytes().ct_eq(expected.as_bytes()).into()
+}
+
+/// Format bytes as lowercase hex
+fn hex_string(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Derive a filename from the SHA-256 digest of the image contents
+fn content_filename(contents: &[u8], extension: &str) -> String {
+    let digest = format!("{:x}", Sha256::digest(contents));
+    format!("{}.{}", &digest[..16], extension)
+}
+
+/// Map a file extension to the content type of the image format it denotes
+///
+/// Formats the `image` crate knows are looked up directly; others are accepted
+/// as long as they are commonly registered as an `image/*` type.
+fn content_type_for(extension: &str) -> Option<&'static str> {
+    ImageFormat::from_extension(extension)
+        .map(|format| format.to_mime_type())
+        .or_else(|| {
+            mime_guess::from_ext(extension)
+                .iter_raw()
+                .find(|mime| mime.starts_with("image/"))
+        })
+}
+
+/// Recover the image bytes from an upload's `image` field
+///
+/// Current clients send the raw file, while older ones base64-encode it. Raw
+/// images start with a binary magic number that base64 text never matches,
+/// so anything recognisable as an image is kept as is and everything else is
+/// decoded as base64.
+fn decode_image_field(bytes: Vec<u8>) -> Result<Vec<u8>, base64::DecodeError> {
+    if image::guess_format(&bytes).is_ok() {
+        return Ok(bytes);
+    }
+    general_purpose::STANDARD.decode(bytes.trim_ascii())
+}
+
+/// Number of bits in a perceptual hash
+const PERCEPTUAL_HASH_BITS: u32 = 64;
+
+/// Compute a perceptual hash of an image, which changes little when the image is
+/// re-encoded, resized or slightly cropped
+fn perceptual_hash(contents: &[u8], limits: DimensionLimits) -> Result<u64> {
+    let image = decode_image(contents, limits)?;
+    let hash = HasherConfig::new()
+        .hash_size(8, 8)
+        .to_hasher()
+        .hash_image(&image);
+    let bytes = hash
+        .as_bytes()
+        .try_into()
+        .context("Perceptual hash has an unexpected size")?;
+    Ok(u64::from_be_bytes(bytes))
+}
+
+/// Largest images the server accepts and will decode
+#[derive(Clone, Copy)]
+struct DimensionLimits {
+    max_width: u32,
+    max_height: u32,
+    max_pixels: u64,
+}
+
+impl DimensionLimits {
+    /// Explain why a `width`x`height` image is too large, if it is
+    fn check(self, width: u32, height: u32) -> Result<(), String> {
+        if width > self.max_width || height > self.max_height {
+            return Err(format!(
+                "Images may be at most {}x{} pixels, not {}x{}",
+                self.max_width, self.max_height, width, height
+            ));
+        }
+        let pixels = u64::from(width) * u64::from(height);
+        if pixels > self.max_pixels {
+            return Err(format!(
+                "Images may have at most {} pixels, not {} ({}x{})",
+                self.max_pixels, pixels, width, height
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Decode an image, first reading its dimensions from the header so one beyond
+/// `limits` is refused before memory is allocated for its pixels
+fn decode_image(contents: &[u8], limits: DimensionLimits) -> Result<DynamicImage> {
+    let reader = || {
+        image::io::Reader::new(Cursor::new(contents))
+            .with_guessed_format()
+            .context("Failed to read image")
+    };
+    let (width, height) = reader()?
+        .into_dimensions()
+        .context("Failed to read image dimensions")?;
+    limits
+        .check(width, height)
+        .map_err(|reason| anyhow!(reason))?;
+    reader()?.decode().context("Failed to decode image")
+}
+
+/// Check that `contents` is an image in one of the configured formats, returning its
+/// format and dimensions
+///
+/// The header is parsed to make sure the data isn't just a matching magic
+/// number, but the pixels aren't decoded.
+fn validate_image(contents: &[u8], config: &Config) -> Result<(ImageFormat, (u32, u32)), String> {
+    let format = image::guess_format(contents)
+        .map_err(|_| "Upload is not a recognised image format".to_string())?;
+    let permitted = config
+        .allowed_formats
+        .iter()
+        .any(|name| ImageFormat::from_extension(name) == Some(format));
+    if !permitted {
+        return Err(format!(
+            "{} images are not accepted",
+            format.extensions_str()[0].to_uppercase()
+        ));
+    }
+    let dimensions = image::io::Reader::with_format(Cursor::new(contents), format)
+        .into_dimensions()
+        .map_err(|e| format!("Upload is not a valid image: {}", e))?;
+    Ok((format, dimensions))
+}
+
+/// Work out the content type of a stored image
+///
+/// The leading bytes are sniffed first since they describe what was actually
+/// stored, then the file extension is consulted. Anything unrecognised is
+/// served as `application/octet-stream`.
+fn detect_content_type(path: &Path, contents: &[u8]) -> &'static str {
+    image::guess_format(contents)
+        .map(|format| format.to_mime_type())
+        .ok()
+        .or_else(|| {
+            path.extension()
+                .and_then(|ext| ext.to_str())
+                .and_then(content_type_for)
+        })
+        .unwrap_or("application/octet-stream")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::body::to_bytes;
+    use actix_web::dev::{Service, ServiceResponse};
+    use actix_web::test;
+    use tempfile::TempDir;
+
+    const API_KEY: &str = "test-key";
+    const BOUNDARY: &str = "kimage-test-boundary";
+
+    /// App state storing images in `images/` inside a fresh temporary directory,
+    /// which is removed when the `TempDir` drops
+    fn test_state() -> (TempDir, web::Data<AppState>) {
+        test_state_with("")
+    }
+
+    /// Like [`test_state`], with `extra` lines added to the config, in which `{dir}`
+    /// stands for the temporary directory
+    fn test_state_with(extra: &str) -> (TempDir, web::Data<AppState>) {
+        let dir = tempfile::tempdir().unwrap();
+        let storage_path = dir.path().join("images");
+        fs::create_dir(&storage_path).unwrap();
+        let config: Config = toml::from_str(&format!(
+            "port = 0\nserver_url = \"http://img.test\"\napi_key = \"{}\"\nstorage_path = {:?}\n\
+             signing_secret = \"test-secret\"\n{}",
+            API_KEY,
+            storage_path,
+            extra.replace("{dir}", &dir.path().to_string_lossy())
+        ))
+        .unwrap();
+        let storage = Arc::new(LocalStorage::new(storage_path));
+        let index = config
+            .index_path
+            .as_deref()
+            .map(|path| Arc::new(Index::open(path).unwrap()));
+        let state = AppState::new(
+            dir.path().join("kimage.toml"),
+            config,
+            storage,
+            index,
+            Metrics::new().unwrap(),
+        );
+        (dir, web::Data::new(state))
+    }
+
+    /// The server's routes, mounted the way `main` mounts them
+    async fn test_app(
+        state: web::Data<AppState>,
+    ) -> impl Service<actix_http::Request, Response = ServiceResponse, Error = actix_web::Error>
+    {
+        let config = state.config();
+        test::init_service(
+            App::new()
+                .app_data(state)
+                .app_data(query_config())
+                .service(routes(
+                    &config.url_route_prefix(),
+                    &config.image_route_prefix(),
+                    &config.allowed_origins,
+                    config.metrics,
+                ))
+                .default_service(web::to(unknown_route)),
+        )
+        .await
+    }
+
+    /// A small PNG to upload
+    fn png() -> Vec<u8> {
+        let mut bytes = Vec::new();
+        DynamicImage::new_rgb8(4, 3)
+            .write_to(&mut Cursor::new(&mut bytes), ImageOutputFormat::Png)
+            .unwrap();
+        bytes
+    }
+
+    /// A noisy JPEG, big enough that re-encoding it at a lower quality saves space
+    fn jpeg() -> Vec<u8> {
+        let noise =
+            image::RgbImage::from_fn(64, 64, |x, y| image::Rgb([((x * 37) ^ (y * 91)) as u8; 3]));
+        let mut bytes = Vec::new();
+        DynamicImage::ImageRgb8(noise)
+            .write_to(&mut Cursor::new(&mut bytes), ImageOutputFormat::Jpeg(100))
+            .unwrap();
+        bytes
+    }
+
+    /// A multipart body holding one field per `(name, contents)` pair
+    fn multipart(fields: &[(&str, &[u8])]) -> Vec<u8> {
+        let parts: Vec<_> = fields
+            .iter()
+            .map(|&(name, contents)| (name, None, contents))
+            .collect();
+        typed_multipart(&parts)
+    }
+
+    /// A multipart body of `(name, content type, contents)` parts, each part declaring
+    /// its content type if it has one
+    fn typed_multipart(parts: &[(&str, Option<&str>, &[u8])]) -> Vec<u8> {
+        let mut body = Vec::new();
+        for (name, mime, contents) in parts {
+            body.extend_from_slice(
+                format!(
+                    "--{}\r\nContent-Disposition: form-data; name=\"{}\"\r\n",
+                    BOUNDARY, name
+                )
+                .as_bytes(),
+            );
+            if let Some(mime) = mime {
+                body.extend_from_slice(format!("Content-Type: {}\r\n", mime).as_bytes());
+            }
+            body.extend_from_slice(b"\r\n");
+            body.extend_from_slice(contents);
+            body.extend_from_slice(b"\r\n");
+        }
+        body.extend_from_slice(format!("--{}--\r\n", BOUNDARY).as_bytes());
+        body
+    }
+
+    /// An upload request sending `fields`, authorized with `key` if given
+    fn upload_request(key: Option<&str>, fields: &[(&str, &[u8])]) -> test::TestRequest {
+        upload_payload(key, multipart(fields))
+    }
+
+    /// An upload request sending the multipart `body`, authorized with `key` if given
+    fn upload_payload(key: Option<&str>, body: Vec<u8>) -> test::TestRequest {
+        let request = test::TestRequest::post()
+            .uri("/upload")
+            .insert_header((
+                header::CONTENT_TYPE,
+                format!("multipart/form-data; boundary={}", BOUNDARY),
+            ))
+            .set_payload(body);
+        match key {
+            Some(key) => request.insert_header((header::AUTHORIZATION, key)),
+            None => request,
+        }
+    }
+
+    /// The `code` of a JSON error response
+    async fn error_code(response: ServiceResponse) -> String {
+        let body = to_bytes(response.into_body()).await.unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        body["code"].as_str().unwrap().to_owned()
+    }
+
+    #[actix_web::test]
+    async fn upload_with_valid_key_is_stored_and_served() {
+        let (_dir, state) = test_state();
+        let app = test_app(state).await;
+        let image = png();
+
+        let request = upload_request(Some(API_KEY), &[("image", &image), ("extension", b"png")]);
+        let response = test::call_service(&app, request.to_request()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body: serde_json::Value = test::read_body_json(response).await;
+        assert_eq!(body["width"], 4);
+        assert_eq!(body["height"], 3);
+        assert_eq!(body["content_type"], "image/png");
+        let url = body["url"].as_str().unwrap();
+        let path = url.strip_prefix("http://img.test").unwrap();
+        assert!(path.starts_with("/i/") && path.ends_with(".png"), "{}", url);
+
+        let response =
+            test::call_service(&app, test::TestRequest::get().uri(path).to_request()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.headers().get(header::CONTENT_TYPE).unwrap(),
+            "image/png"
+        );
+        assert_eq!(test::read_body(response).await, image);
+    }
+
+    /// Seed the filename generator, returning the first `count` names it will pick
+    fn seed_filenames(state: &AppState, seed: u64, count: usize) -> Vec<String> {
+        let config = state.config();
+        *state.filename_rng.lock().unwrap() = StdRng::seed_from_u64(seed);
+        let mut rng = StdRng::seed_from_u64(seed);
+        (0..count)
+            .map(|_| generate_filename("png", &config, &mut rng))
+            .collect()
+    }
+
+    #[actix_web::test]
+    async fn upload_retries_taken_filenames() {
+        let (dir, state) = test_state();
+        let names = seed_filenames(&state, 7, 3);
+        for name in &names[..2] {
+            fs::write(dir.path().join("images").join(name), b"taken").unwrap();
+        }
+        let app = test_app(state).await;
+
+        let request = upload_request(Some(API_KEY), &[("image", &png())]);
+        let response = test::call_service(&app, request.to_request()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body: serde_json::Value = test::read_body_json(response).await;
+        assert_eq!(body["url"], format!("http://img.test/i/{}", names[2]));
+        assert_eq!(
+            fs::read(dir.path().join("images").join(&names[0])).unwrap(),
+            b"taken"
+        );
+    }
+
+    #[actix_web::test]
+    async fn upload_fails_once_every_filename_attempt_is_taken() {
+        let (dir, state) = test_state();
+        for name in seed_filenames(&state, 7, FILENAME_ATTEMPTS) {
+            fs::write(dir.path().join("images").join(name), b"taken").unwrap();
+        }
+        let app = test_app(state).await;
+
+        let request = upload_request(Some(API_KEY), &[("image", &png())]);
+        let response = test::call_service(&app, request.to_request()).await;
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(error_code(response).await, "storage_error");
+    }
+
+    #[actix_web::test]
+    async fn metrics_are_served_when_images_have_no_prefix() {
+        let (_dir, state) = test_state_with("image_prefix = \"\"\nmetrics = true\n");
+        let app = test_app(state).await;
+
+        let request = test::TestRequest::get().uri("/metrics");
+        let response = test::call_service(&app, request.to_request()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.headers().get(header::CONTENT_TYPE).unwrap(),
+            kimage::metrics::CONTENT_TYPE
+        );
+    }
+
+    #[actix_web::test]
+    async fn upload_with_invalid_key_is_refused() {
+        let (dir, state) = test_state();
+        let app = test_app(state).await;
+
+        let request = upload_request(Some("wrong-key"), &[("image", &png())]);
+        let response = test::call_service(&app, request.to_request()).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(error_code(response).await, "invalid_api_key");
+
+        let request = upload_request(None, &[("image", &png())]);
+        let response = test::call_service(&app, request.to_request()).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(error_code(response).await, "missing_authorization");
+
+        assert_eq!(fs::read_dir(dir.path().join("images")).unwrap().count(), 0);
+    }
+
+    #[actix_web::test]
+    async fn keys_of_a_different_length_are_refused() {
+        let (dir, state) = test_state();
+        let app = test_app(state).await;
+
+        let longer = format!("{}x", API_KEY);
+        for key in [&API_KEY[..API_KEY.len() - 1], longer.as_str(), ""] {
+            assert!(!api_key_matches(key, API_KEY), "{:?}", key);
+
+            let request = upload_request(Some(key), &[("image", &png())]);
+            let response = test::call_service(&app, request.to_request()).await;
+            assert_eq!(response.status(), StatusCode::UNAUTHORIZED, "{:?}", key);
+            assert_eq!(error_code(response).await, "invalid_api_key");
+        }
+        assert!(api_key_matches(API_KEY, API_KEY));
+        assert_eq!(fs::read_dir(dir.path().join("images")).unwrap().count(), 0);
+    }
+
+    #[actix_web::test]
+    async fn upload_with_key_in_basic_auth_is_accepted() {
+        let (_dir, state) = test_state();
+        let app = test_app(state).await;
+
+        for credentials in [format!("user:{}", API_KEY), format!("{}:", API_KEY)] {
+            let basic = format!("Basic {}", general_purpose::STANDARD.encode(credentials));
+            let request = upload_request(Some(&basic), &[("image", &png())]);
+            let response = test::call_service(&app, request.to_request()).await;
+            assert_eq!(response.status(), StatusCode::OK, "{}", basic);
+        }
+
+        let basic = format!(
+            "Basic {}",
+            general_purpose::STANDARD.encode("user:wrong-key")
+        );
+        let request = upload_request(Some(&basic), &[("image", &png())]);
+        let response = test::call_service(&app, request.to_request()).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(error_code(response).await, "invalid_api_key");
+    }
+
+    #[actix_web::test]
+    async fn upload_is_stored_under_its_sniffed_format() {
+        let (_dir, state) = test_state();
+        let app = test_app(state).await;
+
+        let request = upload_request(Some(API_KEY), &[("image", &png()), ("extension", b"jpg")]);
+        let response = test::call_service(&app, request.to_request()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body: serde_json::Value = test::read_body_json(response).await;
+        let url = body["url"].as_str().unwrap();
+        assert!(url.ends_with(".png"), "{}", url);
+        assert_eq!(body["content_type"], "image/png");
+    }
+
+    #[actix_web::test]
+    async fn declared_type_is_overruled_by_the_contents() {
+        let (_dir, state) = test_state();
+        let app = test_app(state).await;
+
+        let body = typed_multipart(&[("image", Some("image/jpeg"), &png())]);
+        let response =
+            test::call_service(&app, upload_payload(Some(API_KEY), body).to_request()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body: serde_json::Value = test::read_body_json(response).await;
+        let url = body["url"].as_str().unwrap();
+        assert!(url.ends_with(".png"), "{}", url);
+        assert_eq!(body["content_type"], "image/png");
+
+        let body = typed_multipart(&[("image", Some("application/octet-stream"), &png())]);
+        let response =
+            test::call_service(&app, upload_payload(Some(API_KEY), body).to_request()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[actix_web::test]
+    async fn upload_declared_as_a_non_image_is_rejected() {
+        let (_dir, state) = test_state();
+        let app = test_app(state).await;
+
+        let body = typed_multipart(&[("image", Some("text/html"), &png())]);
+        let response =
+            test::call_service(&app, upload_payload(Some(API_KEY), body).to_request()).await;
+        assert_eq!(response.status(), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+        assert_eq!(error_code(response).await, "unsupported_media_type");
+    }
+
+    #[actix_web::test]
+    async fn stats_count_stored_images_by_format() {
+        let (_dir, state) = test_state();
+        let app = test_app(state).await;
+
+        let request = upload_request(Some(API_KEY), &[("image", &png())]);
+        let response = test::call_service(&app, request.to_request()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let request = test::TestRequest::get()
+            .uri("/stats")
+            .insert_header(("Authorization", API_KEY));
+        let response = test::call_service(&app, request.to_request()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body: serde_json::Value = test::read_body_json(response).await;
+        assert_eq!(body["files"], 1);
+        assert_eq!(body["formats"]["image/png"]["files"], 1);
+        assert_eq!(body["bytes"], png().len());
+        assert!(body["free_bytes"].is_u64());
+    }
+
+    /// Mark a stored image as past its TTL, as if the sweeper hadn't got to it yet
+    async fn expire(state: &AppState, filename: &str) {
+        let expired = ImageMetadata {
+            uploaded_at: 1,
+            expires_at: Some(2),
+            content_type: Some("image/png".to_string()),
+            token_hash: None,
+            burn: false,
+        };
+        write_metadata(state.storage.as_ref(), filename, &expired)
+            .await
+            .unwrap();
+    }
+
+    #[actix_web::test]
+    async fn dedupe_replaces_an_expired_identical_copy() {
+        let (_dir, state) = test_state_with("dedupe = true\n");
+        let app = test_app(state.clone()).await;
+
+        let request = upload_request(Some(API_KEY), &[("image", &png())]);
+        let response = test::call_service(&app, request.to_request()).await;
+        let body: serde_json::Value = test::read_body_json(response).await;
+        let filename = body["url"].as_str().unwrap().rsplit('/').next().unwrap();
+
+        // Expired, but not swept yet
+        expire(&state, filename).await;
+
+        let request = upload_request(Some(API_KEY), &[("image", &png())]);
+        let response = test::call_service(&app, request.to_request()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let again: serde_json::Value = test::read_body_json(response).await;
+        assert_eq!(again["url"], body["url"]);
+        assert_eq!(again["existing"], false);
+        assert!(again["expires_at"].is_null());
+        let metadata = read_metadata(state.storage.as_ref(), filename)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(!metadata.is_expired(unix_now()));
+
+        let request = upload_request(Some(API_KEY), &[("image", &png())]);
+        let response = test::call_service(&app, request.to_request()).await;
+        let third: serde_json::Value = test::read_body_json(response).await;
+        assert_eq!(third["existing"], true);
+    }
+
+    #[actix_web::test]
+    async fn dedupe_names_ignore_the_date_layout() {
+        let (_dir, state) = test_state_with("dedupe = true\nstorage_layout = \"date\"\n");
+        let app = test_app(state).await;
+
+        let request = upload_request(Some(API_KEY), &[("image", &png())]);
+        let response = test::call_service(&app, request.to_request()).await;
+        let body: serde_json::Value = test::read_body_json(response).await;
+        let url = body["url"].as_str().unwrap();
+        let key = url.strip_prefix("http://img.test/i/").unwrap();
+        let filename = key.rsplit('/').next().unwrap();
+        assert_eq!(key, StorageLayout::HashPrefix.key(filename));
+
+        let request = upload_request(Some(API_KEY), &[("image", &png())]);
+        let response = test::call_service(&app, request.to_request()).await;
+        let again: serde_json::Value = test::read_body_json(response).await;
+        assert_eq!(again["url"], url);
+        assert_eq!(again["existing"], true);
+    }
+
+    /// The gallery page, logged in with `gallery_key = "gallery"`
+    async fn gallery_page(
+        app: &impl Service<actix_http::Request, Response = ServiceResponse, Error = actix_web::Error>,
+    ) -> String {
+        let credentials = general_purpose::STANDARD.encode("user:gallery");
+        let request = test::TestRequest::get()
+            .uri("/gallery")
+            .insert_header((header::AUTHORIZATION, format!("Basic {}", credentials)));
+        let response = test::call_service(app, request.to_request()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        String::from_utf8(test::read_body(response).await.to_vec()).unwrap()
+    }
+
+    #[actix_web::test]
+    async fn gallery_thumbnails_extend_a_templated_query() {
+        let (_dir, state) =
+            test_state_with("gallery_key = \"gallery\"\nurl_template = \"{base}{path}?v=1\"\n");
+        let app = test_app(state).await;
+
+        let request = upload_request(Some(API_KEY), &[("image", &png())]);
+        let response = test::call_service(&app, request.to_request()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let page = gallery_page(&app).await;
+        assert!(page.contains("?v=1&amp;w="), "{}", page);
+        assert!(!page.contains("?v=1?"), "{}", page);
+    }
+
+    #[actix_web::test]
+    async fn one_time_and_protected_images_are_kept_out_of_listings() {
+        for index in ["", "index_path = \"{dir}/kimage.db\"\n"] {
+            let (_dir, state) = test_state_with(&format!("gallery_key = \"gallery\"\n{}", index));
+            let app = test_app(state).await;
+
+            let image = png();
+            let mut urls = Vec::new();
+            for (fields, burn) in [
+                (vec![("image", &image[..])], false),
+                (vec![("image", &image[..])], true),
+                (
+                    vec![("image", &image[..]), ("password", &b"secret"[..])],
+                    false,
+                ),
+            ] {
+                let request = upload_request(Some(API_KEY), &fields)
+                    .insert_header(("X-Burn", burn.to_string()));
+                let response = test::call_service(&app, request.to_request()).await;
+                assert_eq!(response.status(), StatusCode::OK);
+                let body: serde_json::Value = test::read_body_json(response).await;
+                urls.push(body["url"].as_str().unwrap().to_owned());
+            }
+            let [plain, burned, protected] = &urls[..] else {
+                unreachable!()
+            };
+
+            let request = test::TestRequest::get()
+                .uri("/list")
+                .insert_header((header::AUTHORIZATION, API_KEY));
+            let response = test::call_service(&app, request.to_request()).await;
+            let body: serde_json::Value = test::read_body_json(response).await;
+            assert_eq!(body["total"], 2, "{}", body);
+            let listed: Vec<_> = body["images"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|image| image["url"].as_str().unwrap())
+                .collect();
+            assert!(listed.contains(&plain.as_str()) && listed.contains(&protected.as_str()));
+
+            let page = gallery_page(&app).await;
+            assert!(page.contains(plain.as_str()), "{}", page);
+            assert!(!page.contains(burned.as_str()), "{}", page);
+            assert!(!page.contains(protected.as_str()), "{}", page);
+        }
+    }
+
+    #[actix_web::test]
+    async fn upload_reports_the_full_storage_key() {
+        let (_dir, state) = test_state_with(
+            "storage_layout = \"date\"\nurl_template = \"{base}/view?image={filename}\"\n",
+        );
+        let app = test_app(state).await;
+
+        let request = upload_request(Some(API_KEY), &[("image", &png())]);
+        let response = test::call_service(&app, request.to_request()).await;
+        let body: serde_json::Value = test::read_body_json(response).await;
+        let filename = body["filename"].as_str().unwrap();
+        assert_eq!(filename.matches('/').count(), 3, "{}", filename);
+        assert_eq!(
+            body["url"],
+            format!("http://img.test/view?image={}", filename)
+        );
+
+        let request = test::TestRequest::post()
+            .uri("/sign")
+            .insert_header((header::AUTHORIZATION, API_KEY))
+            .set_json(serde_json::json!({ "filename": filename }));
+        let response = test::call_service(&app, request.to_request()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[actix_web::test]
+    async fn upload_without_image_field_is_refused() {
+        let (_dir, state) = test_state();
+        let app = test_app(state).await;
+
+        let request = upload_request(Some(API_KEY), &[("extension", b"png")]);
+        let response = test::call_service(&app, request.to_request()).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(error_code(response).await, "missing_image");
+    }
+
+    #[actix_web::test]
+    async fn upload_with_bad_base64_is_refused() {
+        let (_dir, state) = test_state();
+        let app = test_app(state).await;
+
+        let request = upload_request(Some(API_KEY), &[("image", b"not base64 (at all)!")]);
+        let response = test::call_service(&app, request.to_request()).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(error_code(response).await, "invalid_base64");
+    }
+
+    #[actix_web::test]
+    async fn upload_of_base64_image_from_older_clients_is_accepted() {
+        let (_dir, state) = test_state();
+        let app = test_app(state).await;
+        let encoded = general_purpose::STANDARD.encode(png());
+
+        let request = upload_request(Some(API_KEY), &[("image", encoded.as_bytes())]);
+        let response = test::call_service(&app, request.to_request()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[actix_web::test]
+    async fn signed_link_serves_image_until_tampered_with() {
+        let (_dir, state) = test_state();
+        let app = test_app(state).await;
+
+        let request = upload_request(Some(API_KEY), &[("image", &png())]);
+        let response = test::call_service(&app, request.to_request()).await;
+        let body: serde_json::Value = test::read_body_json(response).await;
+        let filename = body["url"].as_str().unwrap().rsplit('/').next().unwrap();
+
+        let request = test::TestRequest::post()
+            .uri("/sign")
+            .insert_header((header::AUTHORIZATION, API_KEY))
+            .set_json(serde_json::json!({ "filename": filename, "expires_in": 60 }));
+        let response = test::call_service(&app, request.to_request()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body: serde_json::Value = test::read_body_json(response).await;
+        let url = body["url"].as_str().unwrap();
+        let path = url.strip_prefix("http://img.test").unwrap();
+
+        let response =
+            test::call_service(&app, test::TestRequest::get().uri(path).to_request()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let tampered = path.replace("exp=", "exp=1");
+        let response =
+            test::call_service(&app, test::TestRequest::get().uri(&tampered).to_request()).await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        assert_eq!(error_code(response).await, "invalid_signature");
+
+        // Correctly signed, but for a time that has passed
+        let past = unix_now() - 60;
+        let expired = format!(
+            "/i/{}?exp={}&sig={}",
+            filename,
+            past,
+            url_signature("test-secret", filename, past)
+        );
+        let response =
+            test::call_service(&app, test::TestRequest::get().uri(&expired).to_request()).await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        assert_eq!(error_code(response).await, "link_expired");
+    }
+
+    #[actix_web::test]
+    async fn expired_image_is_not_signed() {
+        let (_dir, state) = test_state();
+        let app = test_app(state.clone()).await;
+
+        let request = upload_request(Some(API_KEY), &[("image", &png())]);
+        let response = test::call_service(&app, request.to_request()).await;
+        let body: serde_json::Value = test::read_body_json(response).await;
+        let filename = body["url"].as_str().unwrap().rsplit('/').next().unwrap();
+        expire(&state, filename).await;
+
+        let request = test::TestRequest::post()
+            .uri("/sign")
+            .insert_header((header::AUTHORIZATION, API_KEY))
+            .set_json(serde_json::json!({ "filename": filename }));
+        let response = test::call_service(&app, request.to_request()).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[actix_web::test]
+    async fn missing_image_is_not_found() {
+        let (_dir, state) = test_state();
+        let app = test_app(state).await;
+
+        let request = test::TestRequest::get().uri("/i/missing.png").to_request();
+        let response = test::call_service(&app, request).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        assert_eq!(error_code(response).await, "not_found");
+    }
+
+    #[actix_web::test]
+    async fn path_traversal_is_rejected() {
+        let (dir, state) = test_state();
+        // Sits next to the storage directory, where a traversal would find it
+        fs::write(dir.path().join("secret.png"), png()).unwrap();
+        let app = test_app(state).await;
+
+        for path in [
+            "/i/..%2Fsecret.png",
+            "/i/../secret.png",
+            "/i/2024/../../secret.png",
+            "/i/%2e%2e/secret.png",
+            "/i/.meta/secret.png.json",
+        ] {
+            let request = test::TestRequest::get().uri(path).to_request();
+            let response = test::call_service(&app, request).await;
+            assert!(
+                matches!(
+                    response.status(),
+                    StatusCode::BAD_REQUEST | StatusCode::NOT_FOUND
+                ),
+                "{} answered {}",
+                path,
+                response.status()
+            );
+        }
+    }
+
+    #[cfg(unix)]
+    #[actix_web::test]
+    async fn symlink_out_of_storage_is_not_served() {
+        let (dir, state) = test_state();
+        let secret = dir.path().join("secret.png");
+        fs::write(&secret, png()).unwrap();
+        std::os::unix::fs::symlink(&secret, dir.path().join("images").join("link.png")).unwrap();
+        let app = test_app(state).await;
+
+        let request = test::TestRequest::get().uri("/i/link.png").to_request();
+        let response = test::call_service(&app, request).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[actix_web::test]
+    async fn jpegs_are_only_recompressed_when_asked_for() {
+        let (_dir, state) = test_state();
+        let app = test_app(state).await;
+        let request = upload_request(Some(API_KEY), &[("image", &jpeg())]);
+        let response = test::call_service(&app, request.to_request()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let recompress = |query: &str| {
+            test::TestRequest::post()
+                .uri(&format!("/admin/recompress?quality=50{}", query))
+                .insert_header((header::AUTHORIZATION, API_KEY))
+                .to_request()
+        };
+        let body: serde_json::Value = test::call_and_read_body_json(&app, recompress("")).await;
+        assert_eq!(body["recompressed"], serde_json::json!([]));
+
+        let body: serde_json::Value =
+            test::call_and_read_body_json(&app, recompress("&format=jpeg")).await;
+        assert_eq!(body["recompressed"].as_array().unwrap().len(), 1);
+    }
+
+    /// Start a resumable upload of `size` bytes of PNG
+    async fn start_upload(
+        app: &impl Service<actix_http::Request, Response = ServiceResponse, Error = actix_web::Error>,
+        size: usize,
+    ) -> ServiceResponse {
+        let request = test::TestRequest::post()
+            .uri("/upload/init")
+            .insert_header((header::AUTHORIZATION, API_KEY))
+            .set_json(serde_json::json!({ "size": size, "extension": "png" }))
+            .to_request();
+        test::call_service(app, request).await
+    }
+
+    /// A chunk of the resumable upload `id` starting at `offset`
+    fn chunk_request(id: &str, offset: usize, chunk: &[u8]) -> test::TestRequest {
+        test::TestRequest::patch()
+            .uri(&format!("/upload/{}", id))
+            .insert_header((header::AUTHORIZATION, API_KEY))
+            .insert_header((UPLOAD_OFFSET, offset.to_string()))
+            .set_payload(chunk.to_vec())
+    }
+
+    /// The offset a resumable upload reports it has reached
+    async fn upload_offset(
+        app: &impl Service<actix_http::Request, Response = ServiceResponse, Error = actix_web::Error>,
+        id: &str,
+    ) -> u64 {
+        let request = test::TestRequest::get()
+            .uri(&format!("/upload/{}", id))
+            .insert_header((header::AUTHORIZATION, API_KEY))
+            .to_request();
+        let body: serde_json::Value = test::call_and_read_body_json(app, request).await;
+        body["offset"].as_u64().unwrap()
+    }
+
+    #[actix_web::test]
+    async fn resumable_upload_is_stored_once_complete() {
+        let (_dir, state) = test_state();
+        let app = test_app(state.clone()).await;
+        let image = png();
+
+        let response = start_upload(&app, image.len()).await;
+        assert_eq!(response.status(), StatusCode::CREATED);
+        let body: serde_json::Value = test::read_body_json(response).await;
+        let id = body["id"].as_str().unwrap().to_owned();
+
+        let request = chunk_request(&id, 0, &image[..10]).to_request();
+        let response = test::call_service(&app, request).await;
+        assert_eq!(response.status(), StatusCode::ACCEPTED);
+        assert_eq!(upload_offset(&app, &id).await, 10);
+
+        // A chunk must start where the upload has got to
+        let request = chunk_request(&id, 5, &image[5..]).to_request();
+        let response = test::call_service(&app, request).await;
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+        assert_eq!(error_code(response).await, "offset_mismatch");
+
+        // Nor may it run past the declared size
+        let mut too_long = image[10..].to_vec();
+        too_long.push(0);
+        let request = chunk_request(&id, 10, &too_long).to_request();
+        let response = test::call_service(&app, request).await;
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        assert_eq!(error_code(response).await, "chunk_too_large");
+        assert_eq!(upload_offset(&app, &id).await, 10);
+
+        // Only one chunk is received at a time
+        state
+            .with_resumable_upload(&id, "default", |upload| upload.busy = true)
+            .unwrap();
+        let request = chunk_request(&id, 10, &image[10..]).to_request();
+        let response = test::call_service(&app, request).await;
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+        assert_eq!(error_code(response).await, "upload_busy");
+        state
+            .with_resumable_upload(&id, "default", |upload| upload.busy = false)
+            .unwrap();
+
+        let request = chunk_request(&id, 10, &image[10..]).to_request();
+        let response = test::call_service(&app, request).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body: serde_json::Value = test::read_body_json(response).await;
+        let filename = body["filename"].as_str().unwrap();
+        let stored = state.storage.get(filename).await.unwrap();
+        assert_eq!(stored, Some(image));
+        assert!(!state.partial_upload_path(&id).exists());
+    }
+
+    #[actix_web::test]
+    async fn resumable_upload_resumes_after_a_cut_short_chunk() {
+        let (_dir, state) = test_state();
+        let app = test_app(state).await;
+        let image = png();
+
+        let response = start_upload(&app, image.len()).await;
+        let body: serde_json::Value = test::read_body_json(response).await;
+        let id = body["id"].as_str().unwrap().to_owned();
+
+        // The connection drops after the first 20 bytes of the chunk
+        let received = bytes::Bytes::copy_from_slice(&image[..20]);
+        let cut_short: actix_http::BoxedPayloadStream = Box::pin(futures::stream::iter([
+            Ok(received),
+            Err(actix_http::error::PayloadError::Incomplete(None)),
+        ]));
+        let (request, _) = chunk_request(&id, 0, &[])
+            .to_request()
+            .replace_payload(cut_short.into());
+        let response = test::call_service(&app, request).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(error_code(response).await, "invalid_chunk");
+
+        let offset = upload_offset(&app, &id).await as usize;
+        assert_eq!(offset, 20);
+        let request = chunk_request(&id, offset, &image[offset..]).to_request();
+        let response = test::call_service(&app, request).await;
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[actix_web::test]
+    async fn resumable_uploads_hold_their_storage_until_abandoned() {
+        let (_dir, state) = test_state_with("max_total_bytes = 1000");
+        let app = test_app(state.clone()).await;
+
+        let response = start_upload(&app, 1001).await;
+        assert_eq!(response.status(), StatusCode::INSUFFICIENT_STORAGE);
+
+        let response = start_upload(&app, 600).await;
+        assert_eq!(response.status(), StatusCode::CREATED);
+        let response = start_upload(&app, 600).await;
+        assert_eq!(response.status(), StatusCode::INSUFFICIENT_STORAGE);
+        assert_eq!(error_code(response).await, "quota_exceeded");
+
+        // A recount keeps the reservation
+        *state.stored_bytes.lock().await = None;
+        let response = start_upload(&app, 600).await;
+        assert_eq!(response.status(), StatusCode::INSUFFICIENT_STORAGE);
+
+        assert_eq!(state.remove_abandoned_uploads(Duration::ZERO).await, 1);
+        let response = start_upload(&app, 600).await;
+        assert_eq!(response.status(), StatusCode::CREATED);
+    }
+
+    #[actix_web::test]
+    async fn resumable_uploads_are_limited_per_key() {
+        let (_dir, state) = test_state();
+        let app = test_app(state).await;
+
+        for _ in 0..MAX_RESUMABLE_UPLOADS_PER_KEY {
+            let response = start_upload(&app, 100).await;
+            assert_eq!(response.status(), StatusCode::CREATED);
+        }
+        let response = start_upload(&app, 100).await;
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(error_code(response).await, "too_many_uploads");
+    }
+
+    /// Plain unit tests, where `#[test]` isn't shadowed by the `actix_web::test` import
+    mod sync {
+        use super::super::sanitize_filename;
+
+        #[test]
+        fn sanitize_filename_rejects_unsafe_names() {
+            for name in ["abc.png", "2024/01/15/abc.png", "ab/cd/abc.png"] {
+                assert_eq!(sanitize_filename(name), Some(name));
+            }
+            for name in [
+                "..",
+                "../abc.png",
+                "2024/../abc.png",
+                "abc..png",
+                ".meta",
+                ".meta/abc.png.json",
+                "2024/.hidden.png",
+                "..\\abc.png",
+                "2024\\abc.png",
+                "abc\0.png",
+                "",
+                "/abc.png",
+                "2024//abc.png",
+                "2024/",
+            ] {
+                assert_eq!(sanitize_filename(name), None, "{:?}", name);
+            }
+        }
+    }
 }
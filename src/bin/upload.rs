@@ -1,103 +1,1844 @@
 //! A command-line tool for uploading images to a server and copying the resulting URL to the clipboard.
 //!
-//! This tool reads an image file, converts it to base64, sends it to a configured server,
-//! and copies the returned URL to the clipboard. It uses `pretty_env_logger` for logging.
+//! This tool reads an image file, sends it to a configured server,
+//! and copies the returned URL to the clipboard. It logs through `kimage::logging`.
+//!
+//! Still images are decoded and re-encoded before upload, which strips their metadata:
+//! the `image` encoders only write pixel data, so EXIF (including GPS position and
+//! device details), XMP, ICC profiles and PNG text chunks are dropped for every
+//! output format. Pass `--keep-metadata` to upload the original file untouched
+//! when it is already in the requested format.
+//!
+//! Animated images are the exception: unless `--format webp` turns a GIF or APNG into
+//! an animated WebP, the original file is uploaded, metadata and all, with a warning.
 use anyhow::{anyhow, Context, Result};
-use base64::{engine::general_purpose, Engine as _};
-use clap::Parser;
-use dirs::home_dir;
-use image::ImageOutputFormat;
-use log::{error, info};
-use serde::Deserialize;
+use arboard::Clipboard;
+use clap::{ArgAction, Parser, Subcommand, ValueEnum};
+use futures::stream::{self, StreamExt};
+use image::codecs::gif::GifDecoder;
+use image::codecs::png::PngDecoder;
+use image::codecs::webp::WebPDecoder;
+use image::imageops::FilterType;
+use image::{
+    AnimationDecoder, DynamicImage, GenericImageView, GrayImage, ImageFormat, ImageOutputFormat,
+    Luma, Rgba, RgbaImage,
+};
+use indicatif::{MultiProgress, ProgressBar, ProgressDrawTarget, ProgressStyle};
+use kimage::client::{
+    self, apply_orientation, encode_image, exif_orientation, ChunkResponse, Config, OutputFormat,
+    PngCompression, UploadError, UploadOptions, UploadResponse,
+};
+use kimage::logging::{self, LogFormat};
+use kimage::ConfigError;
+use log::{error, info, warn};
+use notify::event::ModifyKind;
+use notify::{EventKind, RecursiveMode, Watcher};
+use rusttype::{Font, Scale};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
 use std::fs;
-use std::io::Cursor;
-use std::path::PathBuf;
+use std::io::{self, Cursor, IsTerminal, Read};
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::{Duration, Instant, SystemTime};
+use webp::{AnimEncoder, AnimFrame, WebPConfig};
 
 /// Command-line arguments for the image uploader
 #[derive(Parser, Debug)]
-#[command(author, version, about, long_about = None)]
+#[command(author, version, about, long_about = None, args_conflicts_with_subcommands = true)]
 struct Args {
-    /// Path to the image file to upload
-    #[arg(help = "Path to the image file to upload")]
-    image_path: PathBuf,
+    /// Something to do instead of uploading
+    #[command(subcommand)]
+    command: Option<Command>,
+    /// Paths of the image files to upload, `-` for stdin, or omit to read the clipboard
+    #[arg(
+        help = "Paths of the image files to upload, `-` for stdin, or omit to read the clipboard"
+    )]
+    image_paths: Vec<PathBuf>,
+    /// Upload at most this many images at once
+    #[arg(long, default_value_t = 4, value_parser = clap::value_parser!(u16).range(1..))]
+    concurrency: u16,
+    /// Format to re-encode the image as before uploading
+    #[arg(long, value_enum, default_value_t = OutputFormat::Png)]
+    format: OutputFormat,
+    /// Encoding quality (1-100), only used with `--format jpeg`
+    #[arg(long, default_value_t = 90, value_parser = clap::value_parser!(u8).range(1..=100))]
+    quality: u8,
+    /// How hard to compress re-encoded PNGs, trading encoding speed for size
+    #[arg(long, value_enum, default_value_t = PngCompression::Default)]
+    png_compression: PngCompression,
+    /// Upload the file exactly as it is, without decoding or re-encoding it
+    #[arg(long, conflicts_with_all = [
+        "format", "quality", "png_compression", "keep_metadata", "optimize", "blur", "crop",
+        "max_width", "max_height", "scale", "watermark_text", "watermark_image",
+    ])]
+    no_convert: bool,
+    /// Losslessly recompress re-encoded PNGs with oxipng, which is slower but smaller
+    #[arg(long)]
+    optimize: bool,
+    /// Upload the original file, metadata included, if it is already in the requested format
+    #[arg(long)]
+    keep_metadata: bool,
+    /// Hide this rectangle of the image, given as `x,y,width,height` in pixels; may be repeated
+    #[arg(long, value_name = "X,Y,W,H", value_parser = parse_rect)]
+    blur: Vec<Rect>,
+    /// How `--blur` hides the regions it is given
+    #[arg(long, value_enum, default_value_t = RedactStyle::Blur)]
+    redact_style: RedactStyle,
+    /// Upload only this rectangle of the image, given as `x,y,width,height` in pixels
+    #[arg(long, value_name = "X,Y,W,H", value_parser = parse_rect)]
+    crop: Option<Rect>,
+    /// Shrink the image to at most this many pixels wide, keeping its aspect ratio
+    #[arg(long, value_name = "PX", value_parser = clap::value_parser!(u32).range(1..))]
+    max_width: Option<u32>,
+    /// Shrink the image to at most this many pixels tall, keeping its aspect ratio
+    #[arg(long, value_name = "PX", value_parser = clap::value_parser!(u32).range(1..))]
+    max_height: Option<u32>,
+    /// Shrink the image to this percentage of its size
+    #[arg(long, value_name = "PERCENT", value_parser = clap::value_parser!(u8).range(1..=100))]
+    scale: Option<u8>,
+    /// Stamp this text onto the image
+    #[arg(long, value_name = "TEXT", conflicts_with = "watermark_image")]
+    watermark_text: Option<String>,
+    /// Stamp this image, such as a logo, onto the image
+    #[arg(long, value_name = "PATH")]
+    watermark_image: Option<PathBuf>,
+    /// TrueType font to draw `--watermark-text` in, instead of a common system font
+    #[arg(long, value_name = "PATH")]
+    watermark_font: Option<PathBuf>,
+    /// Where on the image to put the watermark
+    #[arg(long, value_enum, default_value_t = WatermarkPosition::BottomRight)]
+    watermark_position: WatermarkPosition,
+    /// Opacity of the watermark, in percent
+    #[arg(long, value_name = "PERCENT", default_value_t = 50, value_parser = clap::value_parser!(u8).range(1..=100))]
+    watermark_opacity: u8,
+    /// Height of the watermark text in pixels, or of the watermark image if it should be scaled
+    #[arg(long, value_name = "PX", value_parser = clap::value_parser!(u32).range(1..))]
+    watermark_size: Option<u32>,
+    /// Store the image under this name (letters, digits, `-` and `_`) instead of a random one
+    #[arg(long, value_name = "NAME")]
+    name: Option<String>,
+    /// Only print the uploaded URLs, without copying anything to the clipboard
+    #[arg(long)]
+    no_clipboard: bool,
+    /// What to copy to the clipboard for each uploaded image
+    #[arg(long, value_enum, default_value_t = LinkFormat::Url)]
+    link_format: LinkFormat,
+    /// Alt text for markdown and HTML links, instead of the file name without its extension
+    #[arg(long, value_name = "TEXT")]
+    alt: Option<String>,
+    /// Print a JSON object per upload to stdout, and errors as JSON to stderr, instead of logging
+    #[arg(long)]
+    json: bool,
+    /// Open each uploaded image in the default browser
+    #[arg(long)]
+    open: bool,
+    /// Require this password, as `?token=...` or an `X-Image-Token` header, to view the image
+    #[arg(long, value_name = "PASSWORD")]
+    password: Option<String>,
+    /// Ask the server to delete the image once it has been viewed
+    #[arg(long)]
+    burn: bool,
+    /// Ask the server to delete the image after this many seconds
+    #[arg(long, value_name = "SECONDS")]
+    expire_after: Option<u64>,
+    /// Config file to use instead of `$KIMAGE_CONFIG` or `~/.config/kimage.toml`
+    #[arg(long, value_name = "PATH")]
+    config: Option<PathBuf>,
+    /// Upload to this server instead of the config's `server_url`, e.g. a staging server
+    #[arg(long, value_name = "URL", value_parser = parse_server_url)]
+    server: Option<String>,
+    /// Authenticate with this API key instead of the one in the config
+    #[arg(long, value_name = "KEY")]
+    api_key: Option<String>,
+    /// Don't show a progress bar, and log only warnings; pass twice to log only errors
+    #[arg(short, long, action = ArgAction::Count)]
+    quiet: u8,
+    /// Log more detail; pass twice to log everything
+    #[arg(short, long, action = ArgAction::Count)]
+    verbose: u8,
+    /// Prepare the image and print what would be uploaded where, without sending it
+    #[arg(long)]
+    dry_run: bool,
+    /// Upload the image even if it was uploaded before, instead of reusing the earlier URL;
+    /// with `--init`, overwrite an existing config file
+    #[arg(long)]
+    force: bool,
+    /// Write a commented template config, with a new API key, and exit
+    #[arg(long)]
+    init: bool,
+    /// Have the server fetch the image at this URL and store it, instead of uploading a file
+    #[arg(long, value_name = "URL", conflicts_with_all = [
+        "image_paths", "watch", "dry_run", "no_convert", "format", "quality", "png_compression",
+        "keep_metadata", "optimize", "blur", "crop", "max_width", "max_height", "scale", "watermark_text",
+        "watermark_image",
+    ])]
+    from_url: Option<String>,
+    /// Keep watching this directory, such as a screenshots folder, and upload each new image
+    #[arg(long, value_name = "DIR", conflicts_with_all = ["image_paths", "name"])]
+    watch: Option<PathBuf>,
+    /// Retry an upload this many times after a connection error or server error
+    #[arg(long, default_value_t = 2)]
+    retries: u32,
+    /// Milliseconds to wait before the first retry, doubling after each one
+    #[arg(long, value_name = "MS", default_value_t = 500)]
+    retry_delay: u64,
+    /// Seconds to wait for each upload request to complete, or 0 to wait forever
+    #[arg(long, value_name = "SECS", default_value_t = 30)]
+    timeout: u64,
+    /// Send images larger than this many bytes in chunks of this size, which can be resumed
+    #[arg(long, value_name = "BYTES", default_value_t = 8 * 1024 * 1024, value_parser = clap::value_parser!(u64).range(1..))]
+    chunk_size: u64,
 }
 
-/// Configuration for the image uploader
-#[derive(Deserialize)]
-struct Config {
-    /// URL of the server to upload images to
-    server_url: String,
-    /// API key for authentication with the server
-    api_key: String,
+/// Subcommands, which take the place of image paths; upload a file named like one as
+/// `./clean`
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Remove what kimage keeps in the cache directory, such as remembered uploads
+    Clean {
+        /// Only list what would be removed
+        #[arg(long)]
+        dry_run: bool,
+    },
 }
 
-#[tokio::main]
-async fn main() -> Result<()> {
-    std::env::set_var("RUST_LOG", "info");
-    // Initialize the logger
-    pretty_env_logger::init();
+impl Args {
+    /// Whether uploads may be answered from, and recorded in, the local cache of uploads
+    ///
+    /// Named, protected and expiring uploads are always sent, as reusing an
+    /// earlier copy wouldn't give them the requested settings.
+    fn uses_upload_cache(&self) -> bool {
+        !self.dry_run
+            && self.name.is_none()
+            && self.password.is_none()
+            && !self.burn
+            && self.expire_after.is_none()
+    }
+
+    /// Whether any edit to the pixels was requested, which needs the image re-encoded
+    fn edits_image(&self) -> bool {
+        !self.blur.is_empty()
+            || self.crop.is_some()
+            || self.max_width.is_some()
+            || self.max_height.is_some()
+            || self.scale.is_some()
+            || self.watermark_text.is_some()
+            || self.watermark_image.is_some()
+    }
+}
+
+/// A rectangle of an image, from `--crop` or `--blur`
+#[derive(Clone, Copy, Debug)]
+struct Rect {
+    x: u32,
+    y: u32,
+    width: u32,
+    height: u32,
+}
+
+impl Rect {
+    /// Fail unless the rectangle lies within a `width` x `height` image
+    fn check_fits(self, width: u32, height: u32, what: &str) -> Result<()> {
+        let fits = u64::from(self.x) + u64::from(self.width) <= u64::from(width)
+            && u64::from(self.y) + u64::from(self.height) <= u64::from(height);
+        if !fits {
+            return Err(anyhow!(
+                "{} {}x{} at {},{} doesn't fit in the {}x{} image",
+                what,
+                self.width,
+                self.height,
+                self.x,
+                self.y,
+                width,
+                height
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Check a `--server` URL, dropping any trailing slash so paths can be appended to it
+fn parse_server_url(value: &str) -> Result<String, String> {
+    let url = reqwest::Url::parse(value).map_err(|e| format!("invalid URL: {}", e))?;
+    if !matches!(url.scheme(), "http" | "https") {
+        return Err("expected an http:// or https:// URL".to_string());
+    }
+    Ok(value.trim_end_matches('/').to_owned())
+}
+
+/// Parse a rectangle written as `x,y,width,height`
+fn parse_rect(value: &str) -> Result<Rect, String> {
+    let parts = value
+        .split(',')
+        .map(|part| part.trim().parse::<u32>())
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|_| "expected four whole numbers, e.g. 10,20,300,200".to_string())?;
+    let [x, y, width, height] = parts[..] else {
+        return Err("expected x,y,width,height".to_string());
+    };
+    if width == 0 || height == 0 {
+        return Err("width and height must be at least 1".to_string());
+    }
+    Ok(Rect {
+        x,
+        y,
+        width,
+        height,
+    })
+}
+
+/// Ways of hiding the regions given to `--blur`
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum RedactStyle {
+    /// Blur the region beyond recognition
+    Blur,
+    /// Paint over the region in solid black, which leaves nothing to recover
+    Fill,
+}
+
+/// Places on the image a watermark can be put
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum WatermarkPosition {
+    TopLeft,
+    TopRight,
+    BottomLeft,
+    BottomRight,
+    Center,
+}
+
+impl WatermarkPosition {
+    /// Top-left corner at which to draw a `mark`-sized watermark on a `canvas`-sized image
+    fn origin(self, canvas: (u32, u32), mark: (u32, u32)) -> (i64, i64) {
+        let (canvas_width, canvas_height) = (i64::from(canvas.0), i64::from(canvas.1));
+        let (mark_width, mark_height) = (i64::from(mark.0), i64::from(mark.1));
+        let margin = i64::from(WATERMARK_MARGIN);
+        let left = margin;
+        let right = canvas_width - mark_width - margin;
+        let top = margin;
+        let bottom = canvas_height - mark_height - margin;
+        match self {
+            WatermarkPosition::TopLeft => (left, top),
+            WatermarkPosition::TopRight => (right, top),
+            WatermarkPosition::BottomLeft => (left, bottom),
+            WatermarkPosition::BottomRight => (right, bottom),
+            WatermarkPosition::Center => (
+                (canvas_width - mark_width) / 2,
+                (canvas_height - mark_height) / 2,
+            ),
+        }
+    }
+}
+
+/// Shortest frame delay browsers honour; shorter ones play at [`DEFAULT_FRAME_DELAY_MS`]
+const MIN_FRAME_DELAY_MS: u32 = 20;
+
+/// Delay browsers give animation frames with too short a delay, in milliseconds
+const DEFAULT_FRAME_DELAY_MS: u32 = 100;
+
+/// Least blur applied to a `--blur` region, so that small text can't be made out
+const MIN_BLUR_SIGMA: f32 = 8.0;
+
+/// Distance in pixels between a watermark and the edges of the image
+const WATERMARK_MARGIN: u32 = 16;
 
+/// Text height used for `--watermark-text` when `--watermark-size` isn't given
+const DEFAULT_WATERMARK_TEXT_SIZE: u32 = 24;
+
+/// Fonts tried for `--watermark-text` when `--watermark-font` isn't given
+const WATERMARK_FONTS: &[&str] = &[
+    "/usr/share/fonts/truetype/dejavu/DejaVuSans.ttf",
+    "/usr/share/fonts/TTF/DejaVuSans.ttf",
+    "/usr/share/fonts/dejavu/DejaVuSans.ttf",
+    "/usr/share/fonts/truetype/liberation/LiberationSans-Regular.ttf",
+    "/usr/share/fonts/liberation/LiberationSans-Regular.ttf",
+    "/System/Library/Fonts/Supplemental/Arial.ttf",
+    "/Library/Fonts/Arial.ttf",
+    "C:\\Windows\\Fonts\\arial.ttf",
+];
+
+/// Ways of presenting an uploaded image's URL on the clipboard
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum LinkFormat {
+    /// The bare URL
+    Url,
+    /// A markdown image, `![alt](url)`
+    Markdown,
+    /// An HTML image tag, `<img src="url" alt="alt">`
+    Html,
+}
+
+impl LinkFormat {
+    /// Render the link for an image at `url`
+    fn render(self, url: &str, alt: &str) -> String {
+        match self {
+            LinkFormat::Url => url.to_string(),
+            LinkFormat::Markdown => format!(
+                "![{}]({})",
+                alt.replace('[', "\\[").replace(']', "\\]"),
+                url.replace(' ', "%20")
+            ),
+            LinkFormat::Html => format!(
+                "<img src=\"{}\" alt=\"{}\">",
+                escape_html(url),
+                escape_html(alt)
+            ),
+        }
+    }
+}
+
+/// Escape text for use inside a double-quoted HTML attribute
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('"', "&quot;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+/// Describe an upload for `--json` output
+fn json_output(upload: &UploadResponse) -> JsonOutput<'_> {
+    JsonOutput {
+        url: &upload.url,
+        filename: upload.filename(),
+        bytes: upload.bytes,
+        width: upload.width,
+        height: upload.height,
+        expires_at: upload.expires_at.as_deref(),
+        existing: upload.existing,
+    }
+}
+
+/// What `--json` prints for each uploaded image
+#[derive(Serialize)]
+struct JsonOutput<'a> {
+    /// URL of the uploaded image
+    url: &'a str,
+    /// Name the server stored the image under
+    filename: &'a str,
+    /// Size of the stored file in bytes
+    bytes: Option<u64>,
+    /// Width of the image in pixels
+    width: Option<u32>,
+    /// Height of the image in pixels
+    height: Option<u32>,
+    /// When the image expires, as an RFC 3339 timestamp; left out for images that don't
+    #[serde(skip_serializing_if = "Option::is_none")]
+    expires_at: Option<&'a str>,
+    /// Whether the server already had an identical image stored
+    existing: bool,
+}
+
+#[tokio::main]
+async fn main() -> ExitCode {
     // Parse command-line arguments
     let args = Args::parse();
+    let json = args.json;
+
+    // Initialize the logger, silenced by default when output is meant for scripts
+    let log_format = match LogFormat::from_env() {
+        Ok(format) => format.unwrap_or_default(),
+        Err(e) => {
+            eprintln!("Error: {:?}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+    logging::init(
+        log_format,
+        logging::verbosity_level(args.verbose, args.quiet),
+        if json { "off" } else { "info" },
+    );
+
+    match run(Arc::new(args)).await {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            if json {
+                eprintln!("{}", serde_json::json!({ "error": format!("{:#}", e) }));
+            } else {
+                eprintln!("Error: {:?}", e);
+                let hint = e.downcast_ref::<ConfigError>().and_then(ConfigError::hint);
+                if let Some(hint) = hint {
+                    eprintln!("Hint: {}", hint);
+                }
+            }
+            ExitCode::FAILURE
+        }
+    }
+}
+
+/// Upload the images named by `args`
+async fn run(args: Arc<Args>) -> Result<()> {
+    if args.init {
+        let config_path = kimage::config_path(args.config.as_deref())?;
+        kimage::init_config(&config_path, args.force)?;
+        println!("Wrote a template config to {}", config_path.display());
+        return Ok(());
+    }
+
+    if let Some(Command::Clean { dry_run }) = args.command {
+        return clean_cache(dry_run);
+    }
+
     // Load configuration
-    let config = load_config()?;
+    let config = Arc::new(load_config(&args)?);
 
-    // Read the image file
-    info!("Loading image from path: {:?}", args.image_path);
-    let image_data = fs::read(&args.image_path).context("Failed to read image file")?;
+    if args.name.is_some() && args.image_paths.len() > 1 {
+        return Err(anyhow!(
+            "--name can only be used when uploading a single image"
+        ));
+    }
+    if args
+        .image_paths
+        .iter()
+        .filter(|path| is_stdin(path))
+        .count()
+        > 1
+    {
+        return Err(anyhow!("stdin (`-`) can only be read once"));
+    }
 
-    // Load the image into memory
-    let img = image::load_from_memory(&image_data).context("Failed to load image")?;
+    // No paths means a single image from the clipboard
+    let sources: Vec<Option<PathBuf>> = if args.image_paths.is_empty() {
+        vec![None]
+    } else {
+        args.image_paths.iter().cloned().map(Some).collect()
+    };
 
-    // Convert the image to PNG format
-    let mut buffer = Cursor::new(Vec::new());
-    img.write_to(&mut buffer, ImageOutputFormat::Png)
-        .context("Failed to encode image as PNG")?;
-
-    // Convert the PNG data to base64
-    let base64_image = general_purpose::STANDARD.encode(buffer.into_inner());
-
-    // Send the image to the server
-    info!("Sending image to server");
-    let client = reqwest::Client::new();
-    let response = client
-        .post(&format!("{}/upload", config.server_url))
-        .header("Authorization", &config.api_key)
-        .multipart(reqwest::multipart::Form::new().text("image", base64_image))
-        .send()
+    // Only draw progress bars when someone is watching
+    let progress = if args.quiet > 0 || args.dry_run || args.json || !io::stdout().is_terminal() {
+        MultiProgress::with_draw_target(ProgressDrawTarget::hidden())
+    } else {
+        MultiProgress::new()
+    };
+
+    // Upload in order, with at most `--concurrency` images in flight
+    let mut client = reqwest::Client::builder();
+    if args.timeout > 0 {
+        client = client.timeout(Duration::from_secs(args.timeout));
+    }
+    let client = client.build().context("Failed to create HTTP client")?;
+    let cache = args
+        .uses_upload_cache()
+        .then(UploadCache::load)
+        .flatten()
+        .map(Mutex::new)
+        .map(Arc::new);
+    if let Some(dir) = &args.watch {
+        return watch_directory(&args, &config, &client, &progress, cache, dir).await;
+    }
+    let results: Vec<(String, String, Result<Option<UploadResponse>>)> = if let Some(url) =
+        &args.from_url
+    {
+        // The server fetches the image, so there is nothing to prepare here
+        let name = url.split(['?', '#']).next().unwrap_or_default();
+        let alt = args
+            .alt
+            .clone()
+            .unwrap_or_else(|| default_alt(name.rsplit('/').next().map(Path::new)));
+        let result = upload_from_url(&args, &config, &client, url).await;
+        vec![(url.clone(), alt, result)]
+    } else {
+        stream::iter(sources)
+            .map(|source| {
+                let (args, config, client, progress, cache) = (
+                    args.clone(),
+                    config.clone(),
+                    client.clone(),
+                    progress.clone(),
+                    cache.clone(),
+                );
+                async move {
+                    let label = source_label(source.as_deref());
+                    let alt = args
+                        .alt
+                        .clone()
+                        .unwrap_or_else(|| default_alt(source.as_deref()));
+                    let result =
+                        process_image(&args, &config, &client, &progress, cache.as_deref(), source)
+                            .await;
+                    (label, alt, result)
+                }
+            })
+            .buffered(usize::from(args.concurrency))
+            .collect()
+            .await
+    };
+
+    // Report failures, but still hand over every URL that was uploaded
+    let mut urls = Vec::new();
+    let mut links = Vec::new();
+    let mut failures = 0;
+    for (label, alt, result) in results {
+        match result {
+            Ok(Some(upload)) => {
+                print_upload(&args, &upload)?;
+                links.push(args.link_format.render(&upload.url, &alt));
+                urls.push(upload.url);
+            }
+            Ok(None) => {}
+            Err(e) => {
+                if args.json {
+                    eprintln!(
+                        "{}",
+                        serde_json::json!({ "source": label, "error": format!("{:#}", e) })
+                    );
+                } else {
+                    error!("Failed to upload {}: {:#}", label, e);
+                }
+                failures += 1;
+            }
+        }
+    }
+    if let Some(cache) = cache {
+        cache.lock().unwrap_or_else(PoisonError::into_inner).save();
+    }
+    if !links.is_empty() && !args.no_clipboard {
+        if display_available() {
+            copy_to_clipboard(&links.join("\n"));
+        } else {
+            info!("No display available, not copying to clipboard");
+        }
+    }
+    if args.open {
+        for url in &urls {
+            open_in_browser(url);
+        }
+    }
+
+    if failures > 0 {
+        return Err(anyhow!(
+            "{} of {} uploads failed",
+            failures,
+            failures + urls.len()
+        ));
+    }
+    Ok(())
+}
+
+/// Print an uploaded image's URL, or its JSON description with `--json`
+fn print_upload(args: &Args, upload: &UploadResponse) -> Result<()> {
+    if args.json {
+        println!(
+            "{}",
+            serde_json::to_string(&json_output(upload)).context("Failed to serialize upload")?
+        );
+    } else {
+        println!("{}", upload.url);
+        // Keep stdout to URLs for scripts
+        if let Some(expires_at) = &upload.expires_at {
+            eprintln!("{} expires at {}", upload.url, expires_at);
+        }
+    }
+    Ok(())
+}
+
+/// How long a watched file must go unchanged before it is taken to be completely written
+const WATCH_SETTLE: Duration = Duration::from_millis(500);
+
+/// Size and modification time of a watched file, which stop changing once it is written
+type FileState = (u64, SystemTime);
+
+/// Upload every image created in `dir` until interrupted, handing over each URL as it arrives
+///
+/// Bursts of events for a file are debounced, and a file is only uploaded once its
+/// size and modification time have held still for [`WATCH_SETTLE`], so partial writes
+/// by screenshot tools aren't picked up.
+async fn watch_directory(
+    args: &Arc<Args>,
+    config: &Config,
+    client: &reqwest::Client,
+    progress: &MultiProgress,
+    cache: Option<Arc<Mutex<UploadCache>>>,
+    dir: &Path,
+) -> Result<()> {
+    if !dir.is_dir() {
+        return Err(anyhow!("{} is not a directory", dir.display()));
+    }
+    let (sender, mut events) = tokio::sync::mpsc::unbounded_channel();
+    let mut watcher = notify::recommended_watcher(move |event| {
+        // Only fails once the loop below has stopped listening
+        let _ = sender.send(event);
+    })
+    .context("Failed to start watching for files")?;
+    watcher
+        .watch(dir, RecursiveMode::NonRecursive)
+        .with_context(|| format!("Failed to watch {}", dir.display()))?;
+    info!(
+        "Watching {} for new images, press Ctrl-C to stop",
+        dir.display()
+    );
+
+    // Keep one clipboard open for the whole session, since on X11 its contents
+    // are only served for as long as it is
+    let mut clipboard = if args.no_clipboard {
+        None
+    } else if !display_available() {
+        info!("No display available, not copying to clipboard");
+        None
+    } else {
+        Clipboard::new()
+            .map_err(|e| warn!("Failed to open clipboard: {}", e))
+            .ok()
+    };
+
+    // Files that changed recently, with when they last changed and how they looked then
+    let mut pending: HashMap<PathBuf, (Instant, Option<FileState>)> = HashMap::new();
+    // How each file looked when it was uploaded, so touching it doesn't upload it again
+    let mut uploaded: HashMap<PathBuf, FileState> = HashMap::new();
+    let mut tick = tokio::time::interval(WATCH_SETTLE / 2);
+    let interrupted = tokio::signal::ctrl_c();
+    tokio::pin!(interrupted);
+    loop {
+        tokio::select! {
+            event = events.recv() => match event {
+                Some(Ok(event)) => {
+                    if is_content_change(&event.kind) {
+                        for path in event.paths.into_iter().filter(|path| is_watched_image(path)) {
+                            pending.insert(path, (Instant::now(), None));
+                        }
+                    }
+                }
+                Some(Err(e)) => warn!("Error watching {}: {}", dir.display(), e),
+                None => return Err(anyhow!("Stopped receiving file events")),
+            },
+            _ = tick.tick() => {}
+            result = &mut interrupted => {
+                result.context("Failed to listen for Ctrl-C")?;
+                break;
+            }
+        }
+
+        // Look again at files that have been quiet for a while, and take the ones
+        // that haven't changed since the last look
+        let mut ready = Vec::new();
+        pending.retain(|path, (changed, last_seen)| {
+            if changed.elapsed() < WATCH_SETTLE {
+                return true;
+            }
+            let Some(state) = file_state(path) else {
+                return false;
+            };
+            if *last_seen != Some(state) || state.0 == 0 {
+                *changed = Instant::now();
+                *last_seen = Some(state);
+                return true;
+            }
+            if uploaded.get(path.as_path()) != Some(&state) {
+                ready.push((path.clone(), state));
+            }
+            false
+        });
+        ready.sort();
+
+        for (path, state) in ready {
+            let label = source_label(Some(&path));
+            let alt = args.alt.clone().unwrap_or_else(|| default_alt(Some(&path)));
+            let result = process_image(
+                args,
+                config,
+                client,
+                progress,
+                cache.as_deref(),
+                Some(path.clone()),
+            )
+            .await;
+            match result {
+                Ok(Some(upload)) => {
+                    print_upload(args, &upload)?;
+                    if let Some(clipboard) = &mut clipboard {
+                        match clipboard.set_text(args.link_format.render(&upload.url, &alt)) {
+                            Ok(()) => info!("Copied to clipboard"),
+                            Err(e) => warn!("Failed to copy to clipboard: {}", e),
+                        }
+                    }
+                    if args.open {
+                        open_in_browser(&upload.url);
+                    }
+                    uploaded.insert(path, state);
+                }
+                Ok(None) => {
+                    uploaded.insert(path, state);
+                }
+                Err(e) => {
+                    if args.json {
+                        eprintln!(
+                            "{}",
+                            serde_json::json!({ "source": label, "error": format!("{:#}", e) })
+                        );
+                    } else {
+                        error!("Failed to upload {}: {:#}", label, e);
+                    }
+                }
+            }
+            if let Some(cache) = &cache {
+                cache.lock().unwrap_or_else(PoisonError::into_inner).save();
+            }
+        }
+    }
+    info!("Stopped watching {}", dir.display());
+    Ok(())
+}
+
+/// Whether a file event may mean new content, as opposed to a read, removal or metadata change
+fn is_content_change(kind: &EventKind) -> bool {
+    match kind {
+        EventKind::Create(_) => true,
+        EventKind::Modify(ModifyKind::Metadata(_)) => false,
+        EventKind::Modify(_) => true,
+        _ => false,
+    }
+}
+
+/// Whether a file in a watched directory should be uploaded
+///
+/// Hidden files and names without an image extension, such as the `.part` and
+/// `.tmp` files some tools write before renaming them into place, are ignored.
+fn is_watched_image(path: &Path) -> bool {
+    let hidden = path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .is_none_or(|name| name.starts_with('.'));
+    !hidden && ImageFormat::from_path(path).is_ok()
+}
+
+/// Size and modification time of a regular file, or `None` if it's gone or not a file
+fn file_state(path: &Path) -> Option<FileState> {
+    let metadata = fs::metadata(path)
+        .ok()
+        .filter(|metadata| metadata.is_file())?;
+    Some((metadata.len(), metadata.modified().ok()?))
+}
+
+/// Open a URL in the default browser, warning rather than failing if none can be started
+fn open_in_browser(url: &str) {
+    if let Err(e) = open::that_detached(url) {
+        warn!("Failed to open {} in a browser: {}", url, e);
+    }
+}
+
+/// Whether a path argument stands for stdin
+fn is_stdin(path: &Path) -> bool {
+    path.as_os_str() == "-"
+}
+
+/// How an image source is referred to in messages
+fn source_label(source: Option<&Path>) -> String {
+    match source {
+        Some(path) if is_stdin(path) => "stdin".to_string(),
+        Some(path) => path.display().to_string(),
+        None => "the clipboard".to_string(),
+    }
+}
+
+/// Alt text for an image's link when `--alt` isn't given: its file name without the extension
+fn default_alt(source: Option<&Path>) -> String {
+    source
+        .filter(|path| !is_stdin(path))
+        .and_then(|path| path.file_stem())
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .unwrap_or_default()
+}
+
+/// Prepare and upload a single image, returning what the server said about it
+///
+/// Returns `None` instead in dry-run mode, once what would be uploaded is printed.
+async fn process_image(
+    args: &Arc<Args>,
+    config: &Config,
+    client: &reqwest::Client,
+    progress: &MultiProgress,
+    cache: Option<&Mutex<UploadCache>>,
+    source: Option<PathBuf>,
+) -> Result<Option<UploadResponse>> {
+    // Decoding and encoding are CPU-bound, so keep them off the async workers
+    let prepare_args = args.clone();
+    let prepare_source = source.clone();
+    let (encoded, extension) = tokio::task::spawn_blocking(move || {
+        prepare_image(&prepare_args, prepare_source.as_deref())
+    })
+    .await
+    .context("Image preparation task failed")??;
+
+    let upload_url = config.upload_url();
+    if args.dry_run {
+        println!(
+            "Would upload {} as a {} byte .{} image to {}",
+            source_label(source.as_deref()),
+            encoded.len(),
+            extension,
+            upload_url
+        );
+        return Ok(None);
+    }
+
+    // Reuse the URL of an identical earlier upload, as long as the server still has it
+    let cache_key = cache.map(|_| UploadCache::key(&config.server_url, &extension, &encoded));
+    if let (Some(cache), Some(key), false) = (cache, &cache_key, args.force) {
+        let cached = cache
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get(key);
+        if let Some(cached) = cached {
+            if still_stored(client, &cached.url).await {
+                info!("Already uploaded, reusing {}", cached.url);
+                return Ok(Some(cached));
+            }
+            info!("Earlier upload is gone from the server, uploading again");
+            cache
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .remove(key);
+        }
+    }
+
+    let bar = progress.add(ProgressBar::new(encoded.len() as u64));
+    bar.set_style(
+        ProgressStyle::with_template("{bar:40} {bytes}/{total_bytes} ({bytes_per_sec}) {msg}")
+            .context("Invalid progress bar template")?,
+    );
+    bar.set_message(source_label(source.as_deref()));
+
+    // Send the image to the server, retrying failures that may be transient
+    let label = source_label(source.as_deref());
+    let options = upload_options(args, source.as_deref());
+    let length = encoded.len() as u64;
+    let mut retries = Retries::new(args, &label);
+    let chunked = if length > args.chunk_size {
+        info!("Sending {} to server in chunks", label);
+        let result = send_in_chunks(
+            args,
+            config,
+            client,
+            &bar,
+            &encoded,
+            &extension,
+            &options,
+            &mut retries,
+        )
         .await
-        .context("Failed to send request")?;
+        .transpose();
+        if result.is_none() {
+            info!("Server doesn't support resumable uploads, sending it in one request");
+        }
+        result
+    } else {
+        None
+    };
+    let result = match chunked {
+        Some(result) => result,
+        None => loop {
+            info!("Sending {} to server", label);
+            bar.reset();
+            let body = progress_body(encoded.clone(), bar.clone());
+            match client::upload_body(client, config, body, length, &extension, &options).await {
+                Ok(upload) => break Ok(upload),
+                Err(e) => {
+                    if let Err(e) = retries.wait(e).await {
+                        break Err(e);
+                    }
+                }
+            }
+        },
+    };
+    bar.finish_and_clear();
+    progress.remove(&bar);
+    let upload_response = result.map_err(|e| upload_error(args, e))?;
 
-    // Check if the upload was successful
-    if !response.status().is_success() {
-        error!("Server returned error: {}", response.status());
-        return Err(anyhow!("Server returned error: {}", response.status()));
+    log_upload(&upload_response);
+    if let (Some(cache), Some(key)) = (cache, cache_key) {
+        cache
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .insert(key, upload_response.clone());
     }
+    Ok(Some(upload_response))
+}
 
-    // Parse the response to get the URL of the uploaded image
-    let upload_response: serde_json::Value =
-        response.json().await.context("Failed to parse response")?;
-    let url = upload_response["url"]
-        .as_str()
-        .ok_or_else(|| anyhow!("Invalid response format"))?
-        .to_string();
+/// Have the server fetch and store the image at `--from-url`
+async fn upload_from_url(
+    args: &Args,
+    config: &Config,
+    client: &reqwest::Client,
+    url: &str,
+) -> Result<Option<UploadResponse>> {
+    let options = upload_options(args, None);
+    let mut retries = Retries::new(args, url);
+    let upload = loop {
+        info!("Asking the server to fetch {}", url);
+        match client::upload_from_url(client, config, url, &options).await {
+            Ok(upload) => break upload,
+            Err(e) => {
+                if let Err(e) = retries.wait(e).await {
+                    return Err(upload_error(args, e));
+                }
+            }
+        }
+    };
+    log_upload(&upload);
+    Ok(Some(upload))
+}
 
-    info!("Image uploaded successfully. URL: {}", url);
+/// Explain why an upload failed, naming the timeout if it ran out
+fn upload_error(args: &Args, error: UploadError) -> anyhow::Error {
+    if error.is_timeout() {
+        anyhow!("Timed out after {}s waiting for the server", args.timeout)
+    } else {
+        error.into()
+    }
+}
 
+/// Log where an image was stored and what the server made of it
+fn log_upload(upload: &UploadResponse) {
+    if upload.existing {
+        info!("Image already uploaded. URL: {}", upload.url);
+    } else {
+        info!("Image uploaded successfully. URL: {}", upload.url);
+    }
+    if let (Some(width), Some(height), Some(bytes), Some(content_type)) = (
+        upload.width,
+        upload.height,
+        upload.bytes,
+        &upload.content_type,
+    ) {
+        info!(
+            "Stored {}x{} {} ({} bytes)",
+            width, height, content_type, bytes
+        );
+    }
+}
+
+/// Paces retries of failures that may be transient, allowing `--retries` of them in a row
+struct Retries<'a> {
+    args: &'a Args,
+    /// What is being uploaded, for messages
+    label: &'a str,
+    /// Failures since the upload last made progress
+    failures: u32,
+    /// How long to wait before the next retry
+    delay: Duration,
+}
+
+impl<'a> Retries<'a> {
+    fn new(args: &'a Args, label: &'a str) -> Self {
+        Self {
+            args,
+            label,
+            failures: 0,
+            delay: Duration::from_millis(args.retry_delay),
+        }
+    }
+
+    /// Wait before trying again after `error`, or hand it back if it isn't worth retrying
+    ///
+    /// Client errors, such as a bad API key, are never retried.
+    async fn wait(&mut self, error: UploadError) -> Result<(), UploadError> {
+        if !error.is_retryable() || self.failures >= self.args.retries {
+            return Err(error);
+        }
+        self.failures += 1;
+        let failure = anyhow::Chain::new(&error)
+            .map(ToString::to_string)
+            .collect::<Vec<_>>()
+            .join(": ");
+        warn!(
+            "Uploading {} failed ({}), retrying in {:?} (retry {} of {})",
+            self.label, failure, self.delay, self.failures, self.args.retries
+        );
+        tokio::time::sleep(self.delay).await;
+        self.delay = self.delay.saturating_mul(2);
+        Ok(())
+    }
+
+    /// Start counting afresh once the upload has made progress
+    fn reset(&mut self) {
+        self.failures = 0;
+        self.delay = Duration::from_millis(self.args.retry_delay);
+    }
+}
+
+/// Send an image to the server in `--chunk-size` pieces, carrying on from whatever it
+/// received after a failure
+///
+/// Returns `None` if the server doesn't support resumable uploads.
+#[allow(clippy::too_many_arguments)]
+async fn send_in_chunks(
+    args: &Args,
+    config: &Config,
+    client: &reqwest::Client,
+    bar: &ProgressBar,
+    encoded: &[u8],
+    extension: &str,
+    options: &UploadOptions,
+    retries: &mut Retries<'_>,
+) -> Result<Option<UploadResponse>, UploadError> {
+    let size = encoded.len() as u64;
+    let id = loop {
+        match client::start_resumable_upload(client, config, size, extension, options).await {
+            Ok(id) => break id,
+            // Servers from before resumable uploads don't have the route
+            Err(UploadError::Server {
+                status: reqwest::StatusCode::NOT_FOUND | reqwest::StatusCode::METHOD_NOT_ALLOWED,
+                ..
+            }) => return Ok(None),
+            Err(e) => retries.wait(e).await?,
+        }
+    };
+
+    let mut offset = 0;
+    let mut resync = false;
+    loop {
+        // After a failure, ask how much of the chunk made it before sending the rest
+        if resync {
+            match client::resumable_upload_offset(client, config, &id).await {
+                Ok(received) => {
+                    offset = resume_offset(received, size)?;
+                    resync = false;
+                }
+                Err(e) => {
+                    retries.wait(e).await?;
+                    continue;
+                }
+            }
+        }
+
+        let end = size.min(offset + args.chunk_size);
+        bar.set_position(offset);
+        let chunk = progress_body(encoded[offset as usize..end as usize].to_vec(), bar.clone());
+        match client::upload_chunk(client, config, &id, offset, chunk, end - offset).await {
+            Ok(ChunkResponse::Complete(upload)) => return Ok(Some(upload)),
+            Ok(ChunkResponse::Partial(received)) => {
+                offset = resume_offset(received, size)?;
+                retries.reset();
+            }
+            Err(e) => {
+                retries.wait(e).await?;
+                resync = true;
+            }
+        }
+    }
+}
+
+/// Check an offset the server reported for an incomplete upload of `size` bytes
+///
+/// Anything but a point before the end would have the next chunk slice past the
+/// data, or send nothing forever.
+fn resume_offset(received: u64, size: u64) -> Result<u64, UploadError> {
+    if received < size {
+        Ok(received)
+    } else {
+        Err(UploadError::InvalidOffset { received, size })
+    }
+}
+
+/// Whether the server still serves an image, judged by fetching its first byte
+///
+/// Only a 404 or 410 counts as gone, so an unreachable server doesn't discard the entry.
+async fn still_stored(client: &reqwest::Client, url: &str) -> bool {
+    match client.get(url).header("Range", "bytes=0-0").send().await {
+        Ok(response) => !matches!(
+            response.status(),
+            reqwest::StatusCode::NOT_FOUND | reqwest::StatusCode::GONE
+        ),
+        Err(e) => {
+            warn!("Failed to check {} is still stored: {}", url, e);
+            true
+        }
+    }
+}
+
+/// Directory kimage keeps its caches in, if the platform has a cache directory
+fn cache_dir() -> Option<PathBuf> {
+    Some(dirs::cache_dir()?.join("kimage"))
+}
+
+/// Empty kimage's cache directory, printing each file removed, or only what would be
+/// removed on a dry run
+fn clean_cache(dry_run: bool) -> Result<()> {
+    let Some(dir) = cache_dir() else {
+        println!("No cache directory, nothing to remove");
+        return Ok(());
+    };
+    let mut paths = match fs::read_dir(&dir) {
+        Ok(entries) => entries
+            .map(|entry| entry.map(|entry| entry.path()))
+            .collect::<io::Result<Vec<_>>>()
+            .with_context(|| format!("Failed to list {}", dir.display()))?,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+        Err(e) => return Err(e).with_context(|| format!("Failed to list {}", dir.display())),
+    };
+    if paths.is_empty() {
+        println!("Nothing to remove in {}", dir.display());
+        return Ok(());
+    }
+    paths.sort();
+
+    for path in &paths {
+        // Say how many uploads are forgotten along with the upload cache
+        let uploads = (path.file_name() == Some(UPLOAD_CACHE_FILE.as_ref()))
+            .then(|| fs::read(path).ok())
+            .flatten()
+            .and_then(|contents| {
+                serde_json::from_slice::<HashMap<String, serde_json::Value>>(&contents).ok()
+            });
+        match uploads {
+            Some(uploads) => println!("{} ({} cached uploads)", path.display(), uploads.len()),
+            None => println!("{}", path.display()),
+        }
+        if dry_run {
+            continue;
+        }
+        let removed = if path.is_dir() {
+            fs::remove_dir_all(path)
+        } else {
+            fs::remove_file(path)
+        };
+        removed.with_context(|| format!("Failed to remove {}", path.display()))?;
+    }
+    if dry_run {
+        println!(
+            "Would remove {} item(s) from {}",
+            paths.len(),
+            dir.display()
+        );
+    } else {
+        println!("Removed {} item(s) from {}", paths.len(), dir.display());
+    }
     Ok(())
 }
 
-/// Load the configuration from a TOML file in the user's home directory
-fn load_config() -> Result<Config> {
-    let config_path = home_dir()
-        .context("Failed to get home directory")?
-        .join(".config")
-        .join("kimage.toml");
+/// Name of the upload cache within [`cache_dir`]
+const UPLOAD_CACHE_FILE: &str = "uploads.json";
 
-    info!("Loading config from: {:?}", config_path);
-    let config_str = fs::read_to_string(config_path).context("Failed to read config file")?;
+/// Earlier uploads, remembered so the same image isn't uploaded twice
+///
+/// Kept in `uploads.json` in the user's cache directory, keyed by a hash of
+/// the server URL and the bytes that were uploaded.
+struct UploadCache {
+    /// File the cache is loaded from and saved to
+    path: PathBuf,
+    /// What the server answered each upload with
+    entries: HashMap<String, UploadResponse>,
+    /// Whether entries were added or removed since loading
+    changed: bool,
+}
+
+impl UploadCache {
+    /// Load the cache, starting an empty one if there is none yet or it can't be read
+    ///
+    /// Returns `None` if there's no cache directory to keep it in.
+    fn load() -> Option<Self> {
+        let path = cache_dir()?.join(UPLOAD_CACHE_FILE);
+        let entries = match fs::read(&path) {
+            Ok(contents) => serde_json::from_slice(&contents).unwrap_or_else(|e| {
+                warn!("Ignoring unreadable upload cache {:?}: {}", path, e);
+                HashMap::new()
+            }),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => HashMap::new(),
+            Err(e) => {
+                warn!("Failed to read upload cache {:?}: {}", path, e);
+                HashMap::new()
+            }
+        };
+        Some(Self {
+            path,
+            entries,
+            changed: false,
+        })
+    }
+
+    /// Key an upload of `payload` as `.extension` to `server_url` is cached under
+    fn key(server_url: &str, extension: &str, payload: &[u8]) -> String {
+        let mut hasher = Sha256::new();
+        hasher.update(server_url.as_bytes());
+        hasher.update([0]);
+        hasher.update(extension.as_bytes());
+        hasher.update([0]);
+        hasher.update(payload);
+        hasher
+            .finalize()
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect()
+    }
+
+    /// The earlier upload cached under `key`, unless it has expired since
+    fn get(&self, key: &str) -> Option<UploadResponse> {
+        let upload = self.entries.get(key)?;
+        let expired = upload
+            .expires_at
+            .as_deref()
+            .and_then(|expires_at| chrono::DateTime::parse_from_rfc3339(expires_at).ok())
+            .is_some_and(|expires_at| expires_at <= chrono::Utc::now());
+        (!expired).then(|| upload.clone())
+    }
+
+    /// Remember an upload
+    fn insert(&mut self, key: String, upload: UploadResponse) {
+        self.entries.insert(key, upload);
+        self.changed = true;
+    }
+
+    /// Forget an upload that is no longer stored
+    fn remove(&mut self, key: &str) {
+        self.changed |= self.entries.remove(key).is_some();
+    }
+
+    /// Write the cache back if it changed, warning rather than failing
+    fn save(&self) {
+        if !self.changed {
+            return;
+        }
+        let saved = self
+            .path
+            .parent()
+            .map_or(Ok(()), fs::create_dir_all)
+            .and_then(|()| {
+                let contents = serde_json::to_vec(&self.entries).map_err(io::Error::other)?;
+                // Write to the side first, so a concurrent run never sees half a file
+                let temp_path = self.path.with_extension("json.tmp");
+                fs::write(&temp_path, contents)?;
+                fs::rename(&temp_path, &self.path)
+            });
+        if let Err(e) = saved {
+            warn!("Failed to save upload cache {:?}: {}", self.path, e);
+        }
+    }
+}
+
+/// What the server is asked to do with an upload from `source`
+fn upload_options(args: &Args, source: Option<&Path>) -> UploadOptions {
+    UploadOptions {
+        // Let the server record what the file was called locally
+        file_name: source
+            .filter(|path| !is_stdin(path))
+            .and_then(|path| path.file_name())
+            .and_then(|name| name.to_str())
+            .map(str::to_owned),
+        name: args.name.clone(),
+        password: args.password.clone(),
+        burn: args.burn,
+        expire_after: args.expire_after,
+    }
+}
+
+/// Read an image and turn it into the bytes to upload, along with their extension
+fn prepare_image(args: &Args, source: Option<&Path>) -> Result<(Vec<u8>, String)> {
+    // Read the image file or stdin, or grab an image from the clipboard
+    let image_data = match source {
+        Some(image_path) if is_stdin(image_path) => {
+            info!("Loading image from stdin");
+            let mut data = Vec::new();
+            io::stdin()
+                .read_to_end(&mut data)
+                .context("Failed to read image from stdin")?;
+            data
+        }
+        Some(image_path) => {
+            info!("Loading image from path: {:?}", image_path);
+            fs::read(image_path).context("Failed to read image file")?
+        }
+        None => {
+            info!("Loading image from clipboard");
+            read_clipboard_image()?
+        }
+    };
+
+    // Metadata only survives if the original bytes are sent as they are
+    let source_format = image::guess_format(&image_data).ok();
+    let mut extension = args.format.extension().to_string();
+    let encoded = if args.no_convert {
+        extension = original_extension(source, source_format)?;
+        info!("Uploading original .{} file without conversion", extension);
+        image_data
+    } else if args.keep_metadata
+        && !args.edits_image()
+        && source_format == Some(args.format.image_format())
+    {
+        info!("Keeping metadata, uploading original file");
+        image_data
+    } else if let Some(format) = source_format.filter(|&format| is_animated(&image_data, format)) {
+        // Editing would flatten the animation, and uploading it unedited could leak what
+        // `--blur` or `--crop` were meant to hide
+        if args.edits_image() {
+            return Err(anyhow!(
+                "Animated {:?} images can't be blurred, cropped, resized or watermarked",
+                format
+            ));
+        }
+        if args.format == OutputFormat::Webp && format != ImageFormat::WebP {
+            info!("Image is animated, encoding it as an animated WebP");
+            encode_animated_webp(&image_data, format)?
+        } else {
+            // Re-encoding as anything else would flatten the animation to its first frame
+            info!("Image is animated, uploading original {:?} file", format);
+            if !args.keep_metadata {
+                warn!("Animated images are uploaded as they are, any metadata in them included");
+            }
+            extension = format.extensions_str()[0].to_string();
+            image_data
+        }
+    } else {
+        if args.keep_metadata {
+            warn!(
+                "Metadata can't be kept when converting to {:?}, it will be stripped",
+                args.format
+            );
+        }
+
+        // Load the image into memory, turning it upright before metadata is lost
+        let img = image::load_from_memory(&image_data).context("Failed to load image")?;
+        let img = apply_orientation(img, exif_orientation(&image_data));
+        let img = redact_regions(img, &args.blur, args.redact_style)?;
+        let img = match args.crop {
+            Some(crop) => crop_image(img, crop)?,
+            None => img,
+        };
+        let img = resize_image(img, args);
+        let img = apply_watermark(img, args)?;
+        let encoded = encode_image(img, args.format, args.quality, args.png_compression)?;
+        match args.format {
+            OutputFormat::Png if args.optimize => optimize_png(&encoded)?,
+            _ => {
+                if args.optimize {
+                    warn!("--optimize only applies to PNG output, skipping it");
+                }
+                encoded
+            }
+        }
+    };
+    Ok((encoded, extension))
+}
+
+/// Put uploaded URLs on the clipboard, warning rather than failing if it's unavailable
+fn copy_to_clipboard(text: &str) {
+    match Clipboard::new().and_then(|mut clipboard| clipboard.set_text(text)) {
+        Ok(()) => info!("Copied to clipboard"),
+        Err(e) => warn!("Failed to copy to clipboard: {}", e),
+    }
+}
+
+/// Whether there is a graphical session whose clipboard can be used
+///
+/// On Linux and the BSDs the clipboard belongs to an X11 or Wayland server, so
+/// without one (e.g. over SSH or in CI) trying to reach it only times out.
+fn display_available() -> bool {
+    if cfg!(any(target_os = "windows", target_os = "macos")) {
+        return true;
+    }
+    ["DISPLAY", "WAYLAND_DISPLAY"]
+        .iter()
+        .any(|var| std::env::var_os(var).is_some_and(|value| !value.is_empty()))
+}
+
+/// Wrap an upload payload in a streaming body that advances `progress` as it is sent
+fn progress_body(data: Vec<u8>, progress: ProgressBar) -> reqwest::Body {
+    const CHUNK_SIZE: usize = 64 * 1024;
+    let chunks = (0..data.len()).step_by(CHUNK_SIZE).map(move |start| {
+        let chunk = data[start..(start + CHUNK_SIZE).min(data.len())].to_vec();
+        progress.inc(chunk.len() as u64);
+        Ok::<_, io::Error>(chunk)
+    });
+    reqwest::Body::wrap_stream(futures::stream::iter(chunks))
+}
 
-    let config: Config = toml::from_str(&config_str).context("Failed to parse config file")?;
+/// Read an image from the clipboard, returning it encoded as PNG
+fn read_clipboard_image() -> Result<Vec<u8>> {
+    let mut clipboard = Clipboard::new().context("Failed to access clipboard")?;
+    let image = clipboard.get_image().map_err(|e| match e {
+        arboard::Error::ContentNotAvailable => anyhow!("Clipboard does not contain an image"),
+        e => anyhow!("Failed to read image from clipboard: {}", e),
+    })?;
 
+    let width = u32::try_from(image.width).context("Clipboard image is too wide")?;
+    let height = u32::try_from(image.height).context("Clipboard image is too tall")?;
+    let img = RgbaImage::from_raw(width, height, image.bytes.into_owned())
+        .ok_or_else(|| anyhow!("Clipboard image data is malformed"))?;
+
+    let mut buffer = Cursor::new(Vec::new());
+    DynamicImage::ImageRgba8(img)
+        .write_to(&mut buffer, ImageOutputFormat::Png)
+        .context("Failed to encode clipboard image")?;
+    Ok(buffer.into_inner())
+}
+
+/// Work out the extension of an image that is uploaded as is
+///
+/// The source file's own extension is preferred; for stdin and the clipboard the
+/// format is sniffed from the data instead.
+fn original_extension(
+    image_path: Option<&Path>,
+    source_format: Option<ImageFormat>,
+) -> Result<String> {
+    let from_path = image_path
+        .filter(|path| !is_stdin(path))
+        .and_then(|path| path.extension())
+        .and_then(|ext| ext.to_str());
+    from_path
+        .or_else(|| source_format.map(|format| format.extensions_str()[0]))
+        .map(str::to_lowercase)
+        .ok_or_else(|| anyhow!("Can't determine the image's file extension"))
+}
+
+/// Whether an encoded image holds more than one frame
+fn is_animated(data: &[u8], format: ImageFormat) -> bool {
+    match format {
+        ImageFormat::Gif => GifDecoder::new(Cursor::new(data))
+            .map(|decoder| decoder.into_frames().take(2).count() > 1)
+            .unwrap_or(false),
+        ImageFormat::Png => PngDecoder::new(Cursor::new(data))
+            .map(|decoder| decoder.is_apng())
+            .unwrap_or(false),
+        ImageFormat::WebP => WebPDecoder::new(Cursor::new(data))
+            .map(|decoder| decoder.has_animation())
+            .unwrap_or(false),
+        _ => false,
+    }
+}
+
+/// Re-encode an animated GIF or APNG as an animated WebP, losslessly like still WebP output
+fn encode_animated_webp(data: &[u8], format: ImageFormat) -> Result<Vec<u8>> {
+    let frames = match format {
+        ImageFormat::Gif => GifDecoder::new(Cursor::new(data))
+            .context("Failed to decode GIF")?
+            .into_frames(),
+        ImageFormat::Png => PngDecoder::new(Cursor::new(data))
+            .context("Failed to decode APNG")?
+            .apng()
+            .into_frames(),
+        _ => return Err(anyhow!("Can't convert animated {:?} to WebP", format)),
+    }
+    .collect_frames()
+    .context("Failed to decode animation frames")?;
+    let (width, height) = frames
+        .first()
+        .map(|frame| frame.buffer().dimensions())
+        .context("Animation has no frames")?;
+
+    let mut config =
+        WebPConfig::new().map_err(|()| anyhow!("Failed to set up the WebP encoder"))?;
+    config.lossless = 1;
+    let mut encoder = AnimEncoder::new(width, height, &config);
+    let mut timestamp: i32 = 0;
+    for frame in &frames {
+        if frame.buffer().dimensions() != (width, height) {
+            return Err(anyhow!(
+                "Animation frames differ in size, which animated WebP encoding doesn't support"
+            ));
+        }
+        encoder.add_frame(AnimFrame::from_rgba(
+            frame.buffer(),
+            width,
+            height,
+            timestamp,
+        ));
+        let (numer, denom) = frame.delay().numer_denom_ms();
+        // Browsers play frames with tiny delays at the default speed
+        let delay = match numer / denom.max(1) {
+            delay if delay < MIN_FRAME_DELAY_MS => DEFAULT_FRAME_DELAY_MS,
+            delay => delay,
+        };
+        timestamp = timestamp.saturating_add(i32::try_from(delay).unwrap_or(i32::MAX));
+    }
+    info!("Encoding {} frames as an animated WebP", frames.len());
+    let mut encoded = encoder
+        .try_encode()
+        .map_err(|e| anyhow!("Failed to encode animated WebP: {:?}", e))?
+        .to_vec();
+    set_last_frame_end(&mut encoded, u32::try_from(timestamp).unwrap_or_default());
+    Ok(encoded)
+}
+
+/// Make the last frame of an animated WebP last until `end_ms`
+///
+/// The `webp` crate ends animations with a zero timestamp, which leaves the
+/// encoder to guess how long the last frame is shown for.
+fn set_last_frame_end(webp: &mut [u8], end_ms: u32) {
+    // Chunks follow the 12-byte RIFF header; each ANMF payload holds its frame's
+    // 24-bit duration at byte 12
+    let mut offset = 12;
+    let mut elapsed_ms = 0;
+    let mut last_frame = None;
+    while let Some(header) = webp.get(offset..offset + 8) {
+        let size = u32::from_le_bytes([header[4], header[5], header[6], header[7]]) as usize;
+        if &header[..4] == b"ANMF" {
+            if let Some(duration) = webp.get(offset + 20..offset + 23) {
+                last_frame = Some((offset + 20, elapsed_ms));
+                elapsed_ms += u32::from_le_bytes([duration[0], duration[1], duration[2], 0]);
+            }
+        }
+        offset += 8 + size + size % 2;
+    }
+    if let Some((position, start_ms)) = last_frame {
+        let duration = end_ms.saturating_sub(start_ms).clamp(1, 0xff_ffff);
+        webp[position..position + 3].copy_from_slice(&duration.to_le_bytes()[..3]);
+    }
+}
+
+/// Hide regions of an image, which must lie within it
+fn redact_regions(img: DynamicImage, regions: &[Rect], style: RedactStyle) -> Result<DynamicImage> {
+    if regions.is_empty() {
+        return Ok(img);
+    }
+
+    let mut canvas = img.to_rgba8();
+    for region in regions {
+        region.check_fits(canvas.width(), canvas.height(), "Blur region")?;
+        let patch = match style {
+            RedactStyle::Blur => {
+                let patch = image::imageops::crop_imm(
+                    &canvas,
+                    region.x,
+                    region.y,
+                    region.width,
+                    region.height,
+                )
+                .to_image();
+                // Scale the blur with the region so that large text is hidden too
+                let sigma = (region.width.min(region.height) as f32 / 4.0).max(MIN_BLUR_SIGMA);
+                imageproc::filter::gaussian_blur_f32(&patch, sigma)
+            }
+            RedactStyle::Fill => {
+                RgbaImage::from_pixel(region.width, region.height, Rgba([0, 0, 0, 255]))
+            }
+        };
+        image::imageops::replace(&mut canvas, &patch, region.x.into(), region.y.into());
+        info!(
+            "Redacted {}x{} at {},{}",
+            region.width, region.height, region.x, region.y
+        );
+    }
+    Ok(DynamicImage::ImageRgba8(canvas))
+}
+
+/// Cut an image down to a rectangle, which must lie within it
+fn crop_image(img: DynamicImage, crop: Rect) -> Result<DynamicImage> {
+    crop.check_fits(img.width(), img.height(), "Crop rectangle")?;
+    info!(
+        "Cropping to {}x{} at {},{}",
+        crop.width, crop.height, crop.x, crop.y
+    );
+    Ok(img.crop_imm(crop.x, crop.y, crop.width, crop.height))
+}
+
+/// Shrink an image as requested by `--scale`, `--max-width` and `--max-height`
+///
+/// Images that already fit are left untouched; they are never enlarged.
+fn resize_image(img: DynamicImage, args: &Args) -> DynamicImage {
+    let (width, height) = img.dimensions();
+    let scaled = |length: u32| match args.scale {
+        Some(percent) => (u64::from(length) * u64::from(percent) / 100).max(1) as u32,
+        None => length,
+    };
+    let bound_width = args
+        .max_width
+        .map_or(scaled(width), |max| max.min(scaled(width)));
+    let bound_height = args
+        .max_height
+        .map_or(scaled(height), |max| max.min(scaled(height)));
+    if bound_width >= width && bound_height >= height {
+        if args.scale.is_some() || args.max_width.is_some() || args.max_height.is_some() {
+            info!(
+                "Image is {}x{}, which already fits, not resizing",
+                width, height
+            );
+        }
+        return img;
+    }
+
+    let resized = img.resize(bound_width, bound_height, FilterType::Lanczos3);
+    info!(
+        "Resized image from {}x{} to {}x{}",
+        width,
+        height,
+        resized.width(),
+        resized.height()
+    );
+    resized
+}
+
+/// Stamp the watermark requested by `args` onto an image, if there is one
+fn apply_watermark(img: DynamicImage, args: &Args) -> Result<DynamicImage> {
+    let mark = match (&args.watermark_text, &args.watermark_image) {
+        (Some(text), _) => text_watermark(text, args)?,
+        (None, Some(path)) => image_watermark(path, args.watermark_size)?,
+        (None, None) => return Ok(img),
+    };
+
+    let opacity = f32::from(args.watermark_opacity) / 100.0;
+    let mark = RgbaImage::from_fn(mark.width(), mark.height(), |x, y| {
+        let Rgba([r, g, b, a]) = *mark.get_pixel(x, y);
+        Rgba([r, g, b, (f32::from(a) * opacity).round() as u8])
+    });
+
+    let mut canvas = img.to_rgba8();
+    let (x, y) = args
+        .watermark_position
+        .origin(canvas.dimensions(), mark.dimensions());
+    info!("Adding a {}x{} watermark", mark.width(), mark.height());
+    image::imageops::overlay(&mut canvas, &mark, x, y);
+    Ok(DynamicImage::ImageRgba8(canvas))
+}
+
+/// Render watermark text as white letters with a dark shadow, on a transparent background
+fn text_watermark(text: &str, args: &Args) -> Result<RgbaImage> {
+    let font_data = match &args.watermark_font {
+        Some(path) => fs::read(path).context("Failed to read watermark font")?,
+        None => WATERMARK_FONTS
+            .iter()
+            .find_map(|path| fs::read(path).ok())
+            .ok_or_else(|| anyhow!("No system font found, pass one with --watermark-font"))?,
+    };
+    let font = Font::try_from_vec(font_data).ok_or_else(|| anyhow!("Invalid watermark font"))?;
+
+    let size = args.watermark_size.unwrap_or(DEFAULT_WATERMARK_TEXT_SIZE);
+    let scale = Scale::uniform(size as f32);
+    let (width, height) = imageproc::drawing::text_size(scale, &font, text);
+    let shadow = (size / 16).max(1);
+    let mut coverage = GrayImage::new(
+        u32::try_from(width).unwrap_or(0).max(1),
+        u32::try_from(height).unwrap_or(0).max(1),
+    );
+    imageproc::drawing::draw_text_mut(&mut coverage, Luma([255]), 0, 0, scale, &font, text);
+
+    // Each pixel takes the text's colour where it's covered, and the shadow's
+    // where only the text shifted by the shadow offset covers it
+    let covered = |x: i64, y: i64| -> f32 {
+        match (u32::try_from(x), u32::try_from(y)) {
+            (Ok(x), Ok(y)) if x < coverage.width() && y < coverage.height() => {
+                f32::from(coverage.get_pixel(x, y)[0]) / 255.0
+            }
+            _ => 0.0,
+        }
+    };
+    let offset = i64::from(shadow);
+    Ok(RgbaImage::from_fn(
+        coverage.width() + shadow,
+        coverage.height() + shadow,
+        |x, y| {
+            let (x, y) = (i64::from(x), i64::from(y));
+            let text = covered(x, y);
+            let shadow = covered(x - offset, y - offset) * (1.0 - text);
+            let alpha = text + shadow;
+            let level = if alpha > 0.0 {
+                255.0 * text / alpha
+            } else {
+                0.0
+            };
+            let level = level.round() as u8;
+            Rgba([level, level, level, (alpha * 255.0).round() as u8])
+        },
+    ))
+}
+
+/// Load a watermark image, scaled to `height` pixels tall if given
+fn image_watermark(path: &Path, height: Option<u32>) -> Result<RgbaImage> {
+    let mark = image::open(path)
+        .context("Failed to load watermark image")?
+        .to_rgba8();
+    let Some(height) = height else {
+        return Ok(mark);
+    };
+    let width = (u64::from(mark.width()) * u64::from(height) / u64::from(mark.height().max(1)))
+        .clamp(1, u64::from(u32::MAX)) as u32;
+    Ok(image::imageops::resize(
+        &mark,
+        width,
+        height,
+        FilterType::Lanczos3,
+    ))
+}
+
+/// Losslessly recompress a PNG with oxipng, logging how much it saved
+fn optimize_png(png: &[u8]) -> Result<Vec<u8>> {
+    let optimized = oxipng::optimize_from_memory(png, &oxipng::Options::from_preset(2))
+        .context("Failed to optimize PNG")?;
+    info!(
+        "Optimized PNG from {} to {} bytes",
+        png.len(),
+        optimized.len()
+    );
+    Ok(optimized)
+}
+
+/// Load the configuration from a TOML file, by default in the user's home directory
+///
+/// `--server` and `--api-key` take precedence over the file's settings.
+fn load_config(args: &Args) -> Result<Config> {
+    let config_path = kimage::config_path(args.config.as_deref())?;
+
+    info!("Loading config from: {:?}", config_path);
+    let mut config: Config = kimage::read_config(&config_path)?;
+    if let Some(server) = &args.server {
+        info!("Uploading to {} instead of {}", server, config.server_url);
+        config.server_url = server.clone();
+    }
+    match &args.api_key {
+        Some(api_key) => config.api_key = api_key.clone(),
+        None => config.resolve_api_key()?,
+    }
     Ok(config)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::codecs::gif::GifEncoder;
+    use image::Frame;
+
+    /// A two-frame 4x3 GIF
+    fn animated_gif() -> Vec<u8> {
+        let mut gif = Vec::new();
+        let mut encoder = GifEncoder::new(&mut gif);
+        for shade in [0, 255] {
+            let frame = RgbaImage::from_pixel(4, 3, Rgba([shade, shade, shade, 255]));
+            encoder.encode_frame(Frame::new(frame)).unwrap();
+        }
+        drop(encoder);
+        gif
+    }
+
+    #[test]
+    fn clean_is_a_subcommand_that_takes_the_place_of_image_paths() {
+        let args = Args::try_parse_from(["upload", "clean", "--dry-run"]).unwrap();
+        assert!(matches!(
+            args.command,
+            Some(Command::Clean { dry_run: true })
+        ));
+        assert!(Args::try_parse_from(["upload", "clean", "image.png"]).is_err());
+
+        // Only the first argument can be a subcommand
+        let args = Args::try_parse_from(["upload", "./clean", "clean"]).unwrap();
+        assert!(args.command.is_none());
+        assert_eq!(args.image_paths, [Path::new("./clean"), Path::new("clean")]);
+    }
+
+    #[test]
+    fn animated_images_are_not_uploaded_unedited() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("animated.gif");
+        fs::write(&path, animated_gif()).unwrap();
+        let path = path.to_str().unwrap();
+
+        for edit in [
+            &["--blur", "0,0,2,2"][..],
+            &["--crop", "0,0,2,2"],
+            &["--scale", "50"],
+            &["--max-width", "2"],
+            &["--watermark-text", "kimage"],
+        ] {
+            let args = Args::try_parse_from(["upload"].iter().chain(edit).chain([&path])).unwrap();
+            let error = prepare_image(&args, Some(Path::new(path))).unwrap_err();
+            assert!(
+                error.to_string().contains("Animated Gif"),
+                "{:?}: {}",
+                edit,
+                error
+            );
+        }
+
+        let args = Args::try_parse_from(["upload", path]).unwrap();
+        let (_, extension) = prepare_image(&args, Some(Path::new(path))).unwrap();
+        assert_eq!(extension, "gif");
+    }
+}
@@ -0,0 +1,559 @@
+//! Preparing and uploading images to a `kimage-serve` server.
+//!
+//! The `kimage` binary is built on these, and other programs can use them to
+//! upload images without going through the command line: load a [`Config`], turn
+//! an image into upload bytes with [`convert_image`], and send it with [`upload_image`].
+
+use crate::ConfigError;
+use anyhow::{Context, Result};
+use clap::ValueEnum;
+use image::codecs::png::{CompressionType, FilterType, PngEncoder};
+use image::{DynamicImage, ImageFormat, ImageOutputFormat};
+use reqwest::StatusCode;
+use serde::{Deserialize, Serialize};
+use std::io::Cursor;
+use std::path::{Path, PathBuf};
+use thiserror::Error;
+
+/// Settings the uploader reads from the shared config file
+#[derive(Deserialize, Debug, Clone)]
+pub struct Config {
+    /// URL of the server to upload images to
+    pub server_url: String,
+    /// API key for authentication with the server
+    #[serde(default)]
+    pub api_key: String,
+    /// File to read the API key from instead, such as a secret kept out of the config
+    #[serde(default)]
+    pub api_key_file: Option<PathBuf>,
+    /// Environment variable to read the API key from instead
+    #[serde(default)]
+    pub api_key_env: Option<String>,
+}
+
+impl Config {
+    /// Read the config file at `path` and resolve its API key
+    pub fn load(path: &Path) -> Result<Self, ConfigError> {
+        let mut config: Self = crate::read_config(path)?;
+        config.resolve_api_key()?;
+        Ok(config)
+    }
+
+    /// Replace `api_key` with the key from `api_key_env` or `api_key_file`, if one is set
+    ///
+    /// Fails if no source holds a key.
+    pub fn resolve_api_key(&mut self) -> Result<(), ConfigError> {
+        self.api_key = crate::resolve_api_key(
+            Some(std::mem::take(&mut self.api_key)).filter(|key| !key.is_empty()),
+            self.api_key_file.as_deref(),
+            self.api_key_env.as_deref(),
+        )?
+        .ok_or_else(|| {
+            ConfigError::Invalid(vec![
+                "api_key (or api_key_file or api_key_env) must be set".to_string()
+            ])
+        })?;
+        Ok(())
+    }
+
+    /// URL images are uploaded to
+    pub fn upload_url(&self) -> String {
+        format!("{}/upload", self.server_url)
+    }
+}
+
+/// JSON body the server answers a successful upload with
+///
+/// Older servers only send the URL.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct UploadResponse {
+    /// URL of the uploaded image
+    pub url: String,
+    /// Key the server stored the image under, such as `2024/01/15/abc.png`
+    pub filename: Option<String>,
+    /// Width of the image in pixels
+    pub width: Option<u32>,
+    /// Height of the image in pixels
+    pub height: Option<u32>,
+    /// Size of the stored file in bytes
+    pub bytes: Option<u64>,
+    /// Content type the image is served with
+    pub content_type: Option<String>,
+    /// When the image expires, as an RFC 3339 timestamp, if it does
+    pub expires_at: Option<String>,
+    /// Whether the server already had an identical image stored
+    #[serde(default)]
+    pub existing: bool,
+}
+
+impl UploadResponse {
+    /// Name the server stored the image under
+    ///
+    /// Servers that don't report it are taken to store images under the last
+    /// segment of their URL.
+    pub fn filename(&self) -> &str {
+        self.filename
+            .as_deref()
+            .unwrap_or_else(|| self.url.rsplit('/').next().unwrap_or_default())
+    }
+}
+
+/// Image formats the uploader can re-encode to
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OutputFormat {
+    Png,
+    Jpeg,
+    Webp,
+}
+
+impl OutputFormat {
+    /// The `image` crate format this output corresponds to
+    pub fn image_format(self) -> ImageFormat {
+        match self {
+            OutputFormat::Png => ImageFormat::Png,
+            OutputFormat::Jpeg => ImageFormat::Jpeg,
+            OutputFormat::Webp => ImageFormat::WebP,
+        }
+    }
+
+    /// File extension the server should store the image under
+    pub fn extension(self) -> &'static str {
+        match self {
+            OutputFormat::Png => "png",
+            OutputFormat::Jpeg => "jpg",
+            OutputFormat::Webp => "webp",
+        }
+    }
+
+    /// Encoder settings to pass to `DynamicImage::write_to`
+    fn output_format(self, quality: u8) -> ImageOutputFormat {
+        match self {
+            OutputFormat::Png => ImageOutputFormat::Png,
+            OutputFormat::Jpeg => ImageOutputFormat::Jpeg(quality),
+            OutputFormat::Webp => ImageOutputFormat::WebP,
+        }
+    }
+}
+
+/// How hard the PNG encoder works to shrink its output
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum PngCompression {
+    /// Quickest to encode, with larger files
+    Fast,
+    /// A balance of speed and size
+    #[default]
+    Default,
+    /// Smallest files, slowest to encode
+    Best,
+}
+
+impl PngCompression {
+    /// The `image` crate compression level this corresponds to
+    fn compression_type(self) -> CompressionType {
+        match self {
+            PngCompression::Fast => CompressionType::Fast,
+            PngCompression::Default => CompressionType::Default,
+            PngCompression::Best => CompressionType::Best,
+        }
+    }
+}
+
+/// Encode an image in the given format
+///
+/// `quality` (1-100) only applies to JPEG and `png_compression` only to PNG.
+pub fn encode_image(
+    img: DynamicImage,
+    format: OutputFormat,
+    quality: u8,
+    png_compression: PngCompression,
+) -> Result<Vec<u8>> {
+    // JPEG has no alpha channel, so flatten to RGB before encoding
+    let img = match format {
+        OutputFormat::Jpeg => DynamicImage::ImageRgb8(img.to_rgb8()),
+        _ => img,
+    };
+
+    log::info!("Encoding image as {:?}", format);
+    let mut buffer = Cursor::new(Vec::new());
+    match format {
+        OutputFormat::Png => img.write_with_encoder(PngEncoder::new_with_quality(
+            &mut buffer,
+            png_compression.compression_type(),
+            FilterType::Adaptive,
+        )),
+        _ => img.write_to(&mut buffer, format.output_format(quality)),
+    }
+    .with_context(|| format!("Failed to encode image as {:?}", format))?;
+    let encoded = buffer.into_inner();
+    log::info!("Encoded image as {:?}, {} bytes", format, encoded.len());
+    Ok(encoded)
+}
+
+/// Decode an image file and re-encode it in the given format, turned upright
+///
+/// Only the pixels are kept, so metadata such as EXIF location tags is stripped.
+pub fn convert_image(
+    data: &[u8],
+    format: OutputFormat,
+    quality: u8,
+    png_compression: PngCompression,
+) -> Result<Vec<u8>> {
+    let img = image::load_from_memory(data).context("Failed to load image")?;
+    encode_image(
+        apply_orientation(img, exif_orientation(data)),
+        format,
+        quality,
+        png_compression,
+    )
+}
+
+/// Read the EXIF orientation tag from an encoded image, if it has one
+pub fn exif_orientation(data: &[u8]) -> Option<u32> {
+    let exif = exif::Reader::new()
+        .read_from_container(&mut Cursor::new(data))
+        .ok()?;
+    exif.get_field(exif::Tag::Orientation, exif::In::PRIMARY)?
+        .value
+        .get_uint(0)
+}
+
+/// Rotate and flip an image so that it displays upright for the given EXIF orientation
+pub fn apply_orientation(img: DynamicImage, orientation: Option<u32>) -> DynamicImage {
+    match orientation {
+        Some(2) => img.fliph(),
+        Some(3) => img.rotate180(),
+        Some(4) => img.flipv(),
+        Some(5) => img.rotate90().fliph(),
+        Some(6) => img.rotate90(),
+        Some(7) => img.rotate270().fliph(),
+        Some(8) => img.rotate270(),
+        _ => img,
+    }
+}
+
+/// How the server should store an upload
+#[derive(Default, Clone, Debug)]
+pub struct UploadOptions {
+    /// Name of the file the image came from, which the server records
+    pub file_name: Option<String>,
+    /// Name to store the image under instead of a generated one, without the extension
+    pub name: Option<String>,
+    /// Password needed to view the image
+    pub password: Option<String>,
+    /// Delete the image once it has been viewed
+    pub burn: bool,
+    /// Delete the image after this many seconds
+    pub expire_after: Option<u64>,
+}
+
+/// Why an upload failed
+#[derive(Debug, Error)]
+pub enum UploadError {
+    /// The request couldn't be sent, or no response arrived
+    #[error("Failed to send request")]
+    Request(#[source] reqwest::Error),
+    /// The server refused the upload
+    #[error("Server returned error {status}: {message}")]
+    Server {
+        /// Status the server answered with
+        status: StatusCode,
+        /// The server's explanation, or `no details given`
+        message: String,
+    },
+    /// The server accepted the upload but its answer couldn't be understood
+    #[error("Invalid response format")]
+    InvalidResponse(#[source] reqwest::Error),
+    /// The server claimed to hold a resumable upload's bytes up to an offset that can't be
+    /// carried on from: past the end, or the whole upload without completing it
+    #[error("Server reported receiving {received} of {size} bytes without completing the upload")]
+    InvalidOffset {
+        /// Bytes the server said it had received
+        received: u64,
+        /// Size of the upload
+        size: u64,
+    },
+}
+
+impl UploadError {
+    /// Whether trying again might succeed: connection failures, timeouts and server errors
+    ///
+    /// Client errors, such as a bad API key, won't go away by themselves.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            UploadError::Request(e) => e.is_connect() || e.is_timeout(),
+            UploadError::Server { status, .. } => status.is_server_error(),
+            UploadError::InvalidResponse(_) | UploadError::InvalidOffset { .. } => false,
+        }
+    }
+
+    /// Whether the request timed out
+    pub fn is_timeout(&self) -> bool {
+        matches!(self, UploadError::Request(e) if e.is_timeout())
+    }
+}
+
+/// Upload an encoded image stored as `.extension`, returning what the server said about it
+///
+/// Makes a single attempt; see [`UploadError::is_retryable`] for which failures are
+/// worth retrying.
+pub async fn upload_image(
+    client: &reqwest::Client,
+    config: &Config,
+    image: Vec<u8>,
+    extension: &str,
+    options: &UploadOptions,
+) -> Result<UploadResponse, UploadError> {
+    let length = image.len() as u64;
+    upload_body(client, config, image.into(), length, extension, options).await
+}
+
+/// Upload an image sent as `body`, which must be `length` bytes long
+///
+/// Like [`upload_image`], but the body can be streamed, e.g. to report progress.
+pub async fn upload_body(
+    client: &reqwest::Client,
+    config: &Config,
+    body: reqwest::Body,
+    length: u64,
+    extension: &str,
+    options: &UploadOptions,
+) -> Result<UploadResponse, UploadError> {
+    let mut image_part = reqwest::multipart::Part::stream_with_length(body, length);
+    // Let the server record what the file was called locally
+    if let Some(file_name) = &options.file_name {
+        image_part = image_part.file_name(file_name.clone());
+    }
+    let mut form = reqwest::multipart::Form::new().text("extension", extension.to_owned());
+    if let Some(name) = &options.name {
+        form = form.text("name", name.clone());
+    }
+    if let Some(password) = &options.password {
+        form = form.text("password", password.clone());
+    }
+    let form = form.part("image", image_part);
+
+    let request = client
+        .post(config.upload_url())
+        .header("Authorization", &config.api_key);
+    let response = storage_headers(request, options)
+        .multipart(form)
+        .send()
+        .await
+        .map_err(UploadError::Request)?;
+    successful(response)
+        .await?
+        .json()
+        .await
+        .map_err(UploadError::InvalidResponse)
+}
+
+/// Have the server fetch the image at `url` and store it like an upload
+///
+/// Servers only fetch from the hosts their `fetch_allowed_hosts` lists.
+pub async fn upload_from_url(
+    client: &reqwest::Client,
+    config: &Config,
+    url: &str,
+    options: &UploadOptions,
+) -> Result<UploadResponse, UploadError> {
+    let request = client
+        .post(format!("{}/upload-url", config.server_url))
+        .header("Authorization", &config.api_key);
+    let response = storage_headers(request, options)
+        .json(&serde_json::json!({
+            "url": url,
+            "name": options.name,
+            "password": options.password,
+        }))
+        .send()
+        .await
+        .map_err(UploadError::Request)?;
+    successful(response)
+        .await?
+        .json()
+        .await
+        .map_err(UploadError::InvalidResponse)
+}
+
+/// What the server answered a chunk of a resumable upload with
+#[derive(Debug)]
+pub enum ChunkResponse {
+    /// More data is expected, starting at this offset
+    Partial(u64),
+    /// The last chunk arrived and the image is stored
+    Complete(UploadResponse),
+}
+
+/// Progress of a resumable upload, as reported by the server
+#[derive(Deserialize)]
+struct ResumableUploadStatus {
+    /// ID chunks are sent to
+    id: String,
+    /// Bytes received so far
+    offset: u64,
+}
+
+/// Start a resumable upload of a `size` byte image stored as `.extension`, returning its ID
+///
+/// The image is then sent in chunks with [`upload_chunk`]. Servers that predate resumable
+/// uploads answer with a `404 Not Found` [`UploadError::Server`].
+pub async fn start_resumable_upload(
+    client: &reqwest::Client,
+    config: &Config,
+    size: u64,
+    extension: &str,
+    options: &UploadOptions,
+) -> Result<String, UploadError> {
+    let request = client
+        .post(format!("{}/init", config.upload_url()))
+        .header("Authorization", &config.api_key);
+    let response = storage_headers(request, options)
+        .json(&serde_json::json!({
+            "size": size,
+            "extension": extension,
+            "name": options.name,
+            "password": options.password,
+            "file_name": options.file_name,
+        }))
+        .send()
+        .await
+        .map_err(UploadError::Request)?;
+    let status: ResumableUploadStatus = successful(response)
+        .await?
+        .json()
+        .await
+        .map_err(UploadError::InvalidResponse)?;
+    Ok(status.id)
+}
+
+/// Ask the server how many bytes of a resumable upload it has, to resume after a failure
+pub async fn resumable_upload_offset(
+    client: &reqwest::Client,
+    config: &Config,
+    id: &str,
+) -> Result<u64, UploadError> {
+    let response = client
+        .get(format!("{}/{}", config.upload_url(), id))
+        .header("Authorization", &config.api_key)
+        .send()
+        .await
+        .map_err(UploadError::Request)?;
+    let status: ResumableUploadStatus = successful(response)
+        .await?
+        .json()
+        .await
+        .map_err(UploadError::InvalidResponse)?;
+    Ok(status.offset)
+}
+
+/// Send the `length` bytes of `chunk`, which start `offset` bytes into the image, to a
+/// resumable upload
+pub async fn upload_chunk(
+    client: &reqwest::Client,
+    config: &Config,
+    id: &str,
+    offset: u64,
+    chunk: reqwest::Body,
+    length: u64,
+) -> Result<ChunkResponse, UploadError> {
+    let response = client
+        .patch(format!("{}/{}", config.upload_url(), id))
+        .header("Authorization", &config.api_key)
+        .header("Upload-Offset", offset)
+        .header(reqwest::header::CONTENT_TYPE, "application/octet-stream")
+        .header(reqwest::header::CONTENT_LENGTH, length)
+        .body(chunk)
+        .send()
+        .await
+        .map_err(UploadError::Request)?;
+    let response = successful(response).await?;
+    if response.status() == StatusCode::ACCEPTED {
+        let status: ResumableUploadStatus = response
+            .json()
+            .await
+            .map_err(UploadError::InvalidResponse)?;
+        return Ok(ChunkResponse::Partial(status.offset));
+    }
+    let upload = response
+        .json()
+        .await
+        .map_err(UploadError::InvalidResponse)?;
+    Ok(ChunkResponse::Complete(upload))
+}
+
+/// Add the headers asking for an upload to expire or be deleted once viewed
+fn storage_headers(
+    mut request: reqwest::RequestBuilder,
+    options: &UploadOptions,
+) -> reqwest::RequestBuilder {
+    if let Some(expire_after) = options.expire_after {
+        request = request.header("X-Expire-After", expire_after);
+    }
+    if options.burn {
+        request = request.header("X-Burn", "true");
+    }
+    request
+}
+
+/// Pass on a successful response, or turn a failed one into an error carrying the
+/// server's explanation, if it gave one
+async fn successful(response: reqwest::Response) -> Result<reqwest::Response, UploadError> {
+    let status = response.status();
+    if status.is_success() {
+        return Ok(response);
+    }
+    let body: Option<serde_json::Value> = response.json().await.ok();
+    let message = body
+        .as_ref()
+        .and_then(|body| body["error"].as_str())
+        .unwrap_or("no details given")
+        .to_owned();
+    Err(UploadError::Server { status, message })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{GenericImageView, Rgb, RgbImage};
+
+    /// A 32x16 JPEG, red in its top-left corner, tagged with EXIF `orientation`
+    fn rotated_jpeg(orientation: u8) -> Vec<u8> {
+        let img = RgbImage::from_fn(32, 16, |x, y| {
+            if x < 8 && y < 8 {
+                Rgb([255, 0, 0])
+            } else {
+                Rgb([255, 255, 255])
+            }
+        });
+        let mut jpeg = Vec::new();
+        DynamicImage::ImageRgb8(img)
+            .write_to(&mut Cursor::new(&mut jpeg), ImageOutputFormat::Jpeg(95))
+            .unwrap();
+
+        // An APP1 segment holding a little-endian TIFF header and one IFD entry: the orientation
+        let mut exif = b"Exif\0\0II\x2a\0\x08\0\0\0\x01\0\x12\x01\x03\0\x01\0\0\0".to_vec();
+        exif.extend_from_slice(&[orientation, 0, 0, 0, 0, 0, 0, 0]);
+        let mut app1 = vec![0xff, 0xe1];
+        app1.extend_from_slice(&(exif.len() as u16 + 2).to_be_bytes());
+        app1.extend_from_slice(&exif);
+        // Goes straight after the start-of-image marker
+        jpeg.splice(2..2, app1);
+        jpeg
+    }
+
+    #[test]
+    fn rotated_jpeg_is_turned_upright() {
+        let jpeg = rotated_jpeg(6);
+        assert_eq!(exif_orientation(&jpeg), Some(6));
+
+        let img = image::load_from_memory(&jpeg).unwrap();
+        assert_eq!(img.dimensions(), (32, 16));
+        let upright = apply_orientation(img, exif_orientation(&jpeg));
+        assert_eq!(upright.dimensions(), (16, 32));
+        // Turning it clockwise carries the top-left corner to the top right
+        let [r, g, b, _] = upright.get_pixel(12, 3).0;
+        assert!(r > 200 && g < 80 && b < 80, "{:?}", (r, g, b));
+        let [r, g, b, _] = upright.get_pixel(3, 3).0;
+        assert!(r > 200 && g > 200 && b > 200, "{:?}", (r, g, b));
+    }
+}
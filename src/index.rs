@@ -0,0 +1,344 @@
+//! An optional SQLite index of uploads kept by `kimage-serve`.
+//!
+//! Listing from the index avoids scanning storage and reading every metadata
+//! sidecar. Calls block on SQLite, so async callers should run them off the
+//! worker threads.
+
+use anyhow::{Context, Result};
+use rusqlite::{params, Connection, OptionalExtension, Row};
+use std::path::Path;
+use std::sync::{Mutex, PoisonError};
+
+/// What the index records about an upload
+pub struct IndexEntry {
+    /// Name the image is stored and served under
+    pub filename: String,
+    /// Name of the file the client uploaded, if it sent one
+    pub original_name: Option<String>,
+    /// Label of the API key the image was uploaded with
+    pub key_label: String,
+    /// Size in bytes
+    pub size: u64,
+    /// Content type of the stored image
+    pub content_type: String,
+    /// Unix timestamp of when the image was uploaded
+    pub created_at: u64,
+    /// Unix timestamp after which the image is no longer served
+    pub expires_at: Option<u64>,
+    /// Salted hash of the token needed to view the image, if it is protected
+    pub token_hash: Option<String>,
+    /// Whether the image is deleted once it has been viewed
+    pub burn: bool,
+    /// Perceptual hash of the image, if one was computed when it was uploaded
+    pub phash: Option<u64>,
+}
+
+impl IndexEntry {
+    fn from_row(row: &Row<'_>) -> rusqlite::Result<Self> {
+        Ok(Self {
+            filename: row.get("filename")?,
+            original_name: row.get("original_name")?,
+            key_label: row.get("key_label")?,
+            size: row.get("size")?,
+            content_type: row.get("content_type")?,
+            created_at: row.get("created_at")?,
+            expires_at: row.get("expires_at")?,
+            token_hash: row.get("token_hash")?,
+            burn: row.get("burn")?,
+            // SQLite integers are signed, so the hash's bits are stored as an i64
+            phash: row.get::<_, Option<i64>>("phash")?.map(|hash| hash as u64),
+        })
+    }
+}
+
+/// How many images of one content type there are and how much space they take up
+pub struct FormatTotals {
+    /// Content type the images are stored as
+    pub content_type: String,
+    /// Number of images
+    pub files: u64,
+    /// Combined size in bytes
+    pub bytes: u64,
+    /// Unix timestamp of the earliest upload
+    pub oldest: u64,
+    /// Unix timestamp of the latest upload
+    pub newest: u64,
+}
+
+/// A SQLite database with a row for each upload
+pub struct Index {
+    connection: Mutex<Connection>,
+}
+
+impl Index {
+    /// Open the index at `path`, creating the database if it doesn't exist yet
+    pub fn open(path: &Path) -> Result<Self> {
+        let connection = Connection::open(path)
+            .with_context(|| format!("Failed to open index database {:?}", path))?;
+        connection
+            .execute_batch(
+                "CREATE TABLE IF NOT EXISTS images (
+                    filename TEXT PRIMARY KEY,
+                    original_name TEXT,
+                    key_label TEXT NOT NULL,
+                    size INTEGER NOT NULL,
+                    content_type TEXT NOT NULL,
+                    created_at INTEGER NOT NULL,
+                    expires_at INTEGER,
+                    token_hash TEXT,
+                    burn INTEGER NOT NULL DEFAULT 0,
+                    phash INTEGER
+                );
+                CREATE INDEX IF NOT EXISTS images_created_at ON images (created_at);
+                CREATE TABLE IF NOT EXISTS aliases (
+                    alias TEXT PRIMARY KEY,
+                    filename TEXT NOT NULL,
+                    created_at INTEGER NOT NULL
+                );
+                CREATE INDEX IF NOT EXISTS aliases_filename ON aliases (filename);",
+            )
+            .context("Failed to create index tables")?;
+
+        // Databases created by older versions lack the columns added since
+        add_missing_column(&connection, "token_hash", "TEXT")?;
+        add_missing_column(&connection, "burn", "INTEGER NOT NULL DEFAULT 0")?;
+        add_missing_column(&connection, "phash", "INTEGER")?;
+        Ok(Self {
+            connection: Mutex::new(connection),
+        })
+    }
+
+    fn connection(&self) -> std::sync::MutexGuard<'_, Connection> {
+        self.connection
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Record an upload, replacing any earlier entry for the same filename
+    pub fn insert(&self, entry: &IndexEntry) -> Result<()> {
+        self.connection()
+            .execute(
+                "INSERT OR REPLACE INTO images
+                    (filename, original_name, key_label, size, content_type, created_at, expires_at,
+                    token_hash, burn, phash)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)",
+                params![
+                    entry.filename,
+                    entry.original_name,
+                    entry.key_label,
+                    entry.size,
+                    entry.content_type,
+                    entry.created_at,
+                    entry.expires_at,
+                    entry.token_hash,
+                    entry.burn,
+                    entry.phash.map(|hash| hash as i64),
+                ],
+            )
+            .context("Failed to insert index entry")?;
+        Ok(())
+    }
+
+    /// Look up the entry for an image
+    pub fn get(&self, filename: &str) -> Result<Option<IndexEntry>> {
+        self.connection()
+            .query_row(
+                "SELECT * FROM images WHERE filename = ?1",
+                params![filename],
+                IndexEntry::from_row,
+            )
+            .optional()
+            .context("Failed to query index")
+    }
+
+    /// Fetch a page of images that haven't expired by `now`, ordered by upload time
+    ///
+    /// One-time images are left out, as listing them would invite using up their
+    /// only view, and so are password-protected ones unless `include_protected`
+    /// is set. Also returns how many matching images there are in total.
+    pub fn list(
+        &self,
+        now: u64,
+        newest_first: bool,
+        include_protected: bool,
+        offset: usize,
+        limit: usize,
+    ) -> Result<(usize, Vec<IndexEntry>)> {
+        let connection = self.connection();
+        let filter = format!(
+            "(expires_at IS NULL OR expires_at > ?1) AND burn = 0{}",
+            if include_protected {
+                ""
+            } else {
+                " AND token_hash IS NULL"
+            }
+        );
+        let total = connection
+            .query_row(
+                &format!("SELECT COUNT(*) FROM images WHERE {}", filter),
+                params![now],
+                |row| row.get(0),
+            )
+            .context("Failed to count index entries")?;
+
+        let order = if newest_first { "DESC" } else { "ASC" };
+        let mut statement = connection
+            .prepare(&format!(
+                "SELECT * FROM images WHERE {filter}
+                ORDER BY created_at {order}, filename {order} LIMIT ?2 OFFSET ?3",
+                filter = filter,
+                order = order
+            ))
+            .context("Failed to prepare index query")?;
+        let entries = statement
+            .query_map(params![now, limit, offset], IndexEntry::from_row)
+            .context("Failed to query index")?
+            .collect::<rusqlite::Result<_>>()
+            .context("Failed to read index entry")?;
+        Ok((total, entries))
+    }
+
+    /// Count the images that haven't expired by `now`, grouped by content type
+    pub fn totals_by_format(&self, now: u64) -> Result<Vec<FormatTotals>> {
+        let connection = self.connection();
+        let mut statement = connection
+            .prepare(
+                "SELECT content_type, COUNT(*), SUM(size), MIN(created_at), MAX(created_at)
+                FROM images WHERE expires_at IS NULL OR expires_at > ?1
+                GROUP BY content_type",
+            )
+            .context("Failed to prepare index query")?;
+        let totals = statement
+            .query_map(params![now], |row| {
+                Ok(FormatTotals {
+                    content_type: row.get(0)?,
+                    files: row.get(1)?,
+                    bytes: row.get(2)?,
+                    oldest: row.get(3)?,
+                    newest: row.get(4)?,
+                })
+            })
+            .context("Failed to query index")?
+            .collect::<rusqlite::Result<_>>()
+            .context("Failed to read index totals")?;
+        Ok(totals)
+    }
+
+    /// Find unexpired images whose perceptual hash is within `max_distance` bits of `phash`
+    ///
+    /// Returns each match with its Hamming distance, closest first.
+    pub fn similar(
+        &self,
+        phash: u64,
+        max_distance: u32,
+        now: u64,
+    ) -> Result<Vec<(u32, IndexEntry)>> {
+        let connection = self.connection();
+        let mut statement = connection
+            .prepare(
+                "SELECT * FROM images WHERE phash IS NOT NULL
+                AND (expires_at IS NULL OR expires_at > ?1)",
+            )
+            .context("Failed to prepare index query")?;
+        let entries = statement
+            .query_map(params![now], IndexEntry::from_row)
+            .context("Failed to query index")?;
+
+        let mut similar = Vec::new();
+        for entry in entries {
+            let entry = entry.context("Failed to read index entry")?;
+            let distance = entry
+                .phash
+                .map_or(u32::MAX, |hash| (hash ^ phash).count_ones());
+            if distance <= max_distance {
+                similar.push((distance, entry));
+            }
+        }
+        similar.sort_by(|a, b| a.0.cmp(&b.0).then_with(|| a.1.filename.cmp(&b.1.filename)));
+        Ok(similar)
+    }
+
+    /// Point `alias` at an image, returning `false` if the alias is already taken
+    pub fn insert_alias(&self, alias: &str, filename: &str, created_at: u64) -> Result<bool> {
+        let inserted = self
+            .connection()
+            .execute(
+                "INSERT OR IGNORE INTO aliases (alias, filename, created_at) VALUES (?1, ?2, ?3)",
+                params![alias, filename, created_at],
+            )
+            .context("Failed to insert alias")?;
+        Ok(inserted > 0)
+    }
+
+    /// Look up the filename of the image an alias points at
+    pub fn resolve_alias(&self, alias: &str) -> Result<Option<String>> {
+        self.connection()
+            .query_row(
+                "SELECT filename FROM aliases WHERE alias = ?1",
+                params![alias],
+                |row| row.get(0),
+            )
+            .optional()
+            .context("Failed to query aliases")
+    }
+
+    /// Record the new size of an image whose contents were replaced
+    pub fn update_size(&self, filename: &str, size: u64) -> Result<()> {
+        self.connection()
+            .execute(
+                "UPDATE images SET size = ?2 WHERE filename = ?1",
+                params![filename, size],
+            )
+            .context("Failed to update index entry")?;
+        Ok(())
+    }
+
+    /// Drop the entry for an image, and any aliases of it
+    pub fn remove(&self, filename: &str) -> Result<()> {
+        let connection = self.connection();
+        connection
+            .execute("DELETE FROM aliases WHERE filename = ?1", params![filename])
+            .context("Failed to remove aliases")?;
+        connection
+            .execute("DELETE FROM images WHERE filename = ?1", params![filename])
+            .context("Failed to remove index entry")?;
+        Ok(())
+    }
+
+    /// Drop the entries of images that have expired by `now`, and their aliases,
+    /// returning how many images were dropped
+    pub fn remove_expired(&self, now: u64) -> Result<usize> {
+        let connection = self.connection();
+        connection
+            .execute(
+                "DELETE FROM aliases WHERE filename IN (
+                    SELECT filename FROM images WHERE expires_at IS NOT NULL AND expires_at <= ?1
+                )",
+                params![now],
+            )
+            .context("Failed to remove aliases of expired images")?;
+        connection
+            .execute(
+                "DELETE FROM images WHERE expires_at IS NOT NULL AND expires_at <= ?1",
+                params![now],
+            )
+            .context("Failed to remove expired index entries")
+    }
+}
+
+/// Add a column to the `images` table unless it is already there
+fn add_missing_column(connection: &Connection, name: &str, definition: &str) -> Result<()> {
+    let exists = connection
+        .prepare("SELECT 1 FROM pragma_table_info('images') WHERE name = ?1")
+        .and_then(|mut statement| statement.exists(params![name]))
+        .context("Failed to inspect index tables")?;
+    if !exists {
+        connection
+            .execute(
+                &format!("ALTER TABLE images ADD COLUMN {} {}", name, definition),
+                [],
+            )
+            .with_context(|| format!("Failed to add {} column to index", name))?;
+    }
+    Ok(())
+}
@@ -0,0 +1,170 @@
+//! Functionality shared between the `kimage` uploader and the `kimage-serve` server.
+
+use anyhow::{bail, Context, Result};
+use dirs::home_dir;
+use rand::distributions::Alphanumeric;
+use rand::Rng;
+use serde::de::DeserializeOwned;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::{env, fs, io};
+
+pub mod client;
+pub mod index;
+pub mod logging;
+pub mod metrics;
+pub mod storage;
+
+/// Environment variable that overrides the default config file location
+pub const CONFIG_ENV_VAR: &str = "KIMAGE_CONFIG";
+
+/// Commented config written by `--init`, with `{api_key}` standing in for a generated key
+const CONFIG_TEMPLATE: &str = include_str!("config_template.toml");
+
+/// Length of the API key generated for a template config
+const GENERATED_API_KEY_LENGTH: usize = 32;
+
+/// Resolve which config file to load
+///
+/// An explicit path (from `--config`) takes precedence, then the `KIMAGE_CONFIG`
+/// environment variable, and finally the default `~/.config/kimage.toml`.
+pub fn config_path(explicit: Option<&Path>) -> Result<PathBuf> {
+    if let Some(path) = explicit {
+        return Ok(path.to_path_buf());
+    }
+
+    if let Some(path) = env::var_os(CONFIG_ENV_VAR).filter(|path| !path.is_empty()) {
+        return Ok(PathBuf::from(path));
+    }
+
+    Ok(home_dir()
+        .context("Failed to get home directory")?
+        .join(".config")
+        .join("kimage.toml"))
+}
+
+/// Reasons a config file can't be loaded
+#[derive(Debug, thiserror::Error)]
+pub enum ConfigError {
+    /// There is no file at the config path
+    #[error("No config file at {}", .0.display())]
+    NotFound(PathBuf),
+    /// The file exists but couldn't be read
+    #[error("Failed to read config file {}", path.display())]
+    Io {
+        /// Path of the config file
+        path: PathBuf,
+        /// Why reading failed
+        #[source]
+        source: io::Error,
+    },
+    /// The file isn't valid TOML, or its fields don't match the config
+    #[error("Failed to parse config file {}", path.display())]
+    Parse {
+        /// Path of the config file
+        path: PathBuf,
+        /// What's wrong with the contents
+        #[source]
+        source: toml::de::Error,
+    },
+    /// The file parsed, but some of its settings can't be used; one message per problem
+    #[error(
+        "Invalid config:{}",
+        .0.iter().map(|problem| format!("\n  - {}", problem)).collect::<String>()
+    )]
+    Invalid(Vec<String>),
+}
+
+impl ConfigError {
+    /// Advice to print alongside the error, for mistakes that are easy to fix
+    pub fn hint(&self) -> Option<&'static str> {
+        match self {
+            Self::NotFound(_) => {
+                Some("run `kimage --init` to write a template config there, or pass --config PATH")
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Read a TOML config file and parse it as `T`
+pub fn read_config<T: DeserializeOwned>(path: &Path) -> Result<T, ConfigError> {
+    let contents = fs::read_to_string(path).map_err(|source| match source.kind() {
+        io::ErrorKind::NotFound => ConfigError::NotFound(path.to_path_buf()),
+        _ => ConfigError::Io {
+            path: path.to_path_buf(),
+            source,
+        },
+    })?;
+    toml::from_str(&contents).map_err(|source| ConfigError::Parse {
+        path: path.to_path_buf(),
+        source,
+    })
+}
+
+/// Pick the API key from the highest-precedence source that is configured
+///
+/// `api_key_env` wins over `api_key_file`, which wins over an inline `api_key`,
+/// so a secret mounted for deployment overrides a key left in the file.
+/// Trailing whitespace, such as the newline most editors add, is trimmed from
+/// keys read from a file.
+pub fn resolve_api_key(
+    inline: Option<String>,
+    file: Option<&Path>,
+    env_var: Option<&str>,
+) -> Result<Option<String>, ConfigError> {
+    if let Some(env_var) = env_var {
+        return env::var(env_var).map(Some).map_err(|_| {
+            ConfigError::Invalid(vec![format!(
+                "api_key_env names {}, which is not set",
+                env_var
+            )])
+        });
+    }
+    if let Some(file) = file {
+        return fs::read_to_string(file)
+            .map(|key| Some(key.trim_end().to_owned()))
+            .map_err(|e| {
+                ConfigError::Invalid(vec![format!(
+                    "Failed to read api_key_file {:?}: {}",
+                    file, e
+                )])
+            });
+    }
+    Ok(inline)
+}
+
+/// Write a commented template config to `path`, with a freshly generated API key
+///
+/// An existing file is only replaced when `force` is set. The file holds the
+/// key, so on Unix it is only readable by its owner.
+pub fn init_config(path: &Path, force: bool) -> Result<()> {
+    if !force && path.exists() {
+        bail!(
+            "{} already exists; pass --force to overwrite it",
+            path.display()
+        );
+    }
+    if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        fs::create_dir_all(dir).with_context(|| format!("Failed to create {}", dir.display()))?;
+    }
+
+    let api_key: String = rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(GENERATED_API_KEY_LENGTH)
+        .map(char::from)
+        .collect();
+    let contents = CONFIG_TEMPLATE.replace("{api_key}", &api_key);
+
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    options
+        .open(path)
+        .and_then(|mut file| file.write_all(contents.as_bytes()))
+        .with_context(|| format!("Failed to write {}", path.display()))
+}
@@ -0,0 +1,99 @@
+//! Logger setup shared by both binaries.
+//!
+//! Logs are human-readable by default; the JSON format writes one object per
+//! event for log aggregators such as Loki or Elasticsearch.
+
+use anyhow::{bail, Result};
+use chrono::{SecondsFormat, Utc};
+use log::LevelFilter;
+use serde::{Deserialize, Serialize};
+use std::env;
+use std::io::Write;
+
+/// Environment variable that selects the log format
+pub const LOG_FORMAT_ENV_VAR: &str = "KIMAGE_LOG_FORMAT";
+
+/// Formats logs can be written in
+#[derive(Deserialize, Clone, Copy, Default, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// Coloured lines for reading in a terminal
+    #[default]
+    Pretty,
+    /// One JSON object per log event
+    Json,
+}
+
+impl LogFormat {
+    /// Read the format from the `KIMAGE_LOG_FORMAT` environment variable, if it is set
+    pub fn from_env() -> Result<Option<Self>> {
+        match env::var(LOG_FORMAT_ENV_VAR).as_deref() {
+            Err(_) | Ok("") => Ok(None),
+            Ok("pretty") => Ok(Some(Self::Pretty)),
+            Ok("json") => Ok(Some(Self::Json)),
+            Ok(other) => bail!(
+                "Invalid {} {:?}, expected \"pretty\" or \"json\"",
+                LOG_FORMAT_ENV_VAR,
+                other
+            ),
+        }
+    }
+}
+
+/// A log event as written in the JSON format
+#[derive(Serialize)]
+struct JsonRecord<'a> {
+    timestamp: String,
+    level: &'a str,
+    target: &'a str,
+    message: String,
+}
+
+/// Levels selected by passing `-q` and `-v` repeatedly, from quietest to noisiest
+const VERBOSITY_LEVELS: [LevelFilter; 5] = [
+    LevelFilter::Error,
+    LevelFilter::Warn,
+    LevelFilter::Info,
+    LevelFilter::Debug,
+    LevelFilter::Trace,
+];
+
+/// Pick the log level asked for with `-v` and `-q` flags, starting from `info`
+///
+/// Returns `None` when neither flag was passed, so `RUST_LOG` applies.
+pub fn verbosity_level(verbose: u8, quiet: u8) -> Option<LevelFilter> {
+    if verbose == 0 && quiet == 0 {
+        return None;
+    }
+    let index = (2 + i32::from(verbose) - i32::from(quiet)).clamp(0, 4);
+    Some(VERBOSITY_LEVELS[index as usize])
+}
+
+/// Install the global logger
+///
+/// `level` comes from the command-line flags and wins over `RUST_LOG`, which in
+/// turn wins over `default_filters`.
+pub fn init(format: LogFormat, level: Option<LevelFilter>, default_filters: &str) {
+    let mut builder = match format {
+        LogFormat::Pretty => pretty_env_logger::formatted_builder(),
+        LogFormat::Json => env_logger::Builder::new(),
+    };
+    match (level, env::var("RUST_LOG")) {
+        (Some(level), _) => builder.filter_level(level),
+        (None, Ok(filters)) => builder.parse_filters(&filters),
+        (None, Err(_)) => builder.parse_filters(default_filters),
+    };
+    if format == LogFormat::Json {
+        builder.format(|buf, record| {
+            let line = serde_json::to_string(&JsonRecord {
+                timestamp: Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true),
+                level: record.level().as_str(),
+                target: record.target(),
+                message: record.args().to_string(),
+            })
+            .map_err(std::io::Error::other)?;
+            writeln!(buf, "{}", line)
+        });
+    }
+    builder.init();
+}
@@ -0,0 +1,93 @@
+//! Prometheus metrics exported by `kimage-serve`.
+//!
+//! Every metric is registered with a private registry when [`Metrics`] is
+//! created, and rendered in the Prometheus text format for scraping.
+
+use anyhow::{Context, Result};
+use prometheus::{
+    exponential_buckets, Encoder, Histogram, HistogramOpts, HistogramVec, IntCounter,
+    IntCounterVec, Opts, Registry, TextEncoder,
+};
+
+/// Content type of the rendered metrics
+pub const CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+
+/// Counters and histograms describing what the server has been doing
+pub struct Metrics {
+    /// Registry every metric below is registered with
+    registry: Registry,
+    /// Successful uploads
+    pub uploads: IntCounter,
+    /// Bytes of images written by successful uploads
+    pub uploaded_bytes: IntCounter,
+    /// Sizes of successfully uploaded images
+    pub upload_size: Histogram,
+    /// Requests for images, including thumbnails and conversions
+    pub image_requests: IntCounter,
+    /// Responses with a 4xx or 5xx status, by status code
+    pub errors: IntCounterVec,
+    /// Time taken to answer requests, by route
+    pub request_duration: HistogramVec,
+}
+
+impl Metrics {
+    /// Create and register every metric, all starting from zero
+    pub fn new() -> Result<Self> {
+        let registry = Registry::new_custom(Some("kimage".to_string()), None)
+            .context("Failed to create metrics registry")?;
+        let uploads = IntCounter::new("uploads_total", "Successful uploads")?;
+        let uploaded_bytes = IntCounter::new(
+            "uploaded_bytes_total",
+            "Bytes of images stored by successful uploads",
+        )?;
+        // 1 KiB up to 64 MiB
+        let upload_size = Histogram::with_opts(
+            HistogramOpts::new("upload_size_bytes", "Sizes of uploaded images")
+                .buckets(exponential_buckets(1024.0, 4.0, 9)?),
+        )?;
+        let image_requests = IntCounter::new("image_requests_total", "Requests for images")?;
+        let errors = IntCounterVec::new(
+            Opts::new("http_errors_total", "Responses with an error status"),
+            &["status"],
+        )?;
+        let request_duration = HistogramVec::new(
+            HistogramOpts::new(
+                "http_request_duration_seconds",
+                "Time taken to answer requests",
+            ),
+            &["route"],
+        )?;
+
+        registry.register(Box::new(uploads.clone()))?;
+        registry.register(Box::new(uploaded_bytes.clone()))?;
+        registry.register(Box::new(upload_size.clone()))?;
+        registry.register(Box::new(image_requests.clone()))?;
+        registry.register(Box::new(errors.clone()))?;
+        registry.register(Box::new(request_duration.clone()))?;
+        Ok(Self {
+            registry,
+            uploads,
+            uploaded_bytes,
+            upload_size,
+            image_requests,
+            errors,
+            request_duration,
+        })
+    }
+
+    /// Record a successful upload of `size` bytes
+    pub fn record_upload(&self, size: u64) {
+        self.uploads.inc();
+        self.uploaded_bytes.inc_by(size);
+        self.upload_size.observe(size as f64);
+    }
+
+    /// Render every metric in the Prometheus text exposition format
+    pub fn render(&self) -> Result<String> {
+        let mut buffer = Vec::new();
+        TextEncoder::new()
+            .encode(&self.registry.gather(), &mut buffer)
+            .context("Failed to encode metrics")?;
+        String::from_utf8(buffer).context("Metrics are not valid UTF-8")
+    }
+}
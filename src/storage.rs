@@ -0,0 +1,471 @@
+//! Where `kimage-serve` keeps uploaded images and the files derived from them.
+//!
+//! Everything is addressed by a `/`-separated key relative to the store's root,
+//! such as `abc.png` or `.meta/abc.png.json`, so the server doesn't need to know
+//! whether it is talking to a directory on disk or an object storage bucket.
+
+use anyhow::{anyhow, Context, Result};
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures::stream::{BoxStream, StreamExt};
+use log::warn;
+use rand::distributions::Alphanumeric;
+use rand::Rng;
+use serde::Deserialize;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+use std::time::UNIX_EPOCH;
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+use tokio_util::io::ReaderStream;
+
+#[cfg(feature = "s3")]
+mod s3;
+
+#[cfg(feature = "s3")]
+pub use s3::S3Storage;
+
+/// Key written and removed again to check that a store accepts writes
+const HEALTH_CHECK_KEY: &str = ".health-check";
+
+/// Suffix of the temporary files [`LocalStorage`] writes objects to before moving them into place
+const TEMP_FILE_SUFFIX: &str = ".kimage-tmp";
+
+/// The bytes of an object, read a chunk at a time
+pub type ByteStream = BoxStream<'static, io::Result<Bytes>>;
+
+/// An object held in a store, as reported by [`Storage::list`] and [`Storage::stat`]
+pub struct StoredObject {
+    /// Full key of the object
+    pub key: String,
+    /// Size in bytes
+    pub size: u64,
+    /// Last modification time as seconds since the Unix epoch
+    pub modified: u64,
+}
+
+/// A place to keep uploaded images
+#[async_trait]
+pub trait Storage: Send + Sync {
+    /// Store `data` under `key`, replacing anything already there
+    async fn put(&self, key: &str, data: Vec<u8>) -> Result<()>;
+
+    /// Store `data` under `key` unless the key is taken, returning whether it was stored
+    ///
+    /// The check and the write happen atomically, so two callers can't both
+    /// claim the same key.
+    async fn put_new(&self, key: &str, data: Vec<u8>) -> Result<bool>;
+
+    /// Fetch the object stored under `key`, if there is one
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>>;
+
+    /// Stream the object stored under `key`, or just the inclusive byte `range` of it
+    ///
+    /// The range must lie within the object, as reported by [`Storage::stat`].
+    async fn read(&self, key: &str, range: Option<(u64, u64)>) -> Result<Option<ByteStream>>;
+
+    /// Look up the size and modification time of the object stored under `key`
+    async fn stat(&self, key: &str) -> Result<Option<StoredObject>>;
+
+    /// Check whether anything is stored under `key`
+    async fn exists(&self, key: &str) -> Result<bool>;
+
+    /// Remove the object stored under `key`; removing a missing key is not an error
+    async fn delete(&self, key: &str) -> Result<()>;
+
+    /// List the objects directly inside `prefix`, which is empty or ends in `/`
+    ///
+    /// Objects nested further down, such as `.meta/abc.png.json` when listing
+    /// the root, are not included.
+    async fn list(&self, prefix: &str) -> Result<Vec<StoredObject>>;
+
+    /// List the prefixes nested directly inside `prefix`, such as `2024/` in the root
+    ///
+    /// Each is returned in full and ends in `/`, ready to be listed in turn.
+    async fn list_dirs(&self, prefix: &str) -> Result<Vec<String>>;
+
+    /// Check that the store is reachable and accepts writes
+    async fn check_writable(&self) -> Result<()> {
+        self.put(HEALTH_CHECK_KEY, b"ok".to_vec())
+            .await
+            .context("Storage is not writable")?;
+        self.delete(HEALTH_CHECK_KEY)
+            .await
+            .context("Failed to remove health check object")
+    }
+}
+
+/// Connection settings for an S3-compatible bucket
+#[derive(Deserialize, Clone)]
+pub struct S3Options {
+    /// Bucket to store images in
+    pub bucket: String,
+    /// Region the bucket lives in
+    #[serde(default = "default_s3_region")]
+    pub region: String,
+    /// Endpoint of a non-AWS service such as MinIO, e.g. `http://localhost:9000`
+    #[serde(default)]
+    pub endpoint: Option<String>,
+    /// Access key ID to sign requests with
+    pub access_key_id: String,
+    /// Secret access key to sign requests with
+    pub secret_access_key: String,
+    /// Address buckets as `endpoint/bucket` rather than `bucket.endpoint`, as MinIO expects
+    #[serde(default)]
+    pub path_style: bool,
+}
+
+fn default_s3_region() -> String {
+    "us-east-1".to_string()
+}
+
+/// Images kept in a directory on the local filesystem
+///
+/// Keys map onto paths below the directory, so `.meta/abc.png.json` lives in
+/// a `.meta` subdirectory.
+pub struct LocalStorage {
+    root: PathBuf,
+}
+
+impl LocalStorage {
+    /// Use `root` as the storage directory
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Self { root: root.into() }
+    }
+
+    /// Delete temporary files left behind by writes that were cut short, returning how many were deleted
+    ///
+    /// Only call this while nothing else is writing to the directory, such as at
+    /// startup, since it can't tell abandoned files from ones still being written.
+    pub async fn remove_temp_files(&self) -> Result<usize> {
+        let mut removed = 0;
+        let mut dirs = vec![self.root.clone()];
+        while let Some(dir) = dirs.pop() {
+            let mut entries = tokio::fs::read_dir(&dir)
+                .await
+                .with_context(|| format!("Failed to read {:?}", dir))?;
+            while let Some(entry) = entries
+                .next_entry()
+                .await
+                .with_context(|| format!("Failed to read entry of {:?}", dir))?
+            {
+                let file_type = entry
+                    .file_type()
+                    .await
+                    .with_context(|| format!("Failed to read type of {:?}", entry.path()))?;
+                if file_type.is_dir() {
+                    dirs.push(entry.path());
+                } else if file_type.is_file()
+                    && entry
+                        .file_name()
+                        .to_string_lossy()
+                        .ends_with(TEMP_FILE_SUFFIX)
+                {
+                    tokio::fs::remove_file(entry.path())
+                        .await
+                        .with_context(|| format!("Failed to remove {:?}", entry.path()))?;
+                    removed += 1;
+                }
+            }
+        }
+        Ok(removed)
+    }
+
+    /// Map a key onto a path below the root, rejecting keys that could escape it
+    fn path(&self, key: &str) -> Result<PathBuf> {
+        let valid = !key.is_empty()
+            && key
+                .split('/')
+                .all(|segment| is_plain_component(Path::new(segment)));
+        if !valid {
+            return Err(anyhow!("Invalid storage key {:?}", key));
+        }
+        Ok(self.root.join(key))
+    }
+
+    /// Find the file holding `key`, if there is one
+    ///
+    /// Symlinks are resolved, and files that turn out to live outside the
+    /// storage directory are treated as missing.
+    async fn resolve(&self, key: &str) -> Result<Option<PathBuf>> {
+        let path = self.path(key)?;
+        let root = tokio::fs::canonicalize(&self.root)
+            .await
+            .context("Failed to canonicalize storage path")?;
+        let resolved = match tokio::fs::canonicalize(&path).await {
+            Ok(resolved) => resolved,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e).with_context(|| format!("Failed to canonicalize {:?}", path)),
+        };
+        if !resolved.starts_with(&root) {
+            warn!(
+                "Ignoring {:?}, which resolves outside the storage directory",
+                path
+            );
+            return Ok(None);
+        }
+        Ok(Some(resolved))
+    }
+
+    /// Open the directory for a listing `prefix`, which is empty or ends in `/`
+    ///
+    /// Returns `None` for a nested prefix with no directory, which simply holds nothing.
+    async fn read_dir(&self, prefix: &str) -> Result<Option<(PathBuf, tokio::fs::ReadDir)>> {
+        let dir = match prefix.strip_suffix('/') {
+            Some(dir) => self.path(dir)?,
+            None if prefix.is_empty() => self.root.clone(),
+            None => return Err(anyhow!("Invalid storage prefix {:?}", prefix)),
+        };
+        match tokio::fs::read_dir(&dir).await {
+            Ok(entries) => Ok(Some((dir, entries))),
+            Err(e) if e.kind() == io::ErrorKind::NotFound && !prefix.is_empty() => Ok(None),
+            Err(e) => Err(e).with_context(|| format!("Failed to read {:?}", dir)),
+        }
+    }
+
+    /// Create the directories leading up to a nested key
+    ///
+    /// The root itself is never created, so a missing storage directory is
+    /// reported rather than silently recreated.
+    async fn create_parent(&self, key: &str, path: &Path) -> Result<()> {
+        if key.contains('/') {
+            if let Some(parent) = path.parent() {
+                tokio::fs::create_dir_all(parent)
+                    .await
+                    .with_context(|| format!("Failed to create directory for {}", key))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Open the file for `key` with `options`, creating its directory first
+    ///
+    /// Deleting the last object of a directory removes the directory, which can
+    /// race with creating a file in it, so that case is retried.
+    async fn create_file(
+        &self,
+        key: &str,
+        path: &Path,
+        options: &tokio::fs::OpenOptions,
+    ) -> Result<io::Result<tokio::fs::File>> {
+        let mut attempts = 0;
+        loop {
+            self.create_parent(key, path).await?;
+            match options.open(path).await {
+                Err(e)
+                    if e.kind() == io::ErrorKind::NotFound && key.contains('/') && attempts < 3 =>
+                {
+                    attempts += 1;
+                }
+                result => return Ok(result),
+            }
+        }
+    }
+
+    /// Write `data` to a new temporary file next to `path`, returning where it was written
+    ///
+    /// Objects are written in full and synced before being moved into place, so
+    /// readers never see a partly written file, even after a crash.
+    async fn write_temp(&self, key: &str, path: &Path, data: &[u8]) -> Result<PathBuf> {
+        let temp_path = temp_path(path);
+        let mut options = tokio::fs::OpenOptions::new();
+        options.write(true).create_new(true);
+        let mut file = self
+            .create_file(key, &temp_path, &options)
+            .await?
+            .with_context(|| format!("Failed to create {:?}", temp_path))?;
+        let written = async {
+            file.write_all(data).await?;
+            file.sync_all().await
+        }
+        .await;
+        if let Err(e) = written {
+            let _ = tokio::fs::remove_file(&temp_path).await;
+            return Err(e).with_context(|| format!("Failed to write {:?}", temp_path));
+        }
+        Ok(temp_path)
+    }
+}
+
+#[async_trait]
+impl Storage for LocalStorage {
+    async fn put(&self, key: &str, data: Vec<u8>) -> Result<()> {
+        let path = self.path(key)?;
+        let temp_path = self.write_temp(key, &path, &data).await?;
+        if let Err(e) = tokio::fs::rename(&temp_path, &path).await {
+            let _ = tokio::fs::remove_file(&temp_path).await;
+            return Err(e).with_context(|| format!("Failed to move {:?} into place", path));
+        }
+        Ok(())
+    }
+
+    async fn put_new(&self, key: &str, data: Vec<u8>) -> Result<bool> {
+        let path = self.path(key)?;
+        let temp_path = self.write_temp(key, &path, &data).await?;
+        // Unlike a rename, linking fails rather than replacing a file that is already there
+        let linked = tokio::fs::hard_link(&temp_path, &path).await;
+        let _ = tokio::fs::remove_file(&temp_path).await;
+        match linked {
+            Ok(()) => Ok(true),
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => Ok(false),
+            Err(e) => Err(e).with_context(|| format!("Failed to move {:?} into place", path)),
+        }
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        let Some(path) = self.resolve(key).await? else {
+            return Ok(None);
+        };
+        // The file may have been deleted since it was resolved
+        match tokio::fs::read(&path).await {
+            Ok(contents) => Ok(Some(contents)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e).with_context(|| format!("Failed to read {:?}", path)),
+        }
+    }
+
+    async fn read(&self, key: &str, range: Option<(u64, u64)>) -> Result<Option<ByteStream>> {
+        let Some(path) = self.resolve(key).await? else {
+            return Ok(None);
+        };
+        let mut file = match tokio::fs::File::open(&path).await {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e).with_context(|| format!("Failed to open {:?}", path)),
+        };
+        let Some((start, end)) = range else {
+            return Ok(Some(ReaderStream::new(file).boxed()));
+        };
+        file.seek(io::SeekFrom::Start(start))
+            .await
+            .with_context(|| format!("Failed to seek in {:?}", path))?;
+        Ok(Some(ReaderStream::new(file.take(end - start + 1)).boxed()))
+    }
+
+    async fn stat(&self, key: &str) -> Result<Option<StoredObject>> {
+        let Some(path) = self.resolve(key).await? else {
+            return Ok(None);
+        };
+        let metadata = match tokio::fs::metadata(&path).await {
+            Ok(metadata) => metadata,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => {
+                return Err(e).with_context(|| format!("Failed to read metadata of {:?}", path))
+            }
+        };
+        Ok(Some(stored_object(key.to_owned(), &metadata)))
+    }
+
+    async fn exists(&self, key: &str) -> Result<bool> {
+        let path = self.path(key)?;
+        tokio::fs::try_exists(&path)
+            .await
+            .with_context(|| format!("Failed to check {:?}", path))
+    }
+
+    async fn delete(&self, key: &str) -> Result<()> {
+        let path = self.path(key)?;
+        match tokio::fs::remove_file(&path).await {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e).with_context(|| format!("Failed to remove {:?}", path)),
+        }
+
+        // Tidy up the directories of a nested key once their last object is gone;
+        // this fails harmlessly, and stops, at the first one still in use
+        let depth = key.matches('/').count();
+        for dir in path.ancestors().skip(1).take(depth) {
+            if tokio::fs::remove_dir(dir).await.is_err() {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    async fn list(&self, prefix: &str) -> Result<Vec<StoredObject>> {
+        let Some((dir, mut entries)) = self.read_dir(prefix).await? else {
+            return Ok(Vec::new());
+        };
+
+        let mut objects = Vec::new();
+        while let Some(entry) = entries
+            .next_entry()
+            .await
+            .with_context(|| format!("Failed to read entry of {:?}", dir))?
+        {
+            let Some(name) = entry.file_name().to_str().map(str::to_owned) else {
+                continue;
+            };
+            let metadata = entry
+                .metadata()
+                .await
+                .with_context(|| format!("Failed to read metadata of {}", name))?;
+            if !metadata.is_file() || name.ends_with(TEMP_FILE_SUFFIX) {
+                continue;
+            }
+
+            objects.push(stored_object(format!("{}{}", prefix, name), &metadata));
+        }
+        Ok(objects)
+    }
+
+    async fn list_dirs(&self, prefix: &str) -> Result<Vec<String>> {
+        let Some((dir, mut entries)) = self.read_dir(prefix).await? else {
+            return Ok(Vec::new());
+        };
+
+        let mut dirs = Vec::new();
+        while let Some(entry) = entries
+            .next_entry()
+            .await
+            .with_context(|| format!("Failed to read entry of {:?}", dir))?
+        {
+            let Some(name) = entry.file_name().to_str().map(str::to_owned) else {
+                continue;
+            };
+            // Symlinked directories aren't followed, so listings stay inside the root
+            let file_type = entry
+                .file_type()
+                .await
+                .with_context(|| format!("Failed to read file type of {}", name))?;
+            if file_type.is_dir() {
+                dirs.push(format!("{}{}/", prefix, name));
+            }
+        }
+        Ok(dirs)
+    }
+}
+
+/// Describe a file on disk as a [`StoredObject`]
+fn stored_object(key: String, metadata: &std::fs::Metadata) -> StoredObject {
+    let modified = metadata
+        .modified()
+        .ok()
+        .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+        .map(|d| d.as_secs())
+        .unwrap_or_default();
+    StoredObject {
+        key,
+        size: metadata.len(),
+        modified,
+    }
+}
+
+/// A unique temporary path next to `path`, hidden from listings
+fn temp_path(path: &Path) -> PathBuf {
+    let suffix: String = rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(8)
+        .map(char::from)
+        .collect();
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    path.with_file_name(format!(".{}.{}{}", name, suffix, TEMP_FILE_SUFFIX))
+}
+
+/// Whether `path` is a single ordinary path component, such as `abc.png`
+fn is_plain_component(path: &Path) -> bool {
+    let mut components = path.components();
+    matches!(
+        (components.next(), components.next()),
+        (Some(Component::Normal(_)), None)
+    ) && !path.as_os_str().as_encoded_bytes().contains(&b'\\')
+}
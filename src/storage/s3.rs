@@ -0,0 +1,215 @@
+//! Storage in an S3-compatible bucket, such as AWS S3 or MinIO.
+
+use super::{ByteStream, S3Options, Storage, StoredObject};
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use aws_sdk_s3::config::{BehaviorVersion, Credentials, Region};
+use aws_sdk_s3::primitives::{ByteStream as S3ByteStream, DateTime};
+use aws_sdk_s3::Client;
+use futures::StreamExt;
+use tokio_util::io::ReaderStream;
+
+/// Images kept as objects in an S3-compatible bucket
+///
+/// Keys are used as object keys unchanged, at the root of the bucket.
+pub struct S3Storage {
+    client: Client,
+    bucket: String,
+}
+
+impl S3Storage {
+    /// Connect to the bucket described by `options`
+    ///
+    /// No request is made until the store is first used.
+    pub fn new(options: &S3Options) -> Self {
+        let credentials = Credentials::new(
+            &options.access_key_id,
+            &options.secret_access_key,
+            None,
+            None,
+            "kimage",
+        );
+        let mut config = aws_sdk_s3::Config::builder()
+            .behavior_version(BehaviorVersion::latest())
+            .region(Region::new(options.region.clone()))
+            .credentials_provider(credentials)
+            .force_path_style(options.path_style);
+        if let Some(endpoint) = &options.endpoint {
+            config = config.endpoint_url(endpoint);
+        }
+
+        Self {
+            client: Client::from_conf(config.build()),
+            bucket: options.bucket.clone(),
+        }
+    }
+}
+
+#[async_trait]
+impl Storage for S3Storage {
+    async fn put(&self, key: &str, data: Vec<u8>) -> Result<()> {
+        self.client
+            .put_object()
+            .bucket(&self.bucket)
+            .key(key)
+            .body(S3ByteStream::from(data))
+            .send()
+            .await
+            .with_context(|| format!("Failed to upload {} to S3", key))?;
+        Ok(())
+    }
+
+    async fn put_new(&self, key: &str, data: Vec<u8>) -> Result<bool> {
+        let result = self
+            .client
+            .put_object()
+            .bucket(&self.bucket)
+            .key(key)
+            .if_none_match("*")
+            .body(S3ByteStream::from(data))
+            .send()
+            .await;
+        match result {
+            Ok(_) => Ok(true),
+            // 412 Precondition Failed means the key is already taken
+            Err(e) if e.raw_response().is_some_and(|r| r.status().as_u16() == 412) => Ok(false),
+            Err(e) => Err(e).with_context(|| format!("Failed to upload {} to S3", key)),
+        }
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        let result = self
+            .client
+            .get_object()
+            .bucket(&self.bucket)
+            .key(key)
+            .send()
+            .await;
+        let output = match result {
+            Ok(output) => output,
+            Err(e) if e.as_service_error().is_some_and(|e| e.is_no_such_key()) => return Ok(None),
+            Err(e) => return Err(e).with_context(|| format!("Failed to fetch {} from S3", key)),
+        };
+        let body = output
+            .body
+            .collect()
+            .await
+            .with_context(|| format!("Failed to read {} from S3", key))?;
+        Ok(Some(body.into_bytes().to_vec()))
+    }
+
+    async fn read(&self, key: &str, range: Option<(u64, u64)>) -> Result<Option<ByteStream>> {
+        let result = self
+            .client
+            .get_object()
+            .bucket(&self.bucket)
+            .key(key)
+            .set_range(range.map(|(start, end)| format!("bytes={}-{}", start, end)))
+            .send()
+            .await;
+        match result {
+            Ok(output) => Ok(Some(
+                ReaderStream::new(output.body.into_async_read()).boxed(),
+            )),
+            Err(e) if e.as_service_error().is_some_and(|e| e.is_no_such_key()) => Ok(None),
+            Err(e) => Err(e).with_context(|| format!("Failed to fetch {} from S3", key)),
+        }
+    }
+
+    async fn stat(&self, key: &str) -> Result<Option<StoredObject>> {
+        let result = self
+            .client
+            .head_object()
+            .bucket(&self.bucket)
+            .key(key)
+            .send()
+            .await;
+        match result {
+            Ok(output) => Ok(Some(StoredObject {
+                key: key.to_owned(),
+                size: output
+                    .content_length()
+                    .unwrap_or_default()
+                    .try_into()
+                    .unwrap_or_default(),
+                modified: output.last_modified().map(unix_seconds).unwrap_or_default(),
+            })),
+            Err(e) if e.as_service_error().is_some_and(|e| e.is_not_found()) => Ok(None),
+            Err(e) => Err(e).with_context(|| format!("Failed to look up {} in S3", key)),
+        }
+    }
+
+    async fn exists(&self, key: &str) -> Result<bool> {
+        Ok(self.stat(key).await?.is_some())
+    }
+
+    async fn delete(&self, key: &str) -> Result<()> {
+        self.client
+            .delete_object()
+            .bucket(&self.bucket)
+            .key(key)
+            .send()
+            .await
+            .with_context(|| format!("Failed to delete {} from S3", key))?;
+        Ok(())
+    }
+
+    async fn list(&self, prefix: &str) -> Result<Vec<StoredObject>> {
+        let mut pages = self
+            .client
+            .list_objects_v2()
+            .bucket(&self.bucket)
+            .prefix(prefix)
+            .delimiter("/")
+            .into_paginator()
+            .send();
+
+        let mut objects = Vec::new();
+        while let Some(page) = pages.next().await {
+            let page = page.with_context(|| format!("Failed to list {:?} in S3", prefix))?;
+            for object in page.contents() {
+                let Some(key) = object.key() else {
+                    continue;
+                };
+                objects.push(StoredObject {
+                    key: key.to_owned(),
+                    size: object
+                        .size()
+                        .unwrap_or_default()
+                        .try_into()
+                        .unwrap_or_default(),
+                    modified: object.last_modified().map(unix_seconds).unwrap_or_default(),
+                });
+            }
+        }
+        Ok(objects)
+    }
+
+    async fn list_dirs(&self, prefix: &str) -> Result<Vec<String>> {
+        let mut pages = self
+            .client
+            .list_objects_v2()
+            .bucket(&self.bucket)
+            .prefix(prefix)
+            .delimiter("/")
+            .into_paginator()
+            .send();
+
+        let mut dirs = Vec::new();
+        while let Some(page) = pages.next().await {
+            let page = page.with_context(|| format!("Failed to list {:?} in S3", prefix))?;
+            dirs.extend(
+                page.common_prefixes()
+                    .iter()
+                    .filter_map(|common| common.prefix())
+                    .map(str::to_owned),
+            );
+        }
+        Ok(dirs)
+    }
+}
+
+/// Convert an S3 timestamp to seconds since the Unix epoch
+fn unix_seconds(time: &DateTime) -> u64 {
+    time.secs().try_into().unwrap_or_default()
+}